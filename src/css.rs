@@ -3,11 +3,38 @@
 //! To support more CSS syntax, it would probably be easiest to replace this
 //! hand-rolled parser with one based on a library or parser generator.
 
+use std::fmt;
+
+use crate::colors;
+use crate::source::Position;
+
 // Data structures:
 
 #[derive(Debug)]
 pub struct Stylesheet {
-    pub rules: Vec<Rule>,
+    pub rules: Vec<CssRule>,
+    pub origin: Origin,
+}
+
+/// スタイルシートの出どころ。カスケードでの優先順位を決めます。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Origin {
+    /// ブラウザに組み込まれた既定のスタイルシート
+    UserAgent,
+    /// 閲覧者が指定したスタイルシート
+    User,
+    /// 文書の作者のスタイルシート
+    Author,
+}
+
+/// スタイルシートの中の規則。スタイル規則とat規則が、書かれた順に並びます。
+#[derive(Debug)]
+pub enum CssRule {
+    Style(Rule),
+    Media(MediaRule),
+    Import(ImportRule),
+    FontFace(FontFaceRule),
+    At(AtRule),
 }
 
 #[derive(Debug)]
@@ -16,9 +43,111 @@ pub struct Rule {
     pub declarations: Vec<Declaration>,
 }
 
+/// `@media screen and (min-width: 600px) { ... }`
+#[derive(Debug)]
+pub struct MediaRule {
+    /// カンマ区切りのメディアクエリ。どれかに合えば中の規則を適用します。空ならいつでも適用します。
+    pub queries: Vec<MediaQuery>,
+    pub rules: Vec<CssRule>,
+}
+
+/// `not screen and (max-width: 600px)`のような1つのメディアクエリ
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaQuery {
+    /// `not`が付いていれば、結果を反転します。
+    pub negated: bool,
+    pub media_type: MediaType,
+    /// `and`でつながったメディア特性。すべてに合う必要があります。
+    pub features: Vec<MediaFeature>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MediaType {
+    All,
+    Screen,
+    Print,
+    /// `tv`などの、このエンジンが表示しない種類
+    Other(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MediaFeature {
+    MinWidth(Value),
+    MaxWidth(Value),
+    MinHeight(Value),
+    MaxHeight(Value),
+    Orientation(Orientation),
+    PrefersColorScheme(ColorScheme),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Orientation {
+    Portrait,
+    Landscape,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorScheme {
+    Light,
+    Dark,
+}
+
+/// `@import url("base.css") screen;`
+#[derive(Debug)]
+pub struct ImportRule {
+    /// 取り込むスタイルシートのURL。書かれたままで、どこからの相対パスかは読み込む側が決めます。
+    pub url: String,
+    /// カンマ区切りのメディアクエリ。空でなければ、どれかに合うときだけ取り込んだ規則を適用します。
+    pub queries: Vec<MediaQuery>,
+    /// 取り込んだスタイルシートの規則。読み込むまでは空です。
+    pub rules: Vec<CssRule>,
+}
+
+/// `@font-face { font-family: "Brand"; src: url(brand.ttf) format("truetype"); }`
+#[derive(Debug)]
+pub struct FontFaceRule {
+    /// `font-family`で参照するときの名前
+    pub family: String,
+    /// フォントの在りか。読み込めるものが見つかるまで前から順に試します。
+    pub sources: Vec<FontSource>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FontSource {
+    /// `url(brand.ttf) format("truetype")`
+    Url { url: String, format: Option<String> },
+    /// `local("DejaVu Sans")`: 閲覧する環境にインストールされたフォント
+    Local(String),
+}
+
+/// `@charset "utf-8";`のような、中身を解釈しないat規則
+#[derive(Debug)]
+pub struct AtRule {
+    /// `@`の後の名前（小文字）
+    pub name: String,
+    /// 名前と`;`の間の部分
+    pub prelude: String,
+}
+
 #[derive(Debug)]
 pub enum Selector {
     Simple(SimpleSelector),
+    /// `div > p`のように結合子でつながったセレクタ。
+    /// 右端の`SimpleSelector`が対象の要素に、左側の`Selector`がその祖先や兄弟に合う必要があります。
+    Compound(Box<Selector>, Combinator, SimpleSelector),
+}
+
+/// セレクタの間の結合子
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Combinator {
+    /// `a b`: 子孫
+    Descendant,
+    /// `a > b`: 子
+    Child,
+    /// `a + b`: 直後の兄弟
+    NextSibling,
+    /// `a ~ b`: 後ろの兄弟
+    SubsequentSibling,
 }
 
 #[derive(Debug)]
@@ -26,24 +155,128 @@ pub struct SimpleSelector {
     pub tag_name: Option<String>,
     pub id: Option<String>,
     pub class: Vec<String>,
+    pub attributes: Vec<AttributeSelector>,
+    pub pseudo_classes: Vec<PseudoClass>,
+}
+
+/// 要素のDOMツリーの中での位置や状態に合う擬似クラス
+#[derive(Debug)]
+pub enum PseudoClass {
+    FirstChild,
+    LastChild,
+    /// `:nth-child(an+b)`。`(a, b)`を持ちます。
+    NthChild(i32, i32),
+    /// `:nth-of-type(an+b)`。同じタグ名の兄弟の中での位置を数えます。
+    NthOfType(i32, i32),
+    /// 引数のセレクタのどれにも合わない
+    Not(Vec<Selector>),
+    /// 引数のセレクタのどれかに合う
+    Is(Vec<Selector>),
+    /// `:is()`と同じだが、詳細度が0
+    Where(Vec<Selector>),
+    /// 文書のルート要素
+    Root,
+    /// 子要素もテキストも持たない
+    Empty,
+}
+
+/// `[name]`や`[name="value" i]`のような属性セレクタ
+#[derive(Debug)]
+pub struct AttributeSelector {
+    /// 属性名（HTMLの属性名と同じく小文字）
+    pub name: String,
+    pub matcher: AttributeMatcher,
+    /// `i`フラグが付いていれば、値をASCIIの大文字小文字を区別せずに比べる
+    pub case_insensitive: bool,
+}
+
+#[derive(Debug)]
+pub enum AttributeMatcher {
+    /// `[name]`: 属性がある
+    Exists,
+    /// `[name=value]`: 値が等しい
+    Equals(String),
+    /// `[name~=value]`: 空白区切りの単語のどれかが等しい
+    Includes(String),
+    /// `[name|=value]`: 値が等しいか、`value-`で始まる
+    DashMatch(String),
+    /// `[name^=value]`: 値が`value`で始まる
+    Prefix(String),
+    /// `[name$=value]`: 値が`value`で終わる
+    Suffix(String),
+    /// `[name*=value]`: 値が`value`を含む
+    Substring(String),
 }
 
 #[derive(Debug)]
 pub struct Declaration {
     pub name: String,
     pub value: Value,
+    /// `!important`が付いているかどうか
+    pub important: bool,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Keyword(String),
     Length(f32, Unit),
+    /// `1.5`や`700`のような単位のない数値
+    Number(f32),
     ColorValue(Color),
+    /// `font-family: Georgia, serif`のように複数の値を並べたもの
+    List(Vec<Value>),
+    /// `calc()`、`min()`、`max()`、`clamp()`の式
+    Calc(Box<Calc>),
+    /// `--brand-color`のようなカスタムプロパティの値。書かれたままの文字列を持ちます。
+    Raw(String),
+    /// `var()`を含む値。算出値を求めるときに`var()`を置き換えてから、`property`の値として解析します。
+    /// 一括指定プロパティの宣言では、展開したすべての個別のプロパティが同じ値を持ちます。
+    Var {
+        property: String,
+        text: String,
+    },
+}
+
+/// `calc(100% - 2 * 16px)`のような数式の木。葉は長さか数値です。
+#[derive(Debug, Clone, PartialEq)]
+pub enum Calc {
+    Value(Value),
+    Sum(Box<Calc>, Box<Calc>),
+    Difference(Box<Calc>, Box<Calc>),
+    Product(Box<Calc>, Box<Calc>),
+    Quotient(Box<Calc>, Box<Calc>),
+    Min(Vec<Calc>),
+    Max(Vec<Calc>),
+    /// `clamp(min, value, max)`
+    Clamp(Box<Calc>, Box<Calc>, Box<Calc>),
+}
+
+/// `calc()`などの式の結果の種類
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CalcType {
+    Number,
+    Length,
+    Percentage,
+    /// `100% - 10px`のように、長さと`%`を合わせたもの
+    LengthPercentage,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Unit {
     Px,
+    /// 要素のフォントサイズに対する倍率（`font-size`では親のフォントサイズ）
+    Em,
+    /// ルート要素のフォントサイズに対する倍率
+    Rem,
+    /// `%`。何に対する割合かはプロパティによって異なります。
+    Percent,
+    /// ビューポートの幅の1/100
+    Vw,
+    /// ビューポートの高さの1/100
+    Vh,
+    /// 1/72インチ
+    Pt,
+    Cm,
 }
 
 #[derive(Debug, Clone, PartialEq, Default)]
@@ -58,15 +291,118 @@ impl Copy for Color {}
 
 pub type Specificity = (usize, usize, usize);
 
+impl Stylesheet {
+    /// スタイル規則を書かれた順に返します。
+    /// `@media`の中の規則は、`matches_media`がそのメディアクエリを受け入れたときだけ含めます。
+    pub fn style_rules(&self, matches_media: &dyn Fn(&[MediaQuery]) -> bool) -> Vec<&Rule> {
+        let mut result = Vec::new();
+        collect_style_rules(&self.rules, matches_media, &mut result);
+        result
+    }
+
+    /// `matches_media`に合う`@media`と`@import`の中も含めて、`@font-face`規則を書かれた順に返します。
+    pub fn font_faces(&self, matches_media: &dyn Fn(&[MediaQuery]) -> bool) -> Vec<&FontFaceRule> {
+        let mut result = Vec::new();
+        collect_font_faces(&self.rules, matches_media, &mut result);
+        result
+    }
+}
+
+fn collect_font_faces<'a>(
+    rules: &'a [CssRule],
+    matches_media: &dyn Fn(&[MediaQuery]) -> bool,
+    result: &mut Vec<&'a FontFaceRule>,
+) {
+    for rule in rules {
+        match rule {
+            CssRule::FontFace(face) => result.push(face),
+            CssRule::Media(media) if matches_media(&media.queries) => {
+                collect_font_faces(&media.rules, matches_media, result)
+            }
+            CssRule::Import(import) if matches_media(&import.queries) => {
+                collect_font_faces(&import.rules, matches_media, result)
+            }
+            CssRule::Style(_) | CssRule::Media(_) | CssRule::Import(_) | CssRule::At(_) => {}
+        }
+    }
+}
+
+fn collect_style_rules<'a>(
+    rules: &'a [CssRule],
+    matches_media: &dyn Fn(&[MediaQuery]) -> bool,
+    result: &mut Vec<&'a Rule>,
+) {
+    for rule in rules {
+        match rule {
+            CssRule::Style(rule) => result.push(rule),
+            CssRule::Media(media) if matches_media(&media.queries) => {
+                collect_style_rules(&media.rules, matches_media, result)
+            }
+            CssRule::Import(import) if matches_media(&import.queries) => {
+                collect_style_rules(&import.rules, matches_media, result)
+            }
+            CssRule::Media(_) | CssRule::Import(_) | CssRule::FontFace(_) | CssRule::At(_) => {}
+        }
+    }
+}
+
 impl Selector {
     // レンダリング・エンジンが競合の際にどちらのスタイルを優先するかを決定する方法の1つです。
     pub fn specificity(&self) -> Specificity {
         // http://www.w3.org/TR/selectors/#specificity
-        let Selector::Simple(ref simple) = *self;
-        let a = simple.id.iter().count();
-        let b = simple.class.len();
-        let c = simple.tag_name.iter().count();
-        (a, b, c)
+        // 結合子でつながったセレクタの詳細度は、各部分の詳細度の和です。
+        match *self {
+            Selector::Simple(ref simple) => simple.specificity(),
+            Selector::Compound(ref left, _, ref right) => {
+                add_specificity(left.specificity(), right.specificity())
+            }
+        }
+    }
+}
+
+fn add_specificity((a1, b1, c1): Specificity, (a2, b2, c2): Specificity) -> Specificity {
+    (a1 + a2, b1 + b2, c1 + c2)
+}
+
+impl PseudoClass {
+    /// `:not()`と`:is()`は引数の中で最も詳細度の高いセレクタの詳細度、`:where()`は0、それ以外は1つのクラスと同じです。
+    pub fn specificity(&self) -> Specificity {
+        match self {
+            PseudoClass::Not(selectors) | PseudoClass::Is(selectors) => selectors
+                .iter()
+                .map(Selector::specificity)
+                .max()
+                .unwrap_or_default(),
+            PseudoClass::Where(_) => (0, 0, 0),
+            _ => (0, 1, 0),
+        }
+    }
+}
+
+impl AttributeMatcher {
+    /// 比べる値を返します。`Exists`には値がありません。
+    pub fn value(&self) -> Option<&str> {
+        match self {
+            AttributeMatcher::Exists => None,
+            AttributeMatcher::Equals(v)
+            | AttributeMatcher::Includes(v)
+            | AttributeMatcher::DashMatch(v)
+            | AttributeMatcher::Prefix(v)
+            | AttributeMatcher::Suffix(v)
+            | AttributeMatcher::Substring(v) => Some(v),
+        }
+    }
+}
+
+impl SimpleSelector {
+    pub fn specificity(&self) -> Specificity {
+        let a = self.id.iter().count();
+        let b = self.class.len() + self.attributes.len();
+        let c = self.tag_name.iter().count();
+        self.pseudo_classes
+            .iter()
+            .map(PseudoClass::specificity)
+            .fold((a, b, c), add_specificity)
     }
 }
 
@@ -80,179 +416,1409 @@ impl Value {
     }
 }
 
+impl Calc {
+    /// 式をpxの値として評価します。`%`は`percent_base`に対する割合です。
+    /// `%`の基準がないときや、px以外の長さが残っているときは`None`を返します。
+    pub fn evaluate(&self, percent_base: Option<f32>) -> Option<f32> {
+        let eval = |calc: &Calc| calc.evaluate(percent_base);
+        let all = |args: &[Calc]| args.iter().map(eval).collect::<Option<Vec<f32>>>();
+        match self {
+            Calc::Value(Value::Number(n)) | Calc::Value(Value::Length(n, Unit::Px)) => Some(*n),
+            Calc::Value(Value::Length(n, Unit::Percent)) => Some(n * percent_base? / 100.0),
+            Calc::Value(_) => None,
+            Calc::Sum(a, b) => Some(eval(a)? + eval(b)?),
+            Calc::Difference(a, b) => Some(eval(a)? - eval(b)?),
+            Calc::Product(a, b) => Some(eval(a)? * eval(b)?),
+            Calc::Quotient(a, b) => match eval(b)? {
+                0.0 => None,
+                divisor => Some(eval(a)? / divisor),
+            },
+            Calc::Min(args) => all(args)?.into_iter().reduce(f32::min),
+            Calc::Max(args) => all(args)?.into_iter().reduce(f32::max),
+            Calc::Clamp(min, value, max) => Some(eval(value)?.min(eval(max)?).max(eval(min)?)),
+        }
+    }
+
+    /// 式の結果の種類を求めます。長さどうしの積、数値と長さの和、長さや0での割り算のように
+    /// 型の合わない式では`None`を返します。
+    pub fn resolve_type(&self) -> Option<CalcType> {
+        match self {
+            Calc::Value(Value::Number(_)) => Some(CalcType::Number),
+            Calc::Value(Value::Length(_, Unit::Percent)) => Some(CalcType::Percentage),
+            Calc::Value(Value::Length(..)) => Some(CalcType::Length),
+            Calc::Value(_) => None,
+            Calc::Sum(a, b) | Calc::Difference(a, b) => {
+                add_calc_types(a.resolve_type()?, b.resolve_type()?)
+            }
+            Calc::Product(a, b) => match (a.resolve_type()?, b.resolve_type()?) {
+                (CalcType::Number, t) | (t, CalcType::Number) => Some(t),
+                _ => None,
+            },
+            // 数値だけの式は、解析した時点で値が決まる。
+            Calc::Quotient(a, b) => match b.resolve_type()? {
+                CalcType::Number if b.evaluate(None) != Some(0.0) => a.resolve_type(),
+                _ => None,
+            },
+            Calc::Min(args) | Calc::Max(args) => args
+                .iter()
+                .map(Calc::resolve_type)
+                .reduce(|a, b| add_calc_types(a?, b?))
+                .flatten(),
+            Calc::Clamp(min, value, max) => {
+                let t = add_calc_types(min.resolve_type()?, value.resolve_type()?)?;
+                add_calc_types(t, max.resolve_type()?)
+            }
+        }
+    }
+
+    /// 式の結果が長さか`%`かどうか。そうでなければ結果は数値です。
+    pub fn is_length(&self) -> bool {
+        self.resolve_type().is_some_and(|t| t != CalcType::Number)
+    }
+
+    /// 式の葉の値をすべて`f`で書き換えます。
+    pub fn for_each_value(&mut self, f: &mut impl FnMut(&mut Value)) {
+        match self {
+            Calc::Value(value) => f(value),
+            Calc::Sum(a, b)
+            | Calc::Difference(a, b)
+            | Calc::Product(a, b)
+            | Calc::Quotient(a, b) => {
+                a.for_each_value(f);
+                b.for_each_value(f);
+            }
+            Calc::Min(args) | Calc::Max(args) => {
+                args.iter_mut().for_each(|arg| arg.for_each_value(f))
+            }
+            Calc::Clamp(min, value, max) => {
+                min.for_each_value(f);
+                value.for_each_value(f);
+                max.for_each_value(f);
+            }
+        }
+    }
+}
+
+/// 足し合わせられる種類どうしなら、和の種類を返します。数値と長さは足せません。
+fn add_calc_types(a: CalcType, b: CalcType) -> Option<CalcType> {
+    match (a, b) {
+        _ if a == b => Some(a),
+        (CalcType::Number, _) | (_, CalcType::Number) => None,
+        _ => Some(CalcType::LengthPercentage),
+    }
+}
+
+/// CSSの解析中に見つかったエラーと、その位置。
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub kind: ErrorKind,
+    pub position: Position,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    /// ルールの途中で入力が終わった
+    UnexpectedEof,
+    /// `expected`があるべき場所に`found`があった
+    UnexpectedChar { expected: char, found: char },
+    /// セレクタリストに使えない文字があった
+    InvalidSelector(char),
+    /// プロパティ名があるべき場所に`found`があった
+    MissingPropertyName(char),
+    /// 値があるべき場所に`found`があった
+    MissingValue(char),
+    /// `!`の後が`important`ではない
+    InvalidImportant(String),
+    /// 数値として解釈できない
+    InvalidNumber(String),
+    /// 知らない擬似クラス
+    UnknownPseudoClass(String),
+    /// `:nth-child()`の引数として解釈できない
+    InvalidNth(String),
+    /// 知らない単位
+    UnknownUnit(String),
+    /// 16進数の色として解釈できない
+    InvalidColor(String),
+    /// 値の並びがプロパティに合わない
+    InvalidValue(String),
+    /// 知らない関数
+    UnknownFunction(String),
+    /// 関数の引数の数が合わない
+    InvalidArguments(String),
+    /// `calc()`の式の型が合わない（長さどうしの積、数値と長さの和、長さや0での割り算など）
+    InvalidCalc,
+    /// `calc()`の`+`や`-`の前後に空白がない
+    MissingWhitespaceAroundOperator(char),
+    /// 知らないプロパティ
+    UnknownProperty(String),
+    /// 対応していないat規則
+    UnknownAtRule(String),
+    /// メディアクエリとして解釈できない
+    InvalidMediaQuery,
+    /// 対応していないメディア特性、またはその値
+    UnknownMediaFeature(String),
+    /// `url()`か文字列のURLがない
+    MissingUrl,
+    /// ほかの規則の後にある`@import`
+    MisplacedImport,
+    /// `@font-face`に必須の記述子がない
+    MissingDescriptor(&'static str),
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::UnexpectedEof => write!(f, "unexpected end of input"),
+            ErrorKind::UnexpectedChar { expected, found } => {
+                write!(f, "expected {:?}, found {:?}", expected, found)
+            }
+            ErrorKind::InvalidSelector(c) => {
+                write!(f, "unexpected character {:?} in selector list", c)
+            }
+            ErrorKind::MissingPropertyName(c) => {
+                write!(f, "expected a property name, found {:?}", c)
+            }
+            ErrorKind::MissingValue(c) => write!(f, "expected a value, found {:?}", c),
+            ErrorKind::InvalidImportant(s) => write!(f, "expected !important, found !{}", s),
+            ErrorKind::InvalidNumber(s) => write!(f, "invalid number {:?}", s),
+            ErrorKind::UnknownPseudoClass(s) => write!(f, "unknown pseudo-class :{}", s),
+            ErrorKind::InvalidNth(s) => write!(f, "invalid an+b expression {:?}", s),
+            ErrorKind::UnknownUnit(s) => write!(f, "unrecognized unit {:?}", s),
+            ErrorKind::InvalidColor(s) => write!(f, "invalid color #{}", s),
+            ErrorKind::InvalidValue(s) => write!(f, "invalid value for property {:?}", s),
+            ErrorKind::UnknownFunction(s) => write!(f, "unknown function {}()", s),
+            ErrorKind::InvalidArguments(s) => write!(f, "wrong number of arguments to {}()", s),
+            ErrorKind::InvalidCalc => write!(f, "mismatched types in math expression"),
+            ErrorKind::MissingWhitespaceAroundOperator(c) => {
+                write!(
+                    f,
+                    "{:?} in a math expression needs whitespace on both sides",
+                    c
+                )
+            }
+            ErrorKind::UnknownProperty(s) => write!(f, "unknown property {:?}", s),
+            ErrorKind::UnknownAtRule(s) => write!(f, "unsupported at-rule @{}", s),
+            ErrorKind::InvalidMediaQuery => write!(f, "invalid media query"),
+            ErrorKind::UnknownMediaFeature(s) => write!(f, "unsupported media feature ({})", s),
+            ErrorKind::MissingUrl => write!(f, "expected url() or a quoted URL"),
+            ErrorKind::MisplacedImport => write!(f, "@import must precede all other rules"),
+            ErrorKind::MissingDescriptor(s) => write!(f, "@font-face has no {} descriptor", s),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.kind, self.position)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+type ParseResult<T> = Result<T, ParseError>;
+
 /// Parse a whole CSS stylesheet.
-pub fn parse(source: String) -> Stylesheet {
+///
+/// 解析は失敗しません。CSSのエラー処理の規則に従い、解析できない宣言や規則は読み飛ばして
+/// 残りを解析し、見つかったエラーを一緒に返します。
+pub fn parse(source: String, origin: Origin) -> (Stylesheet, Vec<ParseError>) {
+    let mut parser = Parser {
+        pos: 0,
+        input: source,
+        errors: Vec::new(),
+    };
+    let rules = parser.parse_rules(false);
+    (Stylesheet { rules, origin }, parser.errors)
+}
+
+/// `style`属性の中身のような、中括弧のない宣言の並びを解析します。
+pub fn parse_declaration_list(source: String) -> (Vec<Declaration>, Vec<ParseError>) {
+    let mut parser = Parser {
+        pos: 0,
+        input: source,
+        errors: Vec::new(),
+    };
+    let declarations = parser.parse_declaration_items(is_known_property);
+    (declarations, parser.errors)
+}
+
+/// `<link media="...">`の値のような、カンマ区切りのメディアクエリを解析します。
+/// 解析できないクエリは`not all`になります。
+pub fn parse_media_query_list(source: String) -> (Vec<MediaQuery>, Vec<ParseError>) {
     let mut parser = Parser {
         pos: 0,
         input: source,
+        errors: Vec::new(),
     };
-    Stylesheet {
-        rules: parser.parse_rules(),
+    let mut queries = parser.parse_media_query_list();
+    if parser.peek().is_some() {
+        // `{`などが残っていれば、どれにも合わないクエリの並びとして扱う。
+        parser
+            .errors
+            .push(parser.error(ErrorKind::InvalidMediaQuery));
+        queries = vec![MediaQuery {
+            negated: true,
+            media_type: MediaType::All,
+            features: Vec::new(),
+        }];
     }
+    (queries, parser.errors)
 }
 
 // 解析中の文字列と現在の位置を保持します。
 struct Parser {
     pos: usize,
     input: String,
+    /// 読み飛ばした部分のエラー
+    errors: Vec<ParseError>,
+}
+
+/// 宣言の値の並びの1つの要素。`/`と`,`は区切りとして残し、一括指定の展開に使います。
+#[derive(Debug)]
+enum Component {
+    Value(Value),
+    Slash,
+    Comma,
 }
 
 impl Parser {
     /// CSSルールを解析し、それらをRuleオブジェクトのベクトルとして返します。
-    fn parse_rules(&mut self) -> Vec<Rule> {
+    /// `nested`なら`@media`のブロックの中として、対応する`}`の手前で止まります。
+    fn parse_rules(&mut self, nested: bool) -> Vec<CssRule> {
         let mut rules = Vec::new();
+        // `@import`はスタイルシートの先頭（`@charset`の後）にだけ書ける。
+        let mut imports_allowed = !nested;
         loop {
             self.consume_whitespace();
-            if self.eof() {
-                break;
+            match self.peek() {
+                None => break,
+                Some('}') if nested => break,
+                Some('}') => {
+                    self.errors
+                        .push(self.error(ErrorKind::InvalidSelector('}')));
+                    self.pos += 1;
+                }
+                Some('@') => {
+                    let start = self.pos;
+                    match self.parse_at_rule() {
+                        Ok(Some(CssRule::Import(_))) if !imports_allowed => self
+                            .errors
+                            .push(self.error_at(start, ErrorKind::MisplacedImport)),
+                        Ok(Some(rule)) => {
+                            imports_allowed &= matches!(rule, CssRule::Import(_) | CssRule::At(_));
+                            rules.push(rule);
+                        }
+                        Ok(None) => imports_allowed = false,
+                        Err(e) => {
+                            self.errors.push(e);
+                            self.skip_until(|c| matches!(c, ';' | '{' | '}'));
+                            match self.peek() {
+                                Some(';') => self.pos += 1,
+                                Some('{') => self.skip_block(),
+                                _ => {}
+                            }
+                        }
+                    }
+                }
+                Some(_) => match self.parse_rule() {
+                    Ok(rule) => {
+                        imports_allowed = false;
+                        rules.push(CssRule::Style(rule));
+                    }
+                    Err(e) => {
+                        // セレクタが解析できない規則は、ブロックごと捨てる。
+                        self.errors.push(e);
+                        self.skip_until(|c| matches!(c, '{' | '}'));
+                        if self.peek() == Some('{') {
+                            self.skip_block();
+                        }
+                    }
+                },
             }
-            rules.push(self.parse_rule());
         }
         rules
     }
 
-    /// Parse a rule set: `<selectors> { <declarations> }`.
-    fn parse_rule(&mut self) -> Rule {
-        Rule {
-            selectors: self.parse_selectors(),
-            declarations: self.parse_declarations(),
+    /// at規則を解析します。対応していないat規則は読み飛ばし、エラーを記録して`None`を返します。
+    fn parse_at_rule(&mut self) -> ParseResult<Option<CssRule>> {
+        let start = self.pos;
+        self.expect_char('@')?;
+        let name = self.parse_identifier().to_ascii_lowercase();
+        if name == "media" {
+            let queries = self.parse_media_query_list();
+            self.expect_char('{')?;
+            let rules = self.parse_rules(true);
+            self.close_block();
+            return Ok(Some(CssRule::Media(MediaRule { queries, rules })));
         }
-    }
 
-    /// セレクターを解析し、その特異性に基づいてソートします。
-    fn parse_selectors(&mut self) -> Vec<Selector> {
-        let mut selectors = Vec::new();
-        loop {
-            selectors.push(Selector::Simple(self.parse_simple_selector()));
+        if name == "import" {
             self.consume_whitespace();
-            match self.next_char() {
-                ',' => {
-                    self.consume_char();
-                    self.consume_whitespace();
+            let url = self.parse_url()?;
+            let queries = self.parse_media_query_list();
+            self.end_statement()?;
+            return Ok(Some(CssRule::Import(ImportRule {
+                url,
+                queries,
+                rules: Vec::new(),
+            })));
+        }
+        if name == "font-face" {
+            self.consume_whitespace();
+            return self.parse_font_face(start);
+        }
+
+        let prelude_start = self.pos;
+        self.skip_until(|c| matches!(c, ';' | '{' | '}'));
+        let prelude = self.input[prelude_start..self.pos].trim().to_string();
+        match (&*name, self.peek()) {
+            ("charset", _) => {
+                self.end_statement()?;
+                Ok(Some(CssRule::At(AtRule { name, prelude })))
+            }
+            (_, next) => {
+                match next {
+                    Some(';') => self.pos += 1,
+                    Some('{') => self.skip_block(),
+                    _ => {}
                 }
-                '{' => break,
-                c => panic!("Unexpected character {} in selector list", c),
+                self.errors
+                    .push(self.error_at(start, ErrorKind::UnknownAtRule(name)));
+                Ok(None)
             }
         }
-        // Return selectors with highest specificity first, for use in matching.
-        selectors.sort_by(|a, b| b.specificity().cmp(&a.specificity()));
-        selectors
     }
 
-    /// Parse one simple selector, e.g.: `type#id.class1.class2.class3`
-    fn parse_simple_selector(&mut self) -> SimpleSelector {
-        let mut selector = SimpleSelector {
-            tag_name: None,
-            id: None,
-            class: Vec::new(),
-        };
-        while !self.eof() {
-            match self.next_char() {
-                '#' => {
-                    self.consume_char();
-                    selector.id = Some(self.parse_identifier());
-                }
-                '.' => {
-                    self.consume_char();
-                    selector.class.push(self.parse_identifier());
-                }
-                '*' => {
-                    // universal selector
-                    self.consume_char();
-                }
-                c if valid_identifier_char(c) => {
-                    selector.tag_name = Some(self.parse_identifier());
-                }
-                _ => break,
-            }
+    /// ブロックを持たないat規則の終わりの`;`を読みます。入力の終わりでは省略できます。
+    fn end_statement(&mut self) -> ParseResult<()> {
+        self.consume_whitespace();
+        if self.peek().is_some() {
+            self.expect_char(';')?;
         }
-        selector
+        Ok(())
     }
 
-    /// 中括弧{}で囲まれた宣言を解析します。
-    fn parse_declarations(&mut self) -> Vec<Declaration> {
-        assert_eq!(self.consume_char(), '{');
-        let mut declarations = Vec::new();
+    /// `url(...)`か、引用符で囲んだ文字列のURLを読みます。
+    fn parse_url(&mut self) -> ParseResult<String> {
+        if let Some('"' | '\'') = self.peek() {
+            return self.parse_string();
+        }
+        let start = self.pos;
+        if !self.parse_identifier().eq_ignore_ascii_case("url") || self.peek() != Some('(') {
+            return Err(self.error_at(start, ErrorKind::MissingUrl));
+        }
+        self.pos += 1;
+        self.consume_whitespace();
+        let url = match self.peek() {
+            Some('"' | '\'') => self.parse_string()?,
+            _ => self.consume_while(|c| c != ')' && !c.is_whitespace()),
+        };
+        self.consume_whitespace();
+        self.expect_char(')')?;
+        self.consume_whitespace();
+        Ok(url)
+    }
+
+    /// `@font-face`のブロックを解析します。
+    /// `font-family`か`src`のない規則は、エラーを記録して`None`を返します。
+    fn parse_font_face(&mut self, start: usize) -> ParseResult<Option<CssRule>> {
+        self.expect_char('{')?;
+        let mut family = None;
+        let mut sources = None;
         loop {
             self.consume_whitespace();
-            if self.next_char() == '}' {
-                self.consume_char();
-                break;
+            match self.peek() {
+                None | Some('}') => break,
+                Some(';') => self.pos += 1,
+                Some(_) => {
+                    if let Err(e) = self.parse_font_descriptor(&mut family, &mut sources) {
+                        self.errors.push(e);
+                        self.skip_until(|c| matches!(c, ';' | '}'));
+                    }
+                }
             }
-            declarations.push(self.parse_declaration());
         }
-        declarations
+        self.close_block();
+
+        let missing = match (family, sources) {
+            (Some(family), Some(sources)) => {
+                return Ok(Some(CssRule::FontFace(FontFaceRule { family, sources })))
+            }
+            (None, _) => "font-family",
+            (_, None) => "src",
+        };
+        self.errors
+            .push(self.error_at(start, ErrorKind::MissingDescriptor(missing)));
+        Ok(None)
     }
 
-    /// Parse one `<property>: <value>;` declaration.
-    fn parse_declaration(&mut self) -> Declaration {
-        let property_name = self.parse_identifier();
+    /// `@font-face`の中の記述子を1つ読みます。`font-family`と`src`のほかは読み飛ばします。
+    fn parse_font_descriptor(
+        &mut self,
+        family: &mut Option<String>,
+        sources: &mut Option<Vec<FontSource>>,
+    ) -> ParseResult<()> {
+        let name_start = self.pos;
+        let name = self.parse_identifier().to_ascii_lowercase();
+        if name.is_empty() {
+            return Err(self.error(ErrorKind::MissingPropertyName(self.next_char()?)));
+        }
+        if !is_font_descriptor(&name) {
+            return Err(self.error_at(name_start, ErrorKind::UnknownProperty(name)));
+        }
         self.consume_whitespace();
-        assert_eq!(self.consume_char(), ':');
+        self.expect_char(':')?;
         self.consume_whitespace();
-        let value = self.parse_value();
+        match &*name {
+            "font-family" => *family = Some(self.parse_family_name()?),
+            "src" => *sources = Some(self.parse_font_sources()?),
+            _ => self.skip_until(|c| matches!(c, ';' | '}')),
+        }
         self.consume_whitespace();
-        assert_eq!(self.consume_char(), ';');
-
-        Declaration {
-            name: property_name,
-            value,
+        if !matches!(self.peek(), Some('}') | None) {
+            self.expect_char(';')?;
         }
+        Ok(())
     }
 
-    // 数値、色、キーワードを解析します。
-    fn parse_value(&mut self) -> Value {
-        match self.next_char() {
-            '0'..='9' => self.parse_length(),
-            '#' => self.parse_color(),
-            _ => Value::Keyword(self.parse_identifier()),
+    /// 引用符で囲んだ名前か、空白で区切った識別子の並びをファミリー名として読みます。
+    fn parse_family_name(&mut self) -> ParseResult<String> {
+        if let Some('"' | '\'') = self.peek() {
+            return self.parse_string();
+        }
+        let mut words = Vec::new();
+        loop {
+            let word = self.parse_identifier();
+            if word.is_empty() {
+                break;
+            }
+            words.push(word);
+            self.consume_whitespace();
+        }
+        if words.is_empty() {
+            return Err(self.error(ErrorKind::MissingValue(self.next_char()?)));
         }
+        Ok(words.join(" "))
     }
 
-    fn parse_length(&mut self) -> Value {
-        Value::Length(self.parse_float(), self.parse_unit())
+    /// `src`の値として、`url() format()`か`local()`をカンマ区切りで読みます。
+    fn parse_font_sources(&mut self) -> ParseResult<Vec<FontSource>> {
+        let mut sources = Vec::new();
+        loop {
+            self.consume_whitespace();
+            let start = self.pos;
+            let is_local = self.parse_identifier().eq_ignore_ascii_case("local");
+            let source = if is_local && self.peek() == Some('(') {
+                self.pos += 1;
+                self.consume_whitespace();
+                let name = self.parse_family_name()?;
+                self.consume_whitespace();
+                self.expect_char(')')?;
+                FontSource::Local(name)
+            } else {
+                self.pos = start;
+                let url = self.parse_url()?;
+                let format_start = self.pos;
+                let format = if self.parse_identifier().eq_ignore_ascii_case("format")
+                    && self.peek() == Some('(')
+                {
+                    self.pos += 1;
+                    self.consume_whitespace();
+                    let format = self.parse_family_name()?;
+                    self.consume_whitespace();
+                    self.expect_char(')')?;
+                    Some(format.to_ascii_lowercase())
+                } else {
+                    self.pos = format_start;
+                    None
+                };
+                FontSource::Url { url, format }
+            };
+            sources.push(source);
+            self.consume_whitespace();
+            if self.peek() != Some(',') {
+                break;
+            }
+            self.pos += 1;
+        }
+        Ok(sources)
     }
 
-    fn parse_float(&mut self) -> f32 {
-        let s = self.consume_while(|c| match c {
-            '0'..='9' | '.' => true,
-            _ => false,
-        });
-        s.parse().unwrap()
+    /// `{`の手前までのカンマ区切りのメディアクエリを解析します。
+    /// 解析できないクエリは、エラーを記録して決して合わない`not all`として扱います。
+    fn parse_media_query_list(&mut self) -> Vec<MediaQuery> {
+        let mut queries = Vec::new();
+        loop {
+            self.consume_whitespace();
+            if matches!(self.peek(), Some('{' | ';' | '}') | None) && queries.is_empty() {
+                break;
+            }
+            let query = self.parse_media_query().and_then(|query| {
+                self.consume_whitespace();
+                match self.peek() {
+                    Some(',' | '{' | ';' | '}') | None => Ok(query),
+                    Some(_) => Err(self.error(ErrorKind::InvalidMediaQuery)),
+                }
+            });
+            queries.push(query.unwrap_or_else(|e| {
+                self.errors.push(e);
+                self.skip_until(|c| matches!(c, ',' | '{' | ';' | '}'));
+                MediaQuery {
+                    negated: true,
+                    media_type: MediaType::All,
+                    features: Vec::new(),
+                }
+            }));
+            if self.peek() != Some(',') {
+                break;
+            }
+            self.pos += 1;
+        }
+        queries
     }
 
-    fn parse_unit(&mut self) -> Unit {
-        match &*self.parse_identifier().to_ascii_lowercase() {
-            "px" => Unit::Px,
-            _ => panic!("unrecognized unit"),
+    /// `[not | only]? <media-type> [and <feature>]*`または`<feature> [and <feature>]*`を解析します。
+    fn parse_media_query(&mut self) -> ParseResult<MediaQuery> {
+        let mut query = MediaQuery {
+            negated: false,
+            media_type: MediaType::All,
+            features: Vec::new(),
+        };
+        if self.peek() != Some('(') {
+            let mut keyword = self.parse_identifier().to_ascii_lowercase();
+            if keyword == "not" || keyword == "only" {
+                query.negated = keyword == "not";
+                self.consume_whitespace();
+                // メディアの種類を書かずに`not (max-width: 600px)`とも書ける。後に`and`は続けられない。
+                if query.negated && self.peek() == Some('(') {
+                    query.features.push(self.parse_media_feature()?);
+                    return Ok(query);
+                }
+                keyword = self.parse_identifier().to_ascii_lowercase();
+            }
+            query.media_type = match &*keyword {
+                "" => return Err(self.error(ErrorKind::InvalidMediaQuery)),
+                "all" => MediaType::All,
+                "screen" => MediaType::Screen,
+                "print" => MediaType::Print,
+                _ => MediaType::Other(keyword),
+            };
+            if !self.consume_keyword("and") {
+                return Ok(query);
+            }
+        }
+        loop {
+            query.features.push(self.parse_media_feature()?);
+            if !self.consume_keyword("and") {
+                return Ok(query);
+            }
         }
     }
 
-    fn parse_color(&mut self) -> Value {
-        assert_eq!(self.consume_char(), '#');
-        Value::ColorValue(Color {
-            r: self.parse_hex_pair(),
-            g: self.parse_hex_pair(),
-            b: self.parse_hex_pair(),
-            a: 255,
-        })
-    }
+    /// `(min-width: 600px)`のようなメディア特性を解析します。
+    fn parse_media_feature(&mut self) -> ParseResult<MediaFeature> {
+        self.consume_whitespace();
+        self.expect_char('(')?;
+        self.consume_whitespace();
+        let start = self.pos;
+        let name = self.parse_identifier().to_ascii_lowercase();
+        self.consume_whitespace();
+        let value = match self.peek() {
+            Some(':') => {
+                self.pos += 1;
+                self.consume_whitespace();
+                Some(self.parse_value()?)
+            }
+            _ => None,
+        };
+        self.consume_whitespace();
+        self.expect_char(')')?;
 
-    /// Parse two hexadecimal digits.
-    fn parse_hex_pair(&mut self) -> u8 {
-        let s = &self.input[self.pos..self.pos + 2];
-        self.pos += 2;
-        u8::from_str_radix(s, 16).unwrap()
+        let keyword = match &value {
+            Some(Value::Keyword(keyword)) => keyword.to_ascii_lowercase(),
+            _ => String::new(),
+        };
+        let feature = match (&*name, value) {
+            ("min-width", Some(v)) if is_media_length(&v) => MediaFeature::MinWidth(v),
+            ("max-width", Some(v)) if is_media_length(&v) => MediaFeature::MaxWidth(v),
+            ("min-height", Some(v)) if is_media_length(&v) => MediaFeature::MinHeight(v),
+            ("max-height", Some(v)) if is_media_length(&v) => MediaFeature::MaxHeight(v),
+            ("orientation", Some(_)) if keyword == "portrait" => {
+                MediaFeature::Orientation(Orientation::Portrait)
+            }
+            ("orientation", Some(_)) if keyword == "landscape" => {
+                MediaFeature::Orientation(Orientation::Landscape)
+            }
+            ("prefers-color-scheme", Some(_)) if keyword == "light" => {
+                MediaFeature::PrefersColorScheme(ColorScheme::Light)
+            }
+            ("prefers-color-scheme", Some(_)) if keyword == "dark" => {
+                MediaFeature::PrefersColorScheme(ColorScheme::Dark)
+            }
+            _ => return Err(self.error_at(start, ErrorKind::UnknownMediaFeature(name))),
+        };
+        Ok(feature)
     }
 
-    /// Parse a property name or keyword.
-    fn parse_identifier(&mut self) -> String {
-        self.consume_while(valid_identifier_char)
+    /// 空白の後に`keyword`があれば読んで`true`を返します。なければ何も読みません。
+    fn consume_keyword(&mut self, keyword: &str) -> bool {
+        let start = self.pos;
+        self.consume_whitespace();
+        if self.parse_identifier().eq_ignore_ascii_case(keyword) {
+            return true;
+        }
+        self.pos = start;
+        false
     }
 
-    /// Consume and discard zero or more whitespace characters.
+    /// Parse a rule set: `<selectors> { <declarations> }`.
+    fn parse_rule(&mut self) -> ParseResult<Rule> {
+        Ok(Rule {
+            selectors: self.parse_selectors()?,
+            declarations: self.parse_declarations(is_known_property)?,
+        })
+    }
+
+    /// セレクターを解析し、その特異性に基づいてソートします。
+    fn parse_selectors(&mut self) -> ParseResult<Vec<Selector>> {
+        let mut selectors = Vec::new();
+        loop {
+            selectors.push(self.parse_selector()?);
+            match self.next_char()? {
+                ',' => {
+                    self.consume_char()?;
+                    self.consume_whitespace();
+                }
+                '{' => break,
+                c => return Err(self.error(ErrorKind::InvalidSelector(c))),
+            }
+        }
+        // Return selectors with highest specificity first, for use in matching.
+        selectors.sort_by_key(|s| std::cmp::Reverse(s.specificity()));
+        Ok(selectors)
+    }
+
+    /// 結合子でつながったセレクタを1つ解析します。例: `ul > li.item + li`
+    /// 後ろの空白も読み、`,`や`{`、`)`の直前で止まります。
+    fn parse_selector(&mut self) -> ParseResult<Selector> {
+        let mut selector = Selector::Simple(self.parse_required_simple_selector()?);
+        loop {
+            let start = self.pos;
+            self.consume_whitespace();
+            let combinator = match self.peek() {
+                Some('>') => Combinator::Child,
+                Some('+') => Combinator::NextSibling,
+                Some('~') => Combinator::SubsequentSibling,
+                Some(',') | Some('{') | Some(')') | None => break,
+                Some(_) if self.pos > start => Combinator::Descendant,
+                Some(c) => return Err(self.error(ErrorKind::InvalidSelector(c))),
+            };
+            if combinator != Combinator::Descendant {
+                self.pos += 1;
+                self.consume_whitespace();
+            }
+            let right = self.parse_required_simple_selector()?;
+            selector = Selector::Compound(Box::new(selector), combinator, right);
+        }
+        Ok(selector)
+    }
+
+    /// 空でない単純セレクタを解析します。
+    fn parse_required_simple_selector(&mut self) -> ParseResult<SimpleSelector> {
+        let start = self.pos;
+        let selector = self.parse_simple_selector()?;
+        if self.pos == start {
+            return Err(self.error(ErrorKind::InvalidSelector(self.next_char()?)));
+        }
+        Ok(selector)
+    }
+
+    /// Parse one simple selector, e.g.: `type#id.class1.class2.class3`
+    fn parse_simple_selector(&mut self) -> ParseResult<SimpleSelector> {
+        let mut selector = SimpleSelector {
+            tag_name: None,
+            id: None,
+            class: Vec::new(),
+            attributes: Vec::new(),
+            pseudo_classes: Vec::new(),
+        };
+        while let Some(c) = self.peek() {
+            match c {
+                '#' => {
+                    self.pos += 1;
+                    selector.id = Some(self.parse_identifier());
+                }
+                '.' => {
+                    self.pos += 1;
+                    selector.class.push(self.parse_identifier());
+                }
+                '*' => {
+                    // universal selector
+                    self.pos += 1;
+                }
+                '[' => selector.attributes.push(self.parse_attribute_selector()?),
+                ':' => selector.pseudo_classes.push(self.parse_pseudo_class()?),
+                c if valid_identifier_char(c) => {
+                    selector.tag_name = Some(self.parse_identifier());
+                }
+                _ => break,
+            }
+        }
+        Ok(selector)
+    }
+
+    /// Parse an attribute selector, e.g.: `[href^="https" i]`
+    fn parse_attribute_selector(&mut self) -> ParseResult<AttributeSelector> {
+        self.expect_char('[')?;
+        self.consume_whitespace();
+        let name = self.parse_identifier().to_ascii_lowercase();
+        if name.is_empty() {
+            return Err(self.error(ErrorKind::InvalidSelector(self.next_char()?)));
+        }
+        self.consume_whitespace();
+
+        let operator = match self.next_char()? {
+            ']' => {
+                self.pos += 1;
+                return Ok(AttributeSelector {
+                    name,
+                    matcher: AttributeMatcher::Exists,
+                    case_insensitive: false,
+                });
+            }
+            '=' => None,
+            c @ ('~' | '|' | '^' | '$' | '*') => {
+                self.pos += 1;
+                Some(c)
+            }
+            c => return Err(self.error(ErrorKind::InvalidSelector(c))),
+        };
+        self.expect_char('=')?;
+        self.consume_whitespace();
+        let value = match self.next_char()? {
+            '"' | '\'' => self.parse_string()?,
+            c if valid_identifier_char(c) => self.parse_identifier(),
+            c => return Err(self.error(ErrorKind::InvalidSelector(c))),
+        };
+        self.consume_whitespace();
+
+        let mut case_insensitive = false;
+        if let Some(flag @ ('i' | 'I' | 's' | 'S')) = self.peek() {
+            self.pos += 1;
+            case_insensitive = flag.eq_ignore_ascii_case(&'i');
+            self.consume_whitespace();
+        }
+        self.expect_char(']')?;
+
+        let matcher = match operator {
+            None => AttributeMatcher::Equals(value),
+            Some('~') => AttributeMatcher::Includes(value),
+            Some('|') => AttributeMatcher::DashMatch(value),
+            Some('^') => AttributeMatcher::Prefix(value),
+            Some('$') => AttributeMatcher::Suffix(value),
+            _ => AttributeMatcher::Substring(value),
+        };
+        Ok(AttributeSelector {
+            name,
+            matcher,
+            case_insensitive,
+        })
+    }
+
+    /// Parse a pseudo-class, e.g.: `:first-child`, `:nth-child(2n+1)`, `:not(.a, .b)`
+    fn parse_pseudo_class(&mut self) -> ParseResult<PseudoClass> {
+        self.expect_char(':')?;
+        let start = self.pos;
+        let name = self.parse_identifier().to_ascii_lowercase();
+        if self.peek() != Some('(') {
+            return match &*name {
+                "first-child" => Ok(PseudoClass::FirstChild),
+                "last-child" => Ok(PseudoClass::LastChild),
+                "root" => Ok(PseudoClass::Root),
+                "empty" => Ok(PseudoClass::Empty),
+                _ => Err(self.error_at(start, ErrorKind::UnknownPseudoClass(name))),
+            };
+        }
+
+        self.expect_char('(')?;
+        self.consume_whitespace();
+        let pseudo_class = match &*name {
+            "nth-child" => {
+                let (a, b) = self.parse_nth()?;
+                PseudoClass::NthChild(a, b)
+            }
+            "nth-of-type" => {
+                let (a, b) = self.parse_nth()?;
+                PseudoClass::NthOfType(a, b)
+            }
+            "not" => PseudoClass::Not(self.parse_selector_list()?),
+            "is" => PseudoClass::Is(self.parse_selector_list()?),
+            "where" => PseudoClass::Where(self.parse_selector_list()?),
+            _ => return Err(self.error_at(start, ErrorKind::UnknownPseudoClass(name))),
+        };
+        self.consume_whitespace();
+        self.expect_char(')')?;
+        Ok(pseudo_class)
+    }
+
+    /// `:is()`などの引数のセレクタリストを、`)`の直前まで解析します。
+    fn parse_selector_list(&mut self) -> ParseResult<Vec<Selector>> {
+        let mut selectors = vec![self.parse_selector()?];
+        while self.peek() == Some(',') {
+            self.pos += 1;
+            self.consume_whitespace();
+            selectors.push(self.parse_selector()?);
+        }
+        Ok(selectors)
+    }
+
+    /// `an+b`の式を解析します。`odd`、`even`、`3`、`-n+2`なども受け付けます。
+    fn parse_nth(&mut self) -> ParseResult<(i32, i32)> {
+        let start = self.pos;
+        let text = self.consume_while(|c| c != ')');
+        let expr: String = text
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>()
+            .to_ascii_lowercase();
+        parse_an_plus_b(&expr).ok_or_else(|| self.error_at(start, ErrorKind::InvalidNth(text)))
+    }
+
+    /// Parse a quoted string, e.g.: `"text"` or `'text'`
+    fn parse_string(&mut self) -> ParseResult<String> {
+        let quote = self.consume_char()?;
+        let value = self.consume_while(|c| c != quote);
+        self.expect_char(quote)?;
+        Ok(value)
+    }
+
+    /// 中括弧{}で囲まれた宣言を解析します。
+    /// `known`に合わない名前の宣言は捨てます。
+    fn parse_declarations(&mut self, known: fn(&str) -> bool) -> ParseResult<Vec<Declaration>> {
+        self.expect_char('{')?;
+        let declarations = self.parse_declaration_items(known);
+        self.close_block();
+        Ok(declarations)
+    }
+
+    /// `}`か入力の終わりまで宣言を読みます。
+    /// 解析できない宣言や`known`に合わないプロパティの宣言は、エラーを記録して次の`;`まで読み飛ばします。
+    fn parse_declaration_items(&mut self, known: fn(&str) -> bool) -> Vec<Declaration> {
+        let mut declarations = Vec::new();
+        loop {
+            self.consume_whitespace();
+            match self.peek() {
+                None | Some('}') => break,
+                Some(';') => self.pos += 1,
+                Some(_) => match self.parse_declaration(known) {
+                    Ok(declaration) => declarations.extend(declaration),
+                    Err(e) => {
+                        self.errors.push(e);
+                        self.skip_until(|c| matches!(c, ';' | '}'));
+                    }
+                },
+            }
+        }
+        declarations
+    }
+
+    /// ブロックを閉じる`}`を読みます。入力が先に終わった場合は、エラーを記録してブロックを閉じたものとします。
+    fn close_block(&mut self) {
+        match self.peek() {
+            Some('}') => self.pos += 1,
+            _ => self.errors.push(self.error(ErrorKind::UnexpectedEof)),
+        }
+    }
+
+    /// `{`で始まるブロックを、対応する`}`まで読み飛ばします。
+    fn skip_block(&mut self) {
+        self.pos += 1;
+        self.skip_until(|c| c == '}');
+        if self.peek() == Some('}') {
+            self.pos += 1;
+        }
+    }
+
+    /// 括弧の外で`stop`に合う文字の手前まで読み飛ばします。
+    /// 括弧の中、文字列、コメントの中の文字では止まりません。
+    fn skip_until(&mut self, stop: impl Fn(char) -> bool) {
+        let mut closers = Vec::new();
+        while let Some(c) = self.peek() {
+            if closers.is_empty() && stop(c) {
+                return;
+            }
+            if self.input[self.pos..].starts_with("/*") {
+                self.consume_whitespace();
+                continue;
+            }
+            self.pos += c.len_utf8();
+            match c {
+                '"' | '\'' => {
+                    self.consume_while(|d| d != c);
+                    self.pos += self.peek().map_or(0, char::len_utf8);
+                }
+                '\\' => self.pos += self.peek().map_or(0, char::len_utf8),
+                '(' => closers.push(')'),
+                '[' => closers.push(']'),
+                '{' => closers.push('}'),
+                ')' | ']' | '}' if closers.last() == Some(&c) => {
+                    closers.pop();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Parse one `<property>: <value> [!important];` declaration.
+    /// `}`や入力の終わりの直前では`;`を省略できます。
+    /// 一括指定プロパティは個別のプロパティの宣言に展開して返します。
+    fn parse_declaration(&mut self, known: fn(&str) -> bool) -> ParseResult<Vec<Declaration>> {
+        let name_start = self.pos;
+        let name = self.parse_identifier();
+        // カスタムプロパティの名前は大文字と小文字を区別する。
+        let is_custom = name.starts_with("--");
+        let property_name = if is_custom {
+            name
+        } else {
+            name.to_ascii_lowercase()
+        };
+        if property_name.is_empty() {
+            return Err(self.error(ErrorKind::MissingPropertyName(self.next_char()?)));
+        }
+        if !is_custom && !known(&property_name) {
+            return Err(self.error_at(name_start, ErrorKind::UnknownProperty(property_name)));
+        }
+        self.consume_whitespace();
+        self.expect_char(':')?;
+        self.consume_whitespace();
+        let start = self.pos;
+
+        // カスタムプロパティと`var()`を含む値は、算出値を求めるときまで解析しない。
+        if is_custom {
+            let (text, important) = self.parse_raw_value();
+            return Ok(vec![Declaration {
+                name: property_name,
+                value: Value::Raw(text),
+                important,
+            }]);
+        }
+        let (text, important) = self.parse_raw_value();
+        if find_var(&text).is_some() {
+            let value = Value::Var {
+                property: property_name.clone(),
+                text,
+            };
+            let names = longhands(&property_name).unwrap_or_else(|| vec![property_name]);
+            return Ok(names
+                .into_iter()
+                .map(|name| Declaration {
+                    name,
+                    value: value.clone(),
+                    important,
+                })
+                .collect());
+        }
+        self.pos = start;
+        let components = self.parse_components()?;
+        let important = self.parse_important()?;
+        // 値が正しくなければ、`;`の手前で止まって次の宣言から読み直す。
+        let longhands = expand_shorthand(&property_name, components)
+            .ok_or_else(|| self.error_at(start, ErrorKind::InvalidValue(property_name)))?;
+        // 最後の宣言の`;`は省略できる。
+        if !matches!(self.peek(), Some('}') | None) {
+            self.expect_char(';')?;
+        }
+        Ok(longhands
+            .into_iter()
+            .map(|(name, value)| Declaration {
+                name,
+                value,
+                important,
+            })
+            .collect())
+    }
+
+    /// 宣言の値を`;`か`}`の手前まで、書かれたままの文字列として読みます。
+    /// 末尾の`!important`は取り除き、`!important`があったかどうかと一緒に返します。
+    fn parse_raw_value(&mut self) -> (String, bool) {
+        let start = self.pos;
+        self.skip_until(|c| matches!(c, ';' | '}'));
+        let text = self.input[start..self.pos].trim_end();
+        let (text, important) = match text.rfind('!') {
+            Some(i) if text[i + 1..].trim().eq_ignore_ascii_case("important") => {
+                (text[..i].trim_end(), true)
+            }
+            _ => (text, false),
+        };
+        let text = text.to_string();
+        if self.peek() == Some(';') {
+            self.pos += 1;
+        }
+        (text, important)
+    }
+
+    /// `;`、`}`、`!`の手前まで、空白で区切られた値の並びを読みます。
+    fn parse_components(&mut self) -> ParseResult<Vec<Component>> {
+        let mut components = Vec::new();
+        loop {
+            self.consume_whitespace();
+            match self.peek() {
+                None | Some(';' | '}' | '!') => break,
+                Some('/') => {
+                    self.pos += 1;
+                    components.push(Component::Slash);
+                }
+                Some(',') => {
+                    self.pos += 1;
+                    components.push(Component::Comma);
+                }
+                Some('"' | '\'') => {
+                    components.push(Component::Value(Value::Keyword(self.parse_string()?)))
+                }
+                Some(_) => components.push(Component::Value(self.parse_value()?)),
+            }
+        }
+        if components.is_empty() {
+            return Err(self.error(ErrorKind::MissingValue(self.next_char()?)));
+        }
+        Ok(components)
+    }
+
+    /// 値の後の`!important`を読みます。
+    fn parse_important(&mut self) -> ParseResult<bool> {
+        if self.peek() != Some('!') {
+            return Ok(false);
+        }
+        self.pos += 1;
+        self.consume_whitespace();
+        let start = self.pos;
+        let keyword = self.parse_identifier();
+        if !keyword.eq_ignore_ascii_case("important") {
+            return Err(self.error_at(start, ErrorKind::InvalidImportant(keyword)));
+        }
+        self.consume_whitespace();
+        Ok(true)
+    }
+
+    // 数値、色、キーワード、関数を解析します。
+    fn parse_value(&mut self) -> ParseResult<Value> {
+        match self.next_char()? {
+            '0'..='9' | '.' | '-' | '+' if self.starts_number() => self.parse_length(),
+            '#' => self.parse_color(),
+            c if valid_identifier_char(c) => {
+                let start = self.pos;
+                let name = self.parse_identifier();
+                if self.peek() != Some('(') {
+                    return Ok(keyword_value(name));
+                }
+                self.pos += 1;
+                match &*name.to_ascii_lowercase() {
+                    "calc" | "min" | "max" | "clamp" => Ok(Value::Calc(Box::new(
+                        self.parse_math_function(start, &name)?,
+                    ))),
+                    "rgb" | "rgba" | "hsl" | "hsla" => {
+                        Ok(Value::ColorValue(self.parse_color_function(start, &name)?))
+                    }
+                    _ => Err(self.error_at(start, ErrorKind::UnknownFunction(name))),
+                }
+            }
+            c => Err(self.error(ErrorKind::MissingValue(c))),
+        }
+    }
+
+    /// `calc()`、`min()`、`max()`、`clamp()`の引数を解析します。`(`までは読んだ後です。
+    fn parse_math_function(&mut self, start: usize, name: &str) -> ParseResult<Calc> {
+        let mut args = Vec::new();
+        loop {
+            args.push(self.parse_calc_sum()?);
+            self.consume_whitespace();
+            let pos = self.pos;
+            match self.consume_char()? {
+                ',' => continue,
+                ')' => break,
+                found => {
+                    let kind = ErrorKind::UnexpectedChar {
+                        expected: ')',
+                        found,
+                    };
+                    return Err(self.error_at(pos, kind));
+                }
+            }
+        }
+        let calc = match (&*name.to_ascii_lowercase(), args.len()) {
+            ("calc", 1) => args.pop(),
+            ("min", _) => Some(Calc::Min(args)),
+            ("max", _) => Some(Calc::Max(args)),
+            ("clamp", 3) => {
+                let mut args = args.into_iter().map(Box::new);
+                match (args.next(), args.next(), args.next()) {
+                    (Some(min), Some(value), Some(max)) => Some(Calc::Clamp(min, value, max)),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        let calc = calc
+            .ok_or_else(|| self.error_at(start, ErrorKind::InvalidArguments(name.to_string())))?;
+        // `min()`などの引数は、すべて同じ種類でなければならない。
+        if calc.resolve_type().is_none() {
+            return Err(self.error_at(start, ErrorKind::InvalidCalc));
+        }
+        Ok(calc)
+    }
+
+    /// `a + b - c`のような和と差を解析します。`+`と`-`の前後には空白が必要です。
+    fn parse_calc_sum(&mut self) -> ParseResult<Calc> {
+        let mut left = self.parse_calc_product()?;
+        loop {
+            let end = self.pos;
+            self.consume_whitespace();
+            let op_pos = self.pos;
+            let (op, c): (fn(_, _) -> _, _) = match self.peek() {
+                Some('+') => (Calc::Sum, '+'),
+                Some('-') => (Calc::Difference, '-'),
+                _ => return Ok(left),
+            };
+            self.pos += 1;
+            if op_pos == end || !self.peek().is_some_and(|c| c.is_ascii_whitespace()) {
+                return Err(self.error_at(op_pos, ErrorKind::MissingWhitespaceAroundOperator(c)));
+            }
+            let right = self.parse_calc_product()?;
+            left = self.typed_calc(op_pos, op(Box::new(left), Box::new(right)))?;
+        }
+    }
+
+    /// `a * b / c`のような積と商を解析します。
+    fn parse_calc_product(&mut self) -> ParseResult<Calc> {
+        let mut left = self.parse_calc_value()?;
+        loop {
+            let end = self.pos;
+            self.consume_whitespace();
+            let op_pos = self.pos;
+            let op = match self.peek() {
+                Some('*') => Calc::Product,
+                Some('/') => Calc::Quotient,
+                _ => {
+                    // 和の`+`や`-`の前に空白があるかどうかを確かめられるように、空白は読まずに残す。
+                    self.pos = end;
+                    return Ok(left);
+                }
+            };
+            self.pos += 1;
+            let right = self.parse_calc_value()?;
+            left = self.typed_calc(op_pos, op(Box::new(left), Box::new(right)))?;
+        }
+    }
+
+    /// 組み立てた式の型を確かめます。型が合わなければ、演算子の位置のエラーを返します。
+    fn typed_calc(&self, op_pos: usize, calc: Calc) -> ParseResult<Calc> {
+        match calc.resolve_type() {
+            Some(_) => Ok(calc),
+            None => Err(self.error_at(op_pos, ErrorKind::InvalidCalc)),
+        }
+    }
+
+    /// 式の中の数値、長さ、括弧、入れ子の関数を解析します。
+    fn parse_calc_value(&mut self) -> ParseResult<Calc> {
+        self.consume_whitespace();
+        match self.next_char()? {
+            '0'..='9' | '.' | '-' | '+' if self.starts_number() => {
+                Ok(Calc::Value(self.parse_length()?))
+            }
+            '(' => {
+                self.pos += 1;
+                let calc = self.parse_calc_sum()?;
+                self.consume_whitespace();
+                self.expect_char(')')?;
+                Ok(calc)
+            }
+            c if valid_identifier_char(c) => {
+                let start = self.pos;
+                let name = self.parse_identifier();
+                if self.peek() != Some('(') {
+                    return Err(self.error_at(start, ErrorKind::MissingValue(c)));
+                }
+                self.pos += 1;
+                match &*name.to_ascii_lowercase() {
+                    "calc" | "min" | "max" | "clamp" => self.parse_math_function(start, &name),
+                    _ => Err(self.error_at(start, ErrorKind::UnknownFunction(name))),
+                }
+            }
+            c => Err(self.error(ErrorKind::MissingValue(c))),
+        }
+    }
+
+    /// 符号の付いた数値や`.5`のような小数点で始まる数値が続くかどうか。
+    /// `-webkit-box`のような`-`で始まる識別子とは区別します。
+    fn starts_number(&self) -> bool {
+        let rest = &self.input[self.pos..];
+        let rest = rest.strip_prefix(['-', '+']).unwrap_or(rest);
+        let rest = rest.strip_prefix('.').unwrap_or(rest);
+        rest.starts_with(|c: char| c.is_ascii_digit())
+    }
+
+    /// `-10px`や`.5em`のような、符号の付けられる数値と単位を解析します。
+    /// 単位が続けば長さ、続かなければ数値として読みます。
+    fn parse_length(&mut self) -> ParseResult<Value> {
+        let value = self.parse_float()?;
+        match self.peek() {
+            Some('%') => {
+                self.pos += 1;
+                Ok(Value::Length(value, Unit::Percent))
+            }
+            Some(c) if valid_identifier_char(c) => Ok(Value::Length(value, self.parse_unit()?)),
+            _ => Ok(Value::Number(value)),
+        }
+    }
+
+    fn parse_float(&mut self) -> ParseResult<f32> {
+        let start = self.pos;
+        let mut s = String::new();
+        if let Some(sign @ ('-' | '+')) = self.peek() {
+            self.pos += 1;
+            s.push(sign);
+        }
+        s.push_str(&self.consume_while(|c| matches!(c, '0'..='9' | '.')));
+        s.parse()
+            .map_err(|_| self.error_at(start, ErrorKind::InvalidNumber(s)))
+    }
+
+    fn parse_unit(&mut self) -> ParseResult<Unit> {
+        let start = self.pos;
+        let unit = self.parse_identifier();
+        match &*unit.to_ascii_lowercase() {
+            "px" => Ok(Unit::Px),
+            "em" => Ok(Unit::Em),
+            "rem" => Ok(Unit::Rem),
+            "vw" => Ok(Unit::Vw),
+            "vh" => Ok(Unit::Vh),
+            "pt" => Ok(Unit::Pt),
+            "cm" => Ok(Unit::Cm),
+            _ => Err(self.error_at(start, ErrorKind::UnknownUnit(unit))),
+        }
+    }
+
+    /// `#rgb`、`#rgba`、`#rrggbb`、`#rrggbbaa`を解析します。
+    fn parse_color(&mut self) -> ParseResult<Value> {
+        self.expect_char('#')?;
+        let start = self.pos;
+        let hex = self.consume_while(|c| c.is_ascii_alphanumeric());
+        let digits: Option<Vec<u8>> = hex
+            .chars()
+            .map(|c| c.to_digit(16).map(|d| d as u8))
+            .collect();
+        let channels: Vec<u8> = match (digits, hex.len()) {
+            // 短い形式では各桁を2回繰り返す。
+            (Some(digits), 3 | 4) => digits.iter().map(|d| d * 17).collect(),
+            (Some(digits), 6 | 8) => digits
+                .chunks(2)
+                .map(|pair| pair[0] * 16 + pair[1])
+                .collect(),
+            _ => return Err(self.error_at(start, ErrorKind::InvalidColor(hex))),
+        };
+        Ok(Value::ColorValue(Color {
+            r: channels[0],
+            g: channels[1],
+            b: channels[2],
+            a: channels.get(3).copied().unwrap_or(255),
+        }))
+    }
+
+    /// `rgb()`、`rgba()`、`hsl()`、`hsla()`の引数を解析します。`(`までは読んだ後です。
+    /// `rgb(255, 0, 0, 0.5)`のようなカンマ区切りと、`rgb(255 0 0 / 50%)`のような空白区切りの両方を受け付けます。
+    fn parse_color_function(&mut self, start: usize, name: &str) -> ParseResult<Color> {
+        let mut args = Vec::new();
+        let mut alpha = None;
+        loop {
+            self.consume_whitespace();
+            match self.next_char()? {
+                ')' => {
+                    self.pos += 1;
+                    break;
+                }
+                ',' => self.pos += 1,
+                '/' => {
+                    self.pos += 1;
+                    self.consume_whitespace();
+                    alpha = Some(self.parse_dimension()?);
+                }
+                _ => args.push(self.parse_dimension()?),
+            }
+        }
+        if args.len() == 4 && alpha.is_none() {
+            alpha = args.pop();
+        }
+        let [first, second, third] = &args[..] else {
+            return Err(self.error_at(start, ErrorKind::InvalidArguments(name.to_string())));
+        };
+
+        let (r, g, b) = if name.to_ascii_lowercase().starts_with("rgb") {
+            // 各チャンネルは0〜255の数値か、`%`。
+            if let Some((_, unit)) = args.iter().find(|(_, unit)| !matches!(&**unit, "" | "%")) {
+                return Err(self.error_at(start, ErrorKind::UnknownUnit(unit.clone())));
+            }
+            let channel = |(n, unit): &(f32, String)| match &**unit {
+                "%" => n * 2.55,
+                _ => *n,
+            };
+            (channel(first), channel(second), channel(third))
+        } else {
+            // 色相は度数。彩度と明度は`%`だが、単位のない数値も同じように扱う。
+            let (n, unit) = first;
+            let hue = match &*unit.to_ascii_lowercase() {
+                "" | "deg" => *n,
+                "rad" => n.to_degrees(),
+                "grad" => n * 0.9,
+                "turn" => n * 360.0,
+                _ => return Err(self.error_at(start, ErrorKind::UnknownUnit(unit.clone()))),
+            };
+            hsl_to_rgb(hue, second.0 / 100.0, third.0 / 100.0)
+        };
+        let a = match alpha {
+            Some((n, unit)) if unit == "%" => n / 100.0,
+            Some((n, _)) => n,
+            None => 1.0,
+        };
+        let to_u8 = |c: f32| c.round().clamp(0.0, 255.0) as u8;
+        Ok(Color {
+            r: to_u8(r),
+            g: to_u8(g),
+            b: to_u8(b),
+            a: to_u8(a * 255.0),
+        })
+    }
+
+    /// 色の関数の引数の、符号つきの数値と単位を読みます。単位がなければ空文字列です。
+    fn parse_dimension(&mut self) -> ParseResult<(f32, String)> {
+        let n = self.parse_float()?;
+        let unit = match self.peek() {
+            Some('%') => {
+                self.pos += 1;
+                "%".to_string()
+            }
+            _ => self.parse_identifier(),
+        };
+        Ok((n, unit))
+    }
+
+    /// Parse a property name or keyword.
+    fn parse_identifier(&mut self) -> String {
+        self.consume_while(valid_identifier_char)
+    }
+
+    /// Consume and discard zero or more whitespace characters.
+    /// `/* ... */`のコメントも空白として読み飛ばします。閉じていないコメントは入力の終わりまで続きます。
     fn consume_whitespace(&mut self) {
-        self.consume_while(char::is_whitespace);
+        loop {
+            self.consume_while(char::is_whitespace);
+            if !self.input[self.pos..].starts_with("/*") {
+                break;
+            }
+            self.pos = match self.input[self.pos + 2..].find("*/") {
+                Some(end) => self.pos + 2 + end + 2,
+                None => self.input.len(),
+            };
+        }
     }
 
     /// Consume characters until `test` returns false.
@@ -261,35 +1827,1183 @@ impl Parser {
         F: Fn(char) -> bool,
     {
         let mut result = String::new();
-        while !self.eof() && test(self.next_char()) {
-            result.push(self.consume_char());
+        while let Some(c) = self.peek() {
+            if !test(c) {
+                break;
+            }
+            self.pos += c.len_utf8();
+            result.push(c);
         }
         result
     }
 
     /// Return the current character, and advance self.pos to the next character.
-    fn consume_char(&mut self) -> char {
-        let mut iter = self.input[self.pos..].char_indices();
-        let (_, cur_char) = iter.next().unwrap();
-        let (next_pos, _) = iter.next().unwrap_or((1, ' '));
-        self.pos += next_pos;
-        cur_char
+    fn consume_char(&mut self) -> ParseResult<char> {
+        let cur_char = self.next_char()?;
+        self.pos += cur_char.len_utf8();
+        Ok(cur_char)
+    }
+
+    /// Consume the current character, failing unless it is `expected`.
+    fn expect_char(&mut self, expected: char) -> ParseResult<()> {
+        let start = self.pos;
+        match self.consume_char()? {
+            c if c == expected => Ok(()),
+            found => Err(self.error_at(start, ErrorKind::UnexpectedChar { expected, found })),
+        }
     }
 
     /// Read the current character without consuming it.
-    fn next_char(&self) -> char {
-        self.input[self.pos..].chars().next().unwrap()
+    fn next_char(&self) -> ParseResult<char> {
+        self.peek()
+            .ok_or_else(|| self.error(ErrorKind::UnexpectedEof))
+    }
+
+    /// Read the current character, or `None` at the end of input.
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
     }
 
-    /// Return true if all input is consumed.
-    fn eof(&self) -> bool {
-        self.pos >= self.input.len()
+    /// Build an error of the given kind at the current position.
+    fn error(&self, kind: ErrorKind) -> ParseError {
+        self.error_at(self.pos, kind)
+    }
+
+    fn error_at(&self, pos: usize, kind: ErrorKind) -> ParseError {
+        ParseError {
+            kind,
+            position: Position::new(&self.input, pos),
+        }
     }
 }
 
-fn valid_identifier_char(c: char) -> bool {
-    match c {
-        'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => true, // TODO: Include U+00A0 and higher.
+/// このエンジンが知っている個別のプロパティ
+const LONGHANDS: &[&str] = &[
+    "display",
+    "width",
+    "height",
+    "margin-top",
+    "margin-right",
+    "margin-bottom",
+    "margin-left",
+    "padding-top",
+    "padding-right",
+    "padding-bottom",
+    "padding-left",
+    "border-top-width",
+    "border-right-width",
+    "border-bottom-width",
+    "border-left-width",
+    "border-top-style",
+    "border-right-style",
+    "border-bottom-style",
+    "border-left-style",
+    "border-top-color",
+    "border-right-color",
+    "border-bottom-color",
+    "border-left-color",
+    "background-color",
+    "color",
+    "font-family",
+    "font-size",
+    "font-style",
+    "font-weight",
+    "line-height",
+    "text-align",
+    "visibility",
+    "white-space",
+];
+
+/// 知っているプロパティかどうか。一括指定プロパティも含みます。
+fn is_known_property(name: &str) -> bool {
+    LONGHANDS.contains(&name) || longhands(name).is_some()
+}
+
+/// 個別のプロパティ`name`の値として正しいかどうか。`inherit`、`initial`、`unset`はどのプロパティにも使えます。
+fn is_valid_value(name: &str, value: &Value) -> bool {
+    if is_keyword(value, &["inherit", "initial", "unset"]) {
+        return true;
+    }
+    match name {
+        "display" => is_keyword(value, DISPLAY_KEYWORDS),
+        "width" | "height" => is_keyword(value, &["auto"]) || is_length(value, true, true),
+        "margin-top" | "margin-right" | "margin-bottom" | "margin-left" => {
+            is_keyword(value, &["auto"]) || is_length(value, true, false)
+        }
+        "padding-top" | "padding-right" | "padding-bottom" | "padding-left" => {
+            is_length(value, true, true)
+        }
+        "border-top-width" | "border-right-width" | "border-bottom-width" | "border-left-width" => {
+            is_keyword(value, &["thin", "medium", "thick"]) || is_length(value, false, true)
+        }
+        "border-top-style" | "border-right-style" | "border-bottom-style" | "border-left-style" => {
+            is_keyword(value, BORDER_STYLES)
+        }
+        "border-top-color"
+        | "border-right-color"
+        | "border-bottom-color"
+        | "border-left-color"
+        | "background-color"
+        | "color" => matches!(value, Value::ColorValue(_)) || is_keyword(value, &["currentcolor"]),
+        "font-family" => match value {
+            Value::Keyword(_) => true,
+            Value::List(families) => families.iter().all(|f| matches!(f, Value::Keyword(_))),
+            _ => false,
+        },
+        "font-size" => is_keyword(value, FONT_SIZE_KEYWORDS) || is_length(value, true, true),
+        "font-style" => is_keyword(value, &["normal", "italic", "oblique"]),
+        "font-weight" => match value {
+            Value::Number(n) => (1.0..=1000.0).contains(n),
+            _ => is_keyword(value, &["normal", "bold", "bolder", "lighter"]),
+        },
+        "line-height" => match value {
+            Value::Number(n) => *n >= 0.0,
+            Value::Calc(calc) if calc.resolve_type() == Some(CalcType::Number) => true,
+            _ => is_keyword(value, &["normal"]) || is_length(value, true, true),
+        },
+        "text-align" => is_keyword(
+            value,
+            &["start", "end", "left", "right", "center", "justify"],
+        ),
+        "visibility" => is_keyword(value, &["visible", "hidden", "collapse"]),
+        "white-space" => is_keyword(
+            value,
+            &[
+                "normal",
+                "pre",
+                "nowrap",
+                "pre-wrap",
+                "pre-line",
+                "break-spaces",
+            ],
+        ),
         _ => false,
     }
 }
+
+/// `keywords`のどれかと、大文字と小文字を区別せずに一致するキーワードかどうか。
+fn is_keyword(value: &Value, keywords: &[&str]) -> bool {
+    match value {
+        Value::Keyword(keyword) => keywords.iter().any(|k| keyword.eq_ignore_ascii_case(k)),
+        _ => false,
+    }
+}
+
+/// 長さかどうか。単位のない`0`も長さです。
+/// `percent`なら`%`も許し、`non_negative`なら負の値を許しません。
+fn is_length(value: &Value, percent: bool, non_negative: bool) -> bool {
+    match value {
+        Value::Length(n, unit) => {
+            (percent || *unit != Unit::Percent) && !(non_negative && *n < 0.0)
+        }
+        Value::Number(n) => *n == 0.0,
+        Value::Calc(calc) => match calc.resolve_type() {
+            Some(CalcType::Length) => true,
+            Some(CalcType::Percentage | CalcType::LengthPercentage) => percent,
+            Some(CalcType::Number) | None => false,
+        },
+        _ => false,
+    }
+}
+
+/// `@font-face`の中で使える記述子かどうか。
+fn is_font_descriptor(name: &str) -> bool {
+    matches!(
+        name,
+        "font-family" | "src" | "font-style" | "font-weight" | "font-display" | "unicode-range"
+    )
+}
+
+/// メディア特性の幅・高さとして使える値かどうか。単位のない数は`0`だけを許します。
+fn is_media_length(value: &Value) -> bool {
+    match value {
+        Value::Length(..) => true,
+        Value::Number(n) => *n == 0.0,
+        _ => false,
+    }
+}
+
+/// キーワードを値にします。名前付きの色と`transparent`は色になります。
+/// `currentColor`は要素の`color`の値に決まるまでキーワードのまま残します。
+fn keyword_value(keyword: String) -> Value {
+    if keyword.eq_ignore_ascii_case("transparent") {
+        return Value::ColorValue(Color::default());
+    }
+    match colors::lookup(&keyword) {
+        Some(color) => Value::ColorValue(color),
+        None => Value::Keyword(keyword),
+    }
+}
+
+// Shorthands:
+
+const SIDES: [&str; 4] = ["top", "right", "bottom", "left"];
+
+const DISPLAY_KEYWORDS: &[&str] = &[
+    "none",
+    "block",
+    "inline",
+    "inline-block",
+    "list-item",
+    "flow-root",
+    "flex",
+    "inline-flex",
+    "grid",
+    "inline-grid",
+    "table",
+    "inline-table",
+    "table-row",
+    "table-cell",
+    "contents",
+];
+
+const FONT_SIZE_KEYWORDS: &[&str] = &[
+    "xx-small",
+    "x-small",
+    "small",
+    "medium",
+    "large",
+    "x-large",
+    "xx-large",
+    "xxx-large",
+    "smaller",
+    "larger",
+];
+
+const BORDER_STYLES: &[&str] = &[
+    "none", "hidden", "dotted", "dashed", "solid", "double", "groove", "ridge", "inset", "outset",
+];
+
+/// `background`の値のうち、色以外を表すキーワード。画像や位置は今のところ無視します。
+const BACKGROUND_KEYWORDS: &[&str] = &[
+    "none",
+    "repeat",
+    "repeat-x",
+    "repeat-y",
+    "no-repeat",
+    "space",
+    "round",
+    "scroll",
+    "fixed",
+    "local",
+    "top",
+    "bottom",
+    "left",
+    "right",
+    "center",
+    "border-box",
+    "padding-box",
+    "content-box",
+];
+
+/// 一括指定プロパティが設定する個別のプロパティの名前を返します。一括指定でなければ`None`です。
+fn longhands(name: &str) -> Option<Vec<String>> {
+    let names = match name {
+        "margin" | "padding" => SIDES.map(|side| format!("{}-{}", name, side)).to_vec(),
+        "border-width" | "border-style" | "border-color" => {
+            let kind = &name["border-".len()..];
+            SIDES
+                .map(|side| format!("border-{}-{}", side, kind))
+                .to_vec()
+        }
+        "border" => ["width", "style", "color"]
+            .iter()
+            .flat_map(|kind| SIDES.map(|side| format!("border-{}-{}", side, kind)))
+            .collect(),
+        "background" => vec!["background-color".to_string()],
+        "font" => [
+            "font-style",
+            "font-weight",
+            "font-size",
+            "line-height",
+            "font-family",
+        ]
+        .map(String::from)
+        .to_vec(),
+        _ => return None,
+    };
+    Some(names)
+}
+
+/// 宣言を個別のプロパティと値の組に展開します。値の並びがプロパティに合わなければ`None`を返します。
+fn expand_shorthand(name: &str, components: Vec<Component>) -> Option<Vec<(String, Value)>> {
+    let longhands = expand_components(name, components)?;
+    longhands
+        .iter()
+        .all(|(name, value)| is_valid_value(name, value))
+        .then_some(longhands)
+}
+
+/// 値の並びを、個別のプロパティごとの値に分けます。それぞれの値が正しいかどうかはまだ確かめません。
+fn expand_components(name: &str, components: Vec<Component>) -> Option<Vec<(String, Value)>> {
+    let Some(names) = longhands(name) else {
+        let value = single_value(components)?;
+        let value = match name {
+            "border-top-width"
+            | "border-right-width"
+            | "border-bottom-width"
+            | "border-left-width" => border_width(value),
+            _ => value,
+        };
+        return Some(vec![(name.to_string(), value)]);
+    };
+
+    // `inherit`などはすべての個別のプロパティにそのまま設定する。
+    if let [Component::Value(Value::Keyword(keyword))] = &components[..] {
+        if matches!(
+            &*keyword.to_ascii_lowercase(),
+            "inherit" | "initial" | "unset"
+        ) {
+            let value = Value::Keyword(keyword.clone());
+            return Some(names.into_iter().map(|n| (n, value.clone())).collect());
+        }
+    }
+
+    let values = match name {
+        "margin" | "padding" | "border-style" | "border-color" => four_sides(components)?.to_vec(),
+        "border-width" => four_sides(components)?.map(border_width).to_vec(),
+        "border" => {
+            let [width, style, color] = expand_border(components)?;
+            [width, style, color]
+                .into_iter()
+                .flat_map(|value| [value.clone(), value.clone(), value.clone(), value])
+                .collect()
+        }
+        "background" => vec![expand_background(components)?],
+        "font" => expand_font(components)?.to_vec(),
+        _ => unreachable!("{} has longhands", name),
+    };
+    Some(names.into_iter().zip(values).collect())
+}
+
+/// 一括指定でないプロパティの値。複数の値が並んでいれば`Value::List`にまとめます。
+fn single_value(components: Vec<Component>) -> Option<Value> {
+    let mut values: Vec<Value> = components
+        .into_iter()
+        .filter_map(|component| match component {
+            Component::Value(value) => Some(value),
+            Component::Slash | Component::Comma => None,
+        })
+        .collect();
+    match values.len() {
+        0 => None,
+        1 => values.pop(),
+        _ => Some(Value::List(values)),
+    }
+}
+
+/// 区切りを含まない値だけの並びを返します。
+fn plain_values(components: Vec<Component>) -> Option<Vec<Value>> {
+    components
+        .into_iter()
+        .map(|component| match component {
+            Component::Value(value) => Some(value),
+            Component::Slash | Component::Comma => None,
+        })
+        .collect()
+}
+
+/// 1〜4個の値を、上・右・下・左の順に割り当てます。
+fn four_sides(components: Vec<Component>) -> Option<[Value; 4]> {
+    let values = plain_values(components)?;
+    let sides = match &values[..] {
+        [all] => [all, all, all, all],
+        [vertical, horizontal] => [vertical, horizontal, vertical, horizontal],
+        [top, horizontal, bottom] => [top, horizontal, bottom, horizontal],
+        [top, right, bottom, left] => [top, right, bottom, left],
+        _ => return None,
+    };
+    Some(sides.map(Clone::clone))
+}
+
+/// `thin`、`medium`、`thick`をpxに直します。
+fn border_width(value: Value) -> Value {
+    match &value {
+        Value::Keyword(keyword) => match &*keyword.to_ascii_lowercase() {
+            "thin" => Value::Length(1.0, Unit::Px),
+            "medium" => Value::Length(3.0, Unit::Px),
+            "thick" => Value::Length(5.0, Unit::Px),
+            _ => value,
+        },
+        _ => value,
+    }
+}
+
+/// `border: <width> || <style> || <color>`を幅・線種・色に分けます。省略されたものは初期値になります。
+fn expand_border(components: Vec<Component>) -> Option<[Value; 3]> {
+    let (mut width, mut style, mut color) = (None, None, None);
+    for value in plain_values(components)? {
+        let slot = match &value {
+            Value::Length(..) | Value::Number(_) => &mut width,
+            Value::Keyword(keyword) => match &*keyword.to_ascii_lowercase() {
+                "thin" | "medium" | "thick" => &mut width,
+                keyword if BORDER_STYLES.contains(&keyword) => &mut style,
+                _ => &mut color,
+            },
+            _ => &mut color,
+        };
+        if slot.replace(value).is_some() {
+            return None;
+        }
+    }
+    Some([
+        border_width(width.unwrap_or_else(|| Value::Keyword("medium".to_string()))),
+        style.unwrap_or_else(|| Value::Keyword("none".to_string())),
+        color.unwrap_or_else(|| Value::Keyword("currentcolor".to_string())),
+    ])
+}
+
+/// `background`から背景色を取り出します。色がなければ`transparent`です。
+fn expand_background(components: Vec<Component>) -> Option<Value> {
+    let mut color = None;
+    for component in components {
+        let value = match component {
+            Component::Value(value) => value,
+            Component::Slash | Component::Comma => continue,
+        };
+        match &value {
+            Value::Length(..) | Value::Number(_) => continue,
+            Value::Keyword(keyword)
+                if BACKGROUND_KEYWORDS.contains(&&*keyword.to_ascii_lowercase()) =>
+            {
+                continue
+            }
+            _ => {}
+        }
+        if color.replace(value).is_some() {
+            return None;
+        }
+    }
+    Some(color.unwrap_or_else(|| keyword_value("transparent".to_string())))
+}
+
+/// `font: [<style> || <weight>]? <size> [/ <line-height>]? <family>#`を展開します。
+/// 結果は`font-style`、`font-weight`、`font-size`、`line-height`、`font-family`の順です。
+fn expand_font(components: Vec<Component>) -> Option<[Value; 5]> {
+    let normal = || Value::Keyword("normal".to_string());
+    let (mut style, mut weight) = (None, None);
+    let mut components = components.into_iter().peekable();
+
+    // フォントサイズより前にはスタイルと太さが任意の順で並ぶ。
+    let size = loop {
+        let Component::Value(value) = components.next()? else {
+            return None;
+        };
+        let slot = match &value {
+            Value::Number(_) => &mut weight,
+            Value::Keyword(keyword) => match &*keyword.to_ascii_lowercase() {
+                "normal" => continue,
+                "italic" | "oblique" => &mut style,
+                "bold" | "bolder" | "lighter" => &mut weight,
+                _ => break value,
+            },
+            _ => break value,
+        };
+        if slot.replace(value).is_some() {
+            return None;
+        }
+    };
+
+    let line_height = if components
+        .next_if(|component| matches!(component, Component::Slash))
+        .is_some()
+    {
+        match components.next()? {
+            Component::Value(value) => value,
+            Component::Slash | Component::Comma => return None,
+        }
+    } else {
+        normal()
+    };
+    let family = single_value(components.collect())?;
+
+    Some([
+        style.unwrap_or_else(normal),
+        weight.unwrap_or_else(normal),
+        size,
+        line_height,
+        family,
+    ])
+}
+
+/// 空白を除いた`an+b`の式を`(a, b)`にします。
+fn parse_an_plus_b(expr: &str) -> Option<(i32, i32)> {
+    match expr {
+        "odd" => return Some((2, 1)),
+        "even" => return Some((2, 0)),
+        _ => {}
+    }
+    let Some(n) = expr.find('n') else {
+        return Some((0, expr.parse().ok()?));
+    };
+    let a = match &expr[..n] {
+        "" | "+" => 1,
+        "-" => -1,
+        a => a.parse().ok()?,
+    };
+    let b = match &expr[n + 1..] {
+        "" => 0,
+        b if b.starts_with(['+', '-']) => b.parse().ok()?,
+        _ => return None,
+    };
+    Some((a, b))
+}
+
+/// 色相（度）、彩度、明度（0〜1）を0〜255のRGBにします。
+fn hsl_to_rgb(hue: f32, saturation: f32, lightness: f32) -> (f32, f32, f32) {
+    let s = saturation.clamp(0.0, 1.0);
+    let l = lightness.clamp(0.0, 1.0);
+    let hue = hue.rem_euclid(360.0);
+    // https://www.w3.org/TR/css-color-4/#hsl-to-rgb
+    let f = |n: f32| {
+        let k = (n + hue / 30.0) % 12.0;
+        let a = s * l.min(1.0 - l);
+        (l - a * (k - 3.0).min(9.0 - k).clamp(-1.0, 1.0)) * 255.0
+    };
+    (f(0.0), f(8.0), f(4.0))
+}
+
+/// `text`の中の`var(--name)`と`var(--name, fallback)`を、`lookup`で求めたカスタムプロパティの値に置き換えます。
+/// 参照先が無効で代替値もない`var()`があるときは、値全体が無効になり`None`を返します。
+pub fn substitute_vars(
+    text: &str,
+    lookup: &mut dyn FnMut(&str) -> Option<String>,
+) -> Option<String> {
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some(start) = find_var(rest) {
+        result.push_str(&rest[..start]);
+        let args = &rest[start + "var(".len()..];
+        // 閉じ括弧のない`var(`は入力の終わりで閉じたものとみなす。
+        let end = find_top_level(args, ')').unwrap_or(args.len());
+        let (name, fallback) = match find_top_level(&args[..end], ',') {
+            Some(comma) => (&args[..comma], Some(&args[comma + 1..end])),
+            None => (&args[..end], None),
+        };
+        let value = match lookup(name.trim()) {
+            Some(value) => value,
+            None => substitute_vars(fallback?.trim(), lookup)?,
+        };
+        result.push_str(&value);
+        rest = args.get(end + 1..).unwrap_or("");
+    }
+    result.push_str(rest);
+    Some(result)
+}
+
+/// 文字列の外にある`var(`の位置を探します。大文字と小文字は区別しません。
+fn find_var(text: &str) -> Option<usize> {
+    let mut quote = None;
+    let mut prev = None;
+    for (i, c) in text.char_indices() {
+        match quote {
+            Some(q) if c == q && prev != Some('\\') => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if !prev.is_some_and(valid_identifier_char)
+                && text[i..]
+                    .get(..4)
+                    .is_some_and(|s| s.eq_ignore_ascii_case("var(")) =>
+            {
+                return Some(i)
+            }
+            None => {}
+        }
+        prev = Some(c);
+    }
+    None
+}
+
+/// 括弧と文字列の外にある`target`の位置を探します。
+fn find_top_level(text: &str, target: char) -> Option<usize> {
+    let mut depth = 0;
+    let mut quote = None;
+    let mut prev = None;
+    for (i, c) in text.char_indices() {
+        match quote {
+            Some(q) if c == q && prev != Some('\\') => quote = None,
+            Some(_) => {}
+            None if c == target && depth == 0 => return Some(i),
+            None => match c {
+                '"' | '\'' => quote = Some(c),
+                '(' | '[' | '{' => depth += 1,
+                ')' | ']' | '}' => depth -= 1,
+                _ => {}
+            },
+        }
+        prev = Some(c);
+    }
+    None
+}
+
+fn valid_identifier_char(c: char) -> bool {
+    // TODO: Include U+00A0 and higher.
+    matches!(c, 'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 宣言の並びを解析して、エラーがないことを確かめてから`(名前, 値)`の並びを返します。
+    fn declarations(source: &str) -> Vec<(String, Value)> {
+        let (declarations, errors) = parse_declaration_list(source.to_string());
+        assert!(errors.is_empty(), "{:?}", errors);
+        declarations
+            .into_iter()
+            .map(|declaration| (declaration.name, declaration.value))
+            .collect()
+    }
+
+    fn value(source: &str) -> Value {
+        let mut values = declarations(source);
+        assert_eq!(values.len(), 1);
+        values.pop().unwrap().1
+    }
+
+    /// 宣言の並びを解析して、エラーの種類を返します。
+    fn error_kinds(source: &str) -> Vec<ErrorKind> {
+        let (_, errors) = parse_declaration_list(source.to_string());
+        errors.into_iter().map(|e| e.kind).collect()
+    }
+
+    fn px(n: f32) -> Value {
+        Value::Length(n, Unit::Px)
+    }
+
+    fn keyword(keyword: &str) -> Value {
+        Value::Keyword(keyword.to_string())
+    }
+
+    /// `(名前, 値)`の並びを作ります。
+    fn pairs<const N: usize>(pairs: [(&str, Value); N]) -> Vec<(String, Value)> {
+        pairs
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect()
+    }
+
+    #[test]
+    fn negative_length() {
+        assert_eq!(value("margin-left: -10px"), Value::Length(-10.0, Unit::Px));
+        assert_eq!(value("margin-left: +4em"), Value::Length(4.0, Unit::Em));
+        assert_eq!(value("line-height: +1.5"), Value::Number(1.5));
+    }
+
+    #[test]
+    fn leading_decimal_point() {
+        assert_eq!(value("font-size: .5em"), Value::Length(0.5, Unit::Em));
+        assert_eq!(value("margin-top: -.25px"), Value::Length(-0.25, Unit::Px));
+        assert_eq!(value("width: .5%"), Value::Length(0.5, Unit::Percent));
+    }
+
+    #[test]
+    fn signed_values_in_shorthand() {
+        let values: Vec<Value> = declarations("margin: -1px .5em")
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect();
+        let (a, b) = (Value::Length(-1.0, Unit::Px), Value::Length(0.5, Unit::Em));
+        assert_eq!(values, [a.clone(), b.clone(), a, b]);
+    }
+
+    #[test]
+    fn signed_values_in_calc() {
+        let Value::Calc(calc) = value("width: calc(-.5 * 10px + -2px)") else {
+            panic!("expected calc()");
+        };
+        assert_eq!(calc.evaluate(None), Some(-7.0));
+    }
+
+    #[test]
+    fn dash_identifier_is_keyword() {
+        assert_eq!(
+            value("font-family: -apple-system"),
+            Value::Keyword("-apple-system".to_string())
+        );
+    }
+
+    /// `@media`の規則を1つだけ含むスタイルシートを解析して、そのメディアクエリとエラーの数を返します。
+    fn media_queries(prelude: &str) -> (Vec<MediaQuery>, usize) {
+        let source = format!("@media {} {{ p {{ color: red }} }}", prelude);
+        let (mut stylesheet, errors) = parse(source, Origin::Author);
+        assert_eq!(stylesheet.rules.len(), 1);
+        let Some(CssRule::Media(media)) = stylesheet.rules.pop() else {
+            panic!("expected @media");
+        };
+        (media.queries, errors.len())
+    }
+
+    fn not_all() -> MediaQuery {
+        MediaQuery {
+            negated: true,
+            media_type: MediaType::All,
+            features: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn media_feature_lengths() {
+        let (queries, errors) = media_queries("(min-width: 600px) and (max-height: 0)");
+        assert_eq!(errors, 0);
+        assert_eq!(
+            queries[0].features,
+            [
+                MediaFeature::MinWidth(Value::Length(600.0, Unit::Px)),
+                MediaFeature::MaxHeight(Value::Number(0.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn unitless_media_feature_is_invalid() {
+        for prelude in ["(min-width: 600)", "(max-width: 1.5)", "(min-height: -1)"] {
+            let (queries, errors) = media_queries(prelude);
+            assert_eq!(errors, 1, "{}", prelude);
+            assert_eq!(queries, [not_all()], "{}", prelude);
+        }
+        // 壊れたクエリだけが`not all`になり、ほかのクエリはそのまま残ります。
+        let (queries, _) = media_queries("(max-width: 600), print");
+        assert_eq!(queries[0], not_all());
+        assert_eq!(queries[1].media_type, MediaType::Print);
+    }
+
+    #[test]
+    fn four_side_shorthands() {
+        assert_eq!(
+            declarations("margin: 1px 2px 3px"),
+            pairs([
+                ("margin-top", px(1.0)),
+                ("margin-right", px(2.0)),
+                ("margin-bottom", px(3.0)),
+                ("margin-left", px(2.0)),
+            ])
+        );
+        assert_eq!(
+            declarations("padding: 1px 2px"),
+            pairs([
+                ("padding-top", px(1.0)),
+                ("padding-right", px(2.0)),
+                ("padding-bottom", px(1.0)),
+                ("padding-left", px(2.0)),
+            ])
+        );
+        let widths: Vec<Value> = declarations("border-width: thin thick")
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect();
+        assert_eq!(widths, [px(1.0), px(5.0), px(1.0), px(5.0)]);
+    }
+
+    #[test]
+    fn border_shorthand_resets_omitted_values() {
+        let border = declarations("border: dashed #00f");
+        assert_eq!(border.len(), 12);
+        let find = |name: &str| border.iter().find(|(n, _)| n == name).unwrap().1.clone();
+        assert_eq!(find("border-top-width"), px(3.0));
+        assert_eq!(find("border-right-style"), keyword("dashed"));
+        assert_eq!(
+            find("border-left-color"),
+            Value::ColorValue(Color {
+                r: 0,
+                g: 0,
+                b: 255,
+                a: 255
+            })
+        );
+        let border = declarations("border: 2px");
+        let find = |name: &str| border.iter().find(|(n, _)| n == name).unwrap().1.clone();
+        assert_eq!(find("border-bottom-style"), keyword("none"));
+        assert_eq!(find("border-bottom-color"), keyword("currentcolor"));
+    }
+
+    #[test]
+    fn background_shorthand_keeps_only_color() {
+        assert_eq!(
+            declarations("background: no-repeat center red"),
+            pairs([("background-color", value("color: red"))])
+        );
+        assert_eq!(
+            declarations("background: none"),
+            pairs([("background-color", value("color: transparent"))])
+        );
+    }
+
+    #[test]
+    fn font_shorthand() {
+        assert_eq!(
+            declarations("font: italic bold 12px/1.5 Georgia, serif"),
+            pairs([
+                ("font-style", keyword("italic")),
+                ("font-weight", keyword("bold")),
+                ("font-size", px(12.0)),
+                ("line-height", Value::Number(1.5)),
+                (
+                    "font-family",
+                    Value::List(vec![keyword("Georgia"), keyword("serif")])
+                ),
+            ])
+        );
+        assert_eq!(
+            declarations("font: 2em sans-serif")[..4],
+            pairs([
+                ("font-style", keyword("normal")),
+                ("font-weight", keyword("normal")),
+                ("font-size", Value::Length(2.0, Unit::Em)),
+                ("line-height", keyword("normal")),
+            ])
+        );
+    }
+
+    #[test]
+    fn shorthand_with_inherit_sets_every_longhand() {
+        let margin = declarations("margin: inherit");
+        assert_eq!(margin.len(), 4);
+        assert!(margin.iter().all(|(_, value)| *value == keyword("inherit")));
+    }
+
+    #[test]
+    fn invalid_shorthand_is_dropped() {
+        let invalid = |kind: &str| vec![ErrorKind::InvalidValue(kind.to_string())];
+        assert_eq!(
+            error_kinds("margin: 1px 2px 3px 4px 5px"),
+            invalid("margin")
+        );
+        assert_eq!(error_kinds("border: 1px 2px"), invalid("border"));
+        assert_eq!(error_kinds("background: red blue"), invalid("background"));
+        assert_eq!(error_kinds("font: bold"), invalid("font"));
+    }
+
+    /// `width`の値として解析した`calc()`などの式
+    fn calc(source: &str) -> Calc {
+        match value(&format!("width: {}", source)) {
+            Value::Calc(calc) => *calc,
+            value => panic!("expected a math function, found {:?}", value),
+        }
+    }
+
+    #[test]
+    fn calc_precedence_and_parentheses() {
+        assert_eq!(
+            calc("calc(100% - 2 * 16px)").evaluate(Some(200.0)),
+            Some(168.0)
+        );
+        assert_eq!(calc("calc((10px + 20px) / 2)").evaluate(None), Some(15.0));
+        assert_eq!(calc("calc(10px + 20px / 2)").evaluate(None), Some(20.0));
+        assert_eq!(calc("calc(2 * (1px + 2px) * 3)").evaluate(None), Some(18.0));
+    }
+
+    #[test]
+    fn min_max_clamp() {
+        assert_eq!(calc("min(10px, 5px, 20px)").evaluate(None), Some(5.0));
+        assert_eq!(calc("max(10px, 50%)").evaluate(Some(100.0)), Some(50.0));
+        assert_eq!(calc("clamp(10px, 5px, 20px)").evaluate(None), Some(10.0));
+        assert_eq!(calc("clamp(10px, 30px, 20px)").evaluate(None), Some(20.0));
+        assert_eq!(
+            calc("calc(min(10px, 20px) + max(1px, 2px))").evaluate(None),
+            Some(12.0)
+        );
+    }
+
+    #[test]
+    fn calc_needs_percent_base_and_absolute_lengths() {
+        assert_eq!(calc("calc(50% + 1px)").evaluate(None), None);
+        // emやvwは算出値を求めるときにpxへ直してから評価する。
+        let mut em = calc("calc(2em + 1px)");
+        assert_eq!(em.evaluate(None), None);
+        em.for_each_value(&mut |value| {
+            if let Value::Length(n, Unit::Em) = *value {
+                *value = Value::Length(n * 10.0, Unit::Px);
+            }
+        });
+        assert_eq!(em.evaluate(None), Some(21.0));
+        assert!(em.is_length());
+    }
+
+    #[test]
+    fn math_function_argument_counts() {
+        let invalid = |name: &str| vec![ErrorKind::InvalidArguments(name.to_string())];
+        assert_eq!(error_kinds("width: calc(1px, 2px)"), invalid("calc"));
+        assert_eq!(error_kinds("width: clamp(1px, 2px)"), invalid("clamp"));
+        assert_eq!(
+            error_kinds("width: calc(foo(1px))"),
+            [ErrorKind::UnknownFunction("foo".to_string())]
+        );
+    }
+
+    fn rgba(r: u8, g: u8, b: u8, a: u8) -> Value {
+        Value::ColorValue(Color { r, g, b, a })
+    }
+
+    #[test]
+    fn named_and_hex_colors() {
+        assert_eq!(value("color: red"), rgba(255, 0, 0, 255));
+        assert_eq!(value("color: RebeccaPurple"), rgba(102, 51, 153, 255));
+        assert_eq!(value("color: transparent"), rgba(0, 0, 0, 0));
+        assert_eq!(value("color: #f80"), rgba(255, 136, 0, 255));
+        assert_eq!(value("color: #f808"), rgba(255, 136, 0, 136));
+        assert_eq!(value("color: #FF880080"), rgba(255, 136, 0, 128));
+        assert_eq!(value("color: currentColor"), keyword("currentColor"));
+        assert_eq!(
+            error_kinds("color: #ggg; color: #12345"),
+            [
+                ErrorKind::InvalidColor("ggg".to_string()),
+                ErrorKind::InvalidColor("12345".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rgb_functions() {
+        assert_eq!(value("color: rgb(255, 128, 0)"), rgba(255, 128, 0, 255));
+        assert_eq!(value("color: rgba(0, 0, 255, .5)"), rgba(0, 0, 255, 128));
+        assert_eq!(
+            value("color: rgb(100% 0% 50% / 25%)"),
+            rgba(255, 0, 128, 64)
+        );
+        // 範囲外の値は0〜255に収める。
+        assert_eq!(value("color: rgb(300, -20, 0)"), rgba(255, 0, 0, 255));
+        assert_eq!(
+            error_kinds("color: rgb(1, 2)"),
+            [ErrorKind::InvalidArguments("rgb".to_string())]
+        );
+    }
+
+    #[test]
+    fn hsl_functions() {
+        assert_eq!(value("color: hsl(120, 100%, 50%)"), rgba(0, 255, 0, 255));
+        assert_eq!(
+            value("color: hsl(0.5turn 100% 50%)"),
+            rgba(0, 255, 255, 255)
+        );
+        assert_eq!(
+            value("color: hsla(-120deg, 100%, 25%, 0.5)"),
+            rgba(0, 0, 128, 128)
+        );
+        assert_eq!(value("color: hsl(0, 0%, 100%)"), rgba(255, 255, 255, 255));
+    }
+
+    /// スタイルシートを解析して、規則とエラーの種類を返します。
+    fn parse_rules(source: &str) -> (Vec<CssRule>, Vec<ErrorKind>) {
+        let (stylesheet, errors) = parse(source.to_string(), Origin::Author);
+        (
+            stylesheet.rules,
+            errors.into_iter().map(|e| e.kind).collect(),
+        )
+    }
+
+    #[test]
+    fn import_rules() {
+        let (rules, errors) = parse_rules(
+            "@charset \"utf-8\"; @import url(\"a.css\"); @IMPORT 'b.css' print, (min-width: 10px); p {}",
+        );
+        assert!(errors.is_empty(), "{:?}", errors);
+        let imports: Vec<(&str, usize)> = rules
+            .iter()
+            .filter_map(|rule| match rule {
+                CssRule::Import(import) => Some((&*import.url, import.queries.len())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(imports, [("a.css", 0), ("b.css", 2)]);
+        assert!(matches!(rules.last(), Some(CssRule::Style(_))));
+
+        let (rules, errors) = parse_rules("p {} @import 'c.css'; @import;");
+        assert_eq!(rules.len(), 1);
+        assert_eq!(errors, [ErrorKind::MisplacedImport, ErrorKind::MissingUrl]);
+    }
+
+    #[test]
+    fn font_face_rules() {
+        let (rules, errors) = parse_rules(
+            "@font-face { font-family: 'My Font'; src: local(Brand), url(a.woff2) format(\"WOFF2\"), url('b.ttf'); font-display: swap }",
+        );
+        assert!(errors.is_empty(), "{:?}", errors);
+        let [CssRule::FontFace(face)] = &rules[..] else {
+            panic!("expected @font-face, found {:?}", rules);
+        };
+        assert_eq!(face.family, "My Font");
+        assert_eq!(
+            face.sources,
+            [
+                FontSource::Local("Brand".to_string()),
+                FontSource::Url {
+                    url: "a.woff2".to_string(),
+                    format: Some("woff2".to_string())
+                },
+                FontSource::Url {
+                    url: "b.ttf".to_string(),
+                    format: None
+                },
+            ]
+        );
+
+        let (rules, errors) = parse_rules("@font-face { font-family: X; color: red }");
+        assert!(rules.is_empty());
+        assert_eq!(
+            errors,
+            [
+                ErrorKind::UnknownProperty("color".to_string()),
+                ErrorKind::MissingDescriptor("src"),
+            ]
+        );
+    }
+
+    #[test]
+    fn values_are_checked_against_the_property() {
+        let invalid = |name: &str| vec![ErrorKind::InvalidValue(name.to_string())];
+        assert_eq!(error_kinds("width: foo"), invalid("width"));
+        assert_eq!(error_kinds("width: 5"), invalid("width"));
+        assert_eq!(error_kinds("width: 1px 2px"), invalid("width"));
+        assert_eq!(error_kinds("width: -1px"), invalid("width"));
+        assert_eq!(error_kinds("padding-left: -1%"), invalid("padding-left"));
+        assert_eq!(error_kinds("font-size: -5px"), invalid("font-size"));
+        assert_eq!(error_kinds("line-height: -3"), invalid("line-height"));
+        assert_eq!(
+            error_kinds("border-top-width: 10%"),
+            invalid("border-top-width")
+        );
+        assert_eq!(error_kinds("font-weight: 0"), invalid("font-weight"));
+        assert_eq!(error_kinds("color: 10px"), invalid("color"));
+        assert_eq!(error_kinds("color: reed"), invalid("color"));
+        assert_eq!(error_kinds("display: -webkit-box"), invalid("display"));
+        assert_eq!(
+            error_kinds("font-family: serif, 5px"),
+            invalid("font-family")
+        );
+
+        assert_eq!(value("width: 0"), Value::Number(0.0));
+        assert_eq!(
+            value("margin-left: -5%"),
+            Value::Length(-5.0, Unit::Percent)
+        );
+        assert_eq!(value("margin-top: AUTO"), keyword("AUTO"));
+        assert_eq!(value("font-size: larger"), keyword("larger"));
+        assert_eq!(value("font-weight: 650"), Value::Number(650.0));
+        assert_eq!(value("border-left-width: thick"), px(5.0));
+        assert_eq!(value("width: Inherit"), keyword("Inherit"));
+    }
+
+    #[test]
+    fn shorthand_parts_are_checked_against_their_longhands() {
+        let invalid = |name: &str| vec![ErrorKind::InvalidValue(name.to_string())];
+        assert_eq!(error_kinds("padding: 1px -2px"), invalid("padding"));
+        assert_eq!(error_kinds("border: 5 solid"), invalid("border"));
+        assert_eq!(error_kinds("border: 1px solid foo"), invalid("border"));
+        assert_eq!(
+            error_kinds("border-style: solid wavy"),
+            invalid("border-style")
+        );
+        assert_eq!(error_kinds("background: 12px foo"), invalid("background"));
+        assert_eq!(error_kinds("font: 12px/-1 serif"), invalid("font"));
+        assert_eq!(error_kinds("font: bold foo serif"), invalid("font"));
+        assert_eq!(declarations("margin: -1px auto").len(), 4);
+    }
+
+    #[test]
+    fn invalid_declaration_is_dropped_with_the_earlier_one_kept() {
+        let (declarations, errors) =
+            parse_declaration_list("width: 100px; width: foo; color: red; color: 3".to_string());
+        let declarations: Vec<(&str, &Value)> = declarations
+            .iter()
+            .map(|declaration| (&*declaration.name, &declaration.value))
+            .collect();
+        assert_eq!(
+            declarations,
+            [("width", &px(100.0)), ("color", &value("color: red"))]
+        );
+        assert_eq!(errors.len(), 2);
+    }
+
+    #[test]
+    fn calc_types_must_match() {
+        let invalid_calc = || vec![ErrorKind::InvalidCalc];
+        assert_eq!(error_kinds("width: calc(10px * 10px)"), invalid_calc());
+        assert_eq!(error_kinds("width: calc(10 + 5px)"), invalid_calc());
+        assert_eq!(error_kinds("width: calc(1px / 1px)"), invalid_calc());
+        assert_eq!(error_kinds("width: calc(1px/0)"), invalid_calc());
+        assert_eq!(error_kinds("width: calc(100%/0)"), invalid_calc());
+        assert_eq!(error_kinds("width: calc(1px / (2 - 2))"), invalid_calc());
+        assert_eq!(error_kinds("width: min(1px, 2)"), invalid_calc());
+        assert_eq!(error_kinds("width: clamp(1px, 50%, 3)"), invalid_calc());
+
+        assert_eq!(
+            calc("calc(100% - 10px)").resolve_type(),
+            Some(CalcType::LengthPercentage)
+        );
+        assert_eq!(
+            calc("calc(2 * 50% / 4)").resolve_type(),
+            Some(CalcType::Percentage)
+        );
+        assert_eq!(
+            value("line-height: calc(1.5 * 2)"),
+            Value::Calc(Box::new(Calc::Product(
+                Box::new(Calc::Value(Value::Number(1.5))),
+                Box::new(Calc::Value(Value::Number(2.0))),
+            )))
+        );
+    }
+
+    #[test]
+    fn calc_result_must_fit_the_property() {
+        let invalid = |name: &str| vec![ErrorKind::InvalidValue(name.to_string())];
+        assert_eq!(error_kinds("width: calc(5)"), invalid("width"));
+        assert_eq!(
+            error_kinds("border-top-width: calc(10% + 1px)"),
+            invalid("border-top-width")
+        );
+        assert_eq!(
+            error_kinds("line-height: calc(1px + 1)"),
+            [ErrorKind::InvalidCalc]
+        );
+    }
+
+    #[test]
+    fn calc_sum_needs_whitespace_around_operator() {
+        let missing = |c| vec![ErrorKind::MissingWhitespaceAroundOperator(c)];
+        assert_eq!(error_kinds("width: calc(1px -2px)"), missing('-'));
+        assert_eq!(error_kinds("width: calc(1px +2px)"), missing('+'));
+        assert_eq!(error_kinds("width: calc(1px+2px)"), missing('+'));
+        assert_eq!(calc("calc(1px*2 + (3px)/3)").evaluate(None), Some(3.0));
+        assert_eq!(calc("calc( 1px - -2px )").evaluate(None), Some(3.0));
+    }
+
+    #[test]
+    fn negated_media_feature_without_media_type() {
+        let (queries, errors) = media_queries("not (min-width: 600px), NOT print");
+        assert_eq!(errors, 0);
+        assert_eq!(
+            queries,
+            [
+                MediaQuery {
+                    negated: true,
+                    media_type: MediaType::All,
+                    features: vec![MediaFeature::MinWidth(px(600.0))],
+                },
+                MediaQuery {
+                    negated: true,
+                    media_type: MediaType::Print,
+                    features: Vec::new(),
+                },
+            ]
+        );
+        for prelude in [
+            "not (min-width: 1px) and (max-width: 2px)",
+            "only (min-width: 1px)",
+        ] {
+            let (queries, errors) = media_queries(prelude);
+            assert_eq!((queries, errors), (vec![not_all()], 1), "{}", prelude);
+        }
+    }
+
+    #[test]
+    fn media_attribute_query_list() {
+        let (queries, errors) = parse_media_query_list("print, (max-width: 10px)".to_string());
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(queries.len(), 2);
+        assert_eq!(queries[0].media_type, MediaType::Print);
+        assert!(parse_media_query_list(String::new()).0.is_empty());
+        let (queries, errors) = parse_media_query_list("screen { p {} }".to_string());
+        assert_eq!((queries, errors.len()), (vec![not_all()], 1));
+    }
+}
@@ -17,6 +17,22 @@ pub struct Node {
 pub enum NodeType {
     Element(ElementData),
     Text(String),
+    Comment(String),
+}
+
+/// `<!DOCTYPE>`宣言の内容。
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Doctype {
+    pub name: String,
+    pub public_id: Option<String>,
+    pub system_id: Option<String>,
+}
+
+/// HTML文書全体。DOCTYPE宣言と、ルートの<html>要素およびその前後のコメントを持ちます。
+#[derive(Debug)]
+pub struct Document {
+    pub doctype: Option<Doctype>,
+    pub children: Vec<Node>,
 }
 
 #[derive(Debug)]
@@ -34,9 +50,16 @@ pub fn text(data: String) -> Node {
     }
 }
 
+pub fn comment(data: String) -> Node {
+    Node {
+        children: vec![],
+        node_type: NodeType::Comment(data),
+    }
+}
+
 pub fn elem(name: String, attrs: AttrMap, children: Vec<Node>) -> Node {
     Node {
-        children: children,
+        children,
         node_type: NodeType::Element(ElementData {
             tag_name: name,
             attributes: attrs,
@@ -44,6 +67,50 @@ pub fn elem(name: String, attrs: AttrMap, children: Vec<Node>) -> Node {
     }
 }
 
+// Document methods
+
+impl Document {
+    /// 文書のルート要素（<html>）を返します。
+    pub fn document_element(&self) -> &Node {
+        self.children
+            .iter()
+            .find(|child| matches!(child.node_type, NodeType::Element(_)))
+            .expect("document has no root element")
+    }
+}
+
+// Node methods
+
+impl Node {
+    /// このノードとその子孫を、文書順（前順）に返します。
+    pub fn descendants(&self) -> Vec<&Node> {
+        let mut nodes = vec![self];
+        for child in &self.children {
+            nodes.extend(child.descendants());
+        }
+        nodes
+    }
+
+    /// 子孫のテキストノードをつなげた文字列を返します。
+    pub fn text_content(&self) -> String {
+        self.descendants()
+            .into_iter()
+            .filter_map(|node| match node.node_type {
+                NodeType::Text(ref text) => Some(&**text),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// 要素ならその`ElementData`を返します。
+    pub fn element(&self) -> Option<&ElementData> {
+        match self.node_type {
+            NodeType::Element(ref elem) => Some(elem),
+            _ => None,
+        }
+    }
+}
+
 // Element methods
 
 impl ElementData {
@@ -0,0 +1,196 @@
+//! Font loading, text measurement and glyph shaping.
+//!
+//! フォントはリポジトリに同梱した DejaVu フォント（assets/fonts）と、`@font-face`で登録したフォントだけを使い、
+//! システムのフォントは探しません。
+//! シェーピングは単純な左から右への配置で、グリフの送り幅とカーニングだけを考慮します。
+
+use std::sync::{OnceLock, RwLock};
+
+use ab_glyph::{Font, FontRef, GlyphId, InvalidFont, PxScale, ScaleFont};
+
+use crate::css::Value;
+
+static SANS_SERIF: &[u8] = include_bytes!("../assets/fonts/DejaVuSans.ttf");
+static SERIF: &[u8] = include_bytes!("../assets/fonts/DejaVuSerif.ttf");
+static MONOSPACE: &[u8] = include_bytes!("../assets/fonts/DejaVuSansMono.ttf");
+
+/// どのフォントを使うか。同梱フォントは`font-family`の総称ファミリーに対応します。
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Family {
+    SansSerif,
+    Serif,
+    Monospace,
+    /// `@font-face`で登録したフォント。番号は登録した順番です。
+    Custom(usize),
+}
+
+/// `@font-face`で登録したフォント
+struct Face {
+    name: String,
+    font: &'static FontRef<'static>,
+}
+
+static FACES: RwLock<Vec<Face>> = RwLock::new(Vec::new());
+
+/// TrueType か OpenType のフォントのデータを、`font-family`で`name`として使えるように登録します。
+/// 登録したフォントはプログラムの終わりまで使うので、データは解放しません。
+pub fn register(name: &str, data: Vec<u8>) -> Result<(), InvalidFont> {
+    let data: &'static [u8] = Box::leak(data.into_boxed_slice());
+    let font = Box::leak(Box::new(FontRef::try_from_slice(data)?));
+    FACES.write().unwrap().push(Face {
+        name: name.to_string(),
+        font,
+    });
+    Ok(())
+}
+
+impl Family {
+    /// `font-family`の値から同梱フォントを選びます。知らないファミリー名は sans-serif として扱います。
+    pub fn from_value(value: Option<Value>) -> Family {
+        match value {
+            Some(Value::Keyword(name)) => Family::from_name(&name).unwrap_or(Family::SansSerif),
+            // `Georgia, serif`のような並びでは、最初に知っている名前を使う。
+            Some(Value::List(names)) => names
+                .iter()
+                .find_map(|name| match name {
+                    Value::Keyword(name) => Family::from_name(name),
+                    _ => None,
+                })
+                .unwrap_or(Family::SansSerif),
+            _ => Family::SansSerif,
+        }
+    }
+
+    /// 登録したフォントを先に探します。同じ名前のフォントが複数あれば、最初に登録したものを使います。
+    fn from_name(name: &str) -> Option<Family> {
+        let faces = FACES.read().unwrap();
+        if let Some(i) = faces
+            .iter()
+            .position(|face| face.name.eq_ignore_ascii_case(name))
+        {
+            return Some(Family::Custom(i));
+        }
+        match &*name.to_ascii_lowercase() {
+            "serif" | "times" | "georgia" => Some(Family::Serif),
+            "monospace" | "courier" | "menlo" | "consolas" => Some(Family::Monospace),
+            "sans-serif" | "helvetica" | "arial" => Some(Family::SansSerif),
+            _ => None,
+        }
+    }
+
+    /// このファミリーのフォントを返します。フォントは最初に使われたときに一度だけ読み込みます。
+    pub fn font(self) -> &'static FontRef<'static> {
+        static FONTS: OnceLock<[FontRef<'static>; 3]> = OnceLock::new();
+        let fonts = FONTS.get_or_init(|| {
+            [SANS_SERIF, SERIF, MONOSPACE]
+                .map(|data| FontRef::try_from_slice(data).expect("bundled font is valid"))
+        });
+        match self {
+            Family::SansSerif => &fonts[0],
+            Family::Serif => &fonts[1],
+            Family::Monospace => &fonts[2],
+            Family::Custom(i) => FACES.read().unwrap()[i].font,
+        }
+    }
+
+    /// `@font-face`で登録したフォントなら、登録した名前を返します。
+    pub fn face_name(self) -> Option<String> {
+        match self {
+            Family::Custom(i) => Some(FACES.read().unwrap()[i].name.clone()),
+            _ => None,
+        }
+    }
+
+    /// CSS の`font-size`（1em の大きさ）を ab_glyph のスケールに変換します。
+    /// ab_glyph のスケールは em ではなくアセントからディセントまでの高さで表されます。
+    fn scale(self, font_size: f32) -> PxScale {
+        let font = self.font();
+        let units_per_em = font.units_per_em().unwrap_or(1000.0);
+        PxScale::from(font_size * font.height_unscaled() / units_per_em)
+    }
+}
+
+/// 位置の決まった1つのグリフ。
+#[derive(Clone, Debug)]
+pub struct Glyph {
+    pub id: GlyphId,
+    /// グリフの元になった文字（PDF や SVG にテキストとして書き出すときに使う）
+    pub c: char,
+    /// グリフの原点のx座標
+    pub x: f32,
+}
+
+/// 同じフォント・サイズで1本のベースラインに並ぶグリフの列。
+#[derive(Clone, Debug)]
+pub struct GlyphRun {
+    pub family: Family,
+    pub font_size: f32,
+    /// ベースラインのy座標
+    pub baseline: f32,
+    pub glyphs: Vec<Glyph>,
+    /// 最後のグリフの送り幅までを含めた全体の幅
+    pub width: f32,
+}
+
+/// テキストを`(x, baseline)`から始まるグリフの列に変換します。
+pub fn shape(text: &str, family: Family, font_size: f32, x: f32, baseline: f32) -> GlyphRun {
+    let font = family.font().as_scaled(family.scale(font_size));
+    let mut glyphs = Vec::new();
+    let mut cursor = x;
+    let mut previous: Option<GlyphId> = None;
+    for c in text.chars() {
+        let id = font.glyph_id(c);
+        if let Some(previous) = previous {
+            cursor += font.kern(previous, id);
+        }
+        glyphs.push(Glyph { id, c, x: cursor });
+        cursor += font.h_advance(id);
+        previous = Some(id);
+    }
+    GlyphRun {
+        family,
+        font_size,
+        baseline,
+        glyphs,
+        width: cursor - x,
+    }
+}
+
+/// テキストを1行に並べたときの幅を返します。
+pub fn text_width(text: &str, family: Family, font_size: f32) -> f32 {
+    shape(text, family, font_size, 0.0, 0.0).width
+}
+
+/// 高さ`line_height`の行の上端からベースラインまでの距離を返します。
+/// フォントのアセントとディセントの外側の余白（leading）は上下に半分ずつ配分します。
+pub fn baseline_offset(family: Family, font_size: f32, line_height: f32) -> f32 {
+    let font = family.font().as_scaled(family.scale(font_size));
+    let content_height = font.ascent() - font.descent();
+    (line_height - content_height) / 2.0 + font.ascent()
+}
+
+impl GlyphRun {
+    /// 各グリフをラスタライズし、ピクセルごとの被覆率（0.0〜1.0）を`draw`に渡します。
+    pub fn rasterize<F>(&self, mut draw: F)
+    where
+        F: FnMut(i32, i32, f32),
+    {
+        let font = self.family.font();
+        let scale = self.family.scale(self.font_size);
+        for glyph in &self.glyphs {
+            let positioned = glyph
+                .id
+                .with_scale_and_position(scale, ab_glyph::point(glyph.x, self.baseline));
+            if let Some(outlined) = font.outline_glyph(positioned) {
+                let bounds = outlined.px_bounds();
+                outlined.draw(|x, y, coverage| {
+                    draw(
+                        bounds.min.x as i32 + x as i32,
+                        bounds.min.y as i32 + y as i32,
+                        coverage,
+                    )
+                });
+            }
+        }
+    }
+}
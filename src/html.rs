@@ -1,36 +1,816 @@
 // https://limpet.net/mbrubeck/2014/08/11/toy-layout-engine-2.html
+// https://html.spec.whatwg.org/multipage/parsing.html
+//
+// HTMLの解析は、ブラウザと同じく「字句解析（トークナイザ）」と「木構築（ツリービルダー）」の2段階で行います。
+// トークナイザは入力文字列を開始タグ・終了タグ・テキストなどのトークンに分け、
+// ツリービルダーは開いている要素のスタックと挿入モードを使ってトークンからDOMツリーを組み立てます。
+// 終了タグの省略や<html>/<head>/<body>の省略、空要素（<br>など）もこの段階で補われます。
+// 仕様と同じく解析は失敗せず、不正な入力は解析エラーとして記録したうえで回復します。
 
 use crate::dom;
-use std::collections::HashMap;
+use crate::entities;
+use crate::source::Position;
+use std::fmt;
 
-struct Parser {
+/// HTMLの解析中に見つかったエラーと、その位置。
+/// どのエラーも回復可能で、解析自体は最後まで続きます。
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub kind: ErrorKind,
+    pub position: Position,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    /// タグの途中で入力が終わった
+    EofInTag,
+    /// `<`の後にタグ名として使えない文字が続いた（`<`はテキストとして扱う）
+    InvalidTagName(char),
+    /// `</>`
+    MissingEndTagName,
+    /// `<?`で始まる処理命令（コメントとして扱う）
+    UnexpectedQuestionMark,
+    /// `<!`の後が`--`や`DOCTYPE`ではなかった（コメントとして扱う）
+    IncorrectlyOpenedComment,
+    /// `<!-->`や`<!--->`
+    AbruptClosingOfEmptyComment,
+    /// コメントの途中で入力が終わった
+    EofInComment,
+    /// 文字参照の後に`;`がない
+    MissingSemicolonAfterCharacterReference,
+    /// 表にない名前の文字参照（そのままテキストとして残す）
+    UnknownNamedCharacterReference(String),
+    /// `&#`や`&#x`の後に数字がない
+    MissingDigitsInCharacterReference,
+    /// NUL、サロゲート、Unicodeの範囲外、制御文字を指す数値文字参照
+    InvalidCharacterReference(u32),
+    /// SVGやMathMLの外の`<![CDATA[`（コメントとして扱う）
+    CdataInHtmlContent,
+    /// `<!DOCTYPE>`に名前がない
+    MissingDoctypeName,
+    /// DOCTYPE宣言の途中で入力が終わった
+    EofInDoctype,
+    /// 文書の先頭以外にあるDOCTYPE宣言（無視される）
+    UnexpectedDoctype,
+    /// タグの中に`>`の続かない`/`があった
+    UnexpectedSolidus,
+    /// 引用符なしの属性値に使えない文字があった
+    UnexpectedCharInUnquotedAttributeValue(char),
+    /// `=`の後に値がない（空の値として扱う）
+    MissingAttributeValue(String),
+    /// 引用符で囲まれた属性値の直後に空白がない
+    MissingWhitespaceBetweenAttributes,
+    /// 同じ名前の属性が2回以上現れた（最初の値が使われる）
+    DuplicateAttribute(String),
+    /// 空要素でない要素に`/>`が付いていた（無視される）
+    NonVoidSelfClosing(String),
+    /// この位置では使えない開始タグ
+    UnexpectedStartTag(String),
+    /// 対応する開いている要素のない終了タグ（無視される）
+    UnexpectedClosingTag(String),
+    /// 終了タグが、閉じられていない他の要素を暗黙に閉じた
+    MismatchedClosingTag { expected: String, found: String },
+    /// `</body>`や`</html>`の後に内容があった
+    ContentAfterBody,
+    /// 閉じられないまま入力が終わった要素
+    UnclosedElement(String),
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::EofInTag => write!(f, "unexpected end of input in tag"),
+            ErrorKind::InvalidTagName(c) => {
+                write!(f, "invalid first character of tag name {:?}", c)
+            }
+            ErrorKind::MissingEndTagName => write!(f, "missing end tag name"),
+            ErrorKind::UnexpectedQuestionMark => {
+                write!(f, "processing instructions are not supported in HTML")
+            }
+            ErrorKind::IncorrectlyOpenedComment => write!(f, "incorrectly opened comment"),
+            ErrorKind::AbruptClosingOfEmptyComment => {
+                write!(f, "abrupt closing of empty comment")
+            }
+            ErrorKind::EofInComment => write!(f, "unexpected end of input in comment"),
+            ErrorKind::MissingSemicolonAfterCharacterReference => {
+                write!(f, "missing semicolon after character reference")
+            }
+            ErrorKind::UnknownNamedCharacterReference(name) => {
+                write!(f, "unknown named character reference &{};", name)
+            }
+            ErrorKind::MissingDigitsInCharacterReference => {
+                write!(f, "missing digits in numeric character reference")
+            }
+            ErrorKind::InvalidCharacterReference(code) => {
+                write!(f, "invalid character reference &#x{:X};", code)
+            }
+            ErrorKind::CdataInHtmlContent => {
+                write!(f, "CDATA sections are only allowed in SVG and MathML")
+            }
+            ErrorKind::MissingDoctypeName => write!(f, "missing DOCTYPE name"),
+            ErrorKind::EofInDoctype => write!(f, "unexpected end of input in DOCTYPE"),
+            ErrorKind::UnexpectedDoctype => write!(f, "unexpected DOCTYPE"),
+            ErrorKind::UnexpectedSolidus => write!(f, "unexpected '/' in tag"),
+            ErrorKind::UnexpectedCharInUnquotedAttributeValue(c) => {
+                write!(
+                    f,
+                    "unexpected character {:?} in unquoted attribute value",
+                    c
+                )
+            }
+            ErrorKind::MissingAttributeValue(name) => {
+                write!(f, "missing value for attribute {:?}", name)
+            }
+            ErrorKind::MissingWhitespaceBetweenAttributes => {
+                write!(f, "missing whitespace between attributes")
+            }
+            ErrorKind::DuplicateAttribute(name) => write!(f, "duplicate attribute {:?}", name),
+            ErrorKind::NonVoidSelfClosing(name) => {
+                write!(f, "self-closing syntax on non-void element <{}>", name)
+            }
+            ErrorKind::UnexpectedStartTag(name) => write!(f, "unexpected start tag <{}>", name),
+            ErrorKind::UnexpectedClosingTag(name) => {
+                write!(f, "unexpected closing tag </{}>", name)
+            }
+            ErrorKind::MismatchedClosingTag { expected, found } => write!(
+                f,
+                "mismatched closing tag: expected </{}>, found </{}>",
+                expected, found
+            ),
+            ErrorKind::ContentAfterBody => write!(f, "content after the end of <body>"),
+            ErrorKind::UnclosedElement(name) => write!(f, "unclosed element <{}>", name),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.kind, self.position)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// 終了タグを持たない空要素
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "basefont", "bgsound", "br", "col", "embed", "hr", "img", "input", "keygen",
+    "link", "meta", "param", "source", "track", "wbr",
+];
+
+/// 中身をタグとして解釈せず、対応する終了タグまでをテキストとして読む要素。
+/// このうち<title>と<textarea>（RCDATA）では文字参照を解釈する。
+const RAW_TEXT_ELEMENTS: &[&str] = &[
+    "iframe", "noembed", "noframes", "noscript", "script", "style", "textarea", "title", "xmp",
+];
+
+/// 終了タグが省略されたときに暗黙に閉じられる要素
+const IMPLIED_END_TAGS: &[&str] = &[
+    "dd", "dt", "li", "optgroup", "option", "p", "rb", "rp", "rt", "rtc",
+];
+
+/// 開始タグが現れると、開いている<p>を閉じる要素
+const CLOSES_P: &[&str] = &[
+    "address",
+    "article",
+    "aside",
+    "blockquote",
+    "center",
+    "details",
+    "dialog",
+    "dir",
+    "div",
+    "dl",
+    "fieldset",
+    "figcaption",
+    "figure",
+    "footer",
+    "form",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "header",
+    "hgroup",
+    "hr",
+    "listing",
+    "main",
+    "menu",
+    "nav",
+    "ol",
+    "p",
+    "pre",
+    "search",
+    "section",
+    "summary",
+    "table",
+    "ul",
+];
+
+const HEADINGS: &[&str] = &["h1", "h2", "h3", "h4", "h5", "h6"];
+
+/// <head>の中に置かれる要素
+const HEAD_ELEMENTS: &[&str] = &[
+    "base", "basefont", "bgsound", "link", "meta", "noframes", "noscript", "script", "style",
+    "template", "title",
+];
+
+/// 「特別な」要素。閉じられていない要素を探すとき、これを越えて探さない。
+const SPECIAL_ELEMENTS: &[&str] = &[
+    "address",
+    "applet",
+    "area",
+    "article",
+    "aside",
+    "base",
+    "basefont",
+    "bgsound",
+    "blockquote",
+    "body",
+    "br",
+    "button",
+    "caption",
+    "center",
+    "col",
+    "colgroup",
+    "dd",
+    "details",
+    "dir",
+    "div",
+    "dl",
+    "dt",
+    "embed",
+    "fieldset",
+    "figcaption",
+    "figure",
+    "footer",
+    "form",
+    "frame",
+    "frameset",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "head",
+    "header",
+    "hgroup",
+    "hr",
+    "html",
+    "iframe",
+    "img",
+    "input",
+    "keygen",
+    "li",
+    "link",
+    "listing",
+    "main",
+    "marquee",
+    "menu",
+    "meta",
+    "nav",
+    "noembed",
+    "noframes",
+    "noscript",
+    "object",
+    "ol",
+    "p",
+    "param",
+    "plaintext",
+    "pre",
+    "script",
+    "search",
+    "section",
+    "select",
+    "source",
+    "style",
+    "summary",
+    "table",
+    "tbody",
+    "td",
+    "template",
+    "textarea",
+    "tfoot",
+    "th",
+    "thead",
+    "title",
+    "tr",
+    "track",
+    "ul",
+    "wbr",
+    "xmp",
+];
+
+/// 要素が「スコープ内」にあるかを調べるとき、探索を打ち切る要素
+const SCOPE_BOUNDARIES: &[&str] = &[
+    "applet", "caption", "html", "marquee", "object", "table", "td", "template", "th",
+];
+
+// ---------------------------------------------------------------------------
+// Tokenizer
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    StartTag {
+        name: String,
+        attributes: Vec<(String, String)>,
+        self_closing: bool,
+    },
+    EndTag {
+        name: String,
+    },
+    Text(String),
+    Comment(String),
+    Doctype(dom::Doctype),
+    Eof,
+}
+
+struct Tokenizer {
     pos: usize,    // 現在の解析位置
     input: String, // 入力されたHTMLの文字列
+    /// RAWTEXT/RCDATA要素の中にいる場合、その要素名。対応する終了タグまでをテキストとして読む。
+    raw_text_end: Option<String>,
+    /// SVGやMathMLの中では`<![CDATA[...]]>`をテキストとして読む。
+    cdata_allowed: bool,
+    errors: Vec<(usize, ErrorKind)>,
 }
 
-impl Parser {
-    // パーサが現在処理している文字を返す。posがinputの長さ以上の場合は空白を返す。
-    fn next_char(&self) -> char {
-        self.input[self.pos..].chars().next().unwrap()
+impl Tokenizer {
+    // 次のトークンと、その開始位置を返す。
+    fn next_token(&mut self) -> (Token, usize) {
+        loop {
+            let start = self.pos;
+            if let Some(name) = self.raw_text_end.take() {
+                return (self.raw_text(&name), start);
+            }
+            let token = match self.peek() {
+                None => Token::Eof,
+                Some('<') => self.tag_open(),
+                Some(_) => self.data(),
+            };
+            // 無視されたタグなどで何も読めなかった場合は次のトークンへ進む。
+            // `</>`が長く続いても再帰しないように、ループで読み進める。
+            match token {
+                Token::Text(ref text) if text.is_empty() => continue,
+                token => return (token, start),
+            }
+        }
+    }
+
+    // Data state: 次の'<'までをテキストとして読む。
+    fn data(&mut self) -> Token {
+        let start = self.pos;
+        let text = self.consume_while(|c| c != '<');
+        Token::Text(self.decode_character_references(&text, start, false))
+    }
+
+    // RAWTEXT/RCDATA state: `</name`までをテキストとして読む。
+    fn raw_text(&mut self, name: &str) -> Token {
+        let rest = &self.input[self.pos..];
+        let mut end = rest.len();
+        let mut search = 0;
+        while let Some(i) = rest[search..].find("</") {
+            let candidate = search + i;
+            let after = &rest[candidate + 2..];
+            if after
+                .get(..name.len())
+                .is_some_and(|s| s.eq_ignore_ascii_case(name))
+                && after[name.len()..]
+                    .chars()
+                    .next()
+                    .is_none_or(|c| c.is_ascii_whitespace() || c == '/' || c == '>')
+            {
+                end = candidate;
+                break;
+            }
+            search = candidate + 2;
+        }
+        let text = rest[..end].to_string();
+        let start = self.pos;
+        self.pos += end;
+        if matches!(name, "title" | "textarea") {
+            return Token::Text(self.decode_character_references(&text, start, false));
+        }
+        Token::Text(text)
+    }
+
+    // Tag open state
+    fn tag_open(&mut self) -> Token {
+        let start = self.pos;
+        self.pos += 1; // '<'
+        match self.peek() {
+            Some('/') => {
+                self.pos += 1;
+                self.end_tag_open(start)
+            }
+            Some(c) if c.is_ascii_alphabetic() => self.tag(start, false),
+            Some('!') => {
+                self.pos += 1;
+                self.markup_declaration_open(start)
+            }
+            Some('?') => {
+                // 処理命令（<?xml ...?>など）はコメントとして扱う。
+                self.error(start, ErrorKind::UnexpectedQuestionMark);
+                self.bogus_comment()
+            }
+            Some(c) => {
+                self.error(start, ErrorKind::InvalidTagName(c));
+                Token::Text("<".to_string())
+            }
+            None => {
+                self.error(start, ErrorKind::EofInTag);
+                Token::Text("<".to_string())
+            }
+        }
+    }
+
+    // End tag open state
+    fn end_tag_open(&mut self, start: usize) -> Token {
+        match self.peek() {
+            Some(c) if c.is_ascii_alphabetic() => self.tag(start, true),
+            Some('>') => {
+                self.pos += 1;
+                self.error(start, ErrorKind::MissingEndTagName);
+                Token::Text(String::new())
+            }
+            Some(c) => {
+                // 不正な終了タグはコメントとして扱う。
+                self.error(start, ErrorKind::InvalidTagName(c));
+                self.bogus_comment()
+            }
+            None => {
+                self.error(start, ErrorKind::EofInTag);
+                Token::Text("</".to_string())
+            }
+        }
+    }
+
+    // Markup declaration open state: `<!`の後に続くコメント、DOCTYPE、CDATAを読み分ける。
+    fn markup_declaration_open(&mut self, start: usize) -> Token {
+        if self.starts_with("--") {
+            self.pos += 2;
+            self.comment(start)
+        } else if self.starts_with_ignore_case("doctype") {
+            self.pos += "doctype".len();
+            self.doctype(start)
+        } else if self.starts_with("[CDATA[") {
+            if self.cdata_allowed {
+                self.pos += "[CDATA[".len();
+                self.cdata()
+            } else {
+                self.error(start, ErrorKind::CdataInHtmlContent);
+                self.bogus_comment()
+            }
+        } else {
+            self.error(start, ErrorKind::IncorrectlyOpenedComment);
+            self.bogus_comment()
+        }
+    }
+
+    // Comment state: `-->`（または`--!>`）までをコメントとして読む。
+    fn comment(&mut self, start: usize) -> Token {
+        for abrupt in [">", "->"] {
+            if self.starts_with(abrupt) {
+                self.pos += abrupt.len();
+                self.error(start, ErrorKind::AbruptClosingOfEmptyComment);
+                return Token::Comment(String::new());
+            }
+        }
+        let rest = &self.input[self.pos..];
+        let end = ["-->", "--!>"]
+            .iter()
+            .filter_map(|close| rest.find(close).map(|i| (i, close.len())))
+            .min();
+        match end {
+            Some((i, len)) => {
+                let data = rest[..i].to_string();
+                self.pos += i + len;
+                Token::Comment(data)
+            }
+            None => {
+                let data = rest.to_string();
+                self.error(start, ErrorKind::EofInComment);
+                self.pos = self.input.len();
+                Token::Comment(data)
+            }
+        }
+    }
+
+    // Bogus comment state: 次の'>'までをコメントとして読む。
+    fn bogus_comment(&mut self) -> Token {
+        let data = self.consume_while(|c| c != '>');
+        self.pos = (self.pos + 1).min(self.input.len());
+        Token::Comment(data)
+    }
+
+    // CDATA section state: `]]>`までをテキストとして読む。
+    fn cdata(&mut self) -> Token {
+        let rest = &self.input[self.pos..];
+        let (text, len) = match rest.find("]]>") {
+            Some(i) => (rest[..i].to_string(), i + 3),
+            None => (rest.to_string(), rest.len()),
+        };
+        self.pos += len;
+        Token::Text(text)
+    }
+
+    // DOCTYPE state: 名前と、あればPUBLIC/SYSTEM識別子を読む。
+    fn doctype(&mut self, start: usize) -> Token {
+        let mut doctype = dom::Doctype::default();
+        self.consume_whitespace();
+        doctype.name = self
+            .consume_while(|c| !c.is_ascii_whitespace() && c != '>')
+            .to_ascii_lowercase();
+        if doctype.name.is_empty() {
+            self.error(start, ErrorKind::MissingDoctypeName);
+        }
+        self.consume_whitespace();
+        if self.starts_with_ignore_case("public") {
+            self.pos += "public".len();
+            doctype.public_id = self.doctype_identifier();
+            doctype.system_id = self.doctype_identifier();
+        } else if self.starts_with_ignore_case("system") {
+            self.pos += "system".len();
+            doctype.system_id = self.doctype_identifier();
+        }
+        // 残りは'>'まで読み飛ばす。
+        self.consume_while(|c| c != '>');
+        if self.peek().is_none() {
+            self.error(start, ErrorKind::EofInDoctype);
+        }
+        self.pos = (self.pos + 1).min(self.input.len());
+        Token::Doctype(doctype)
     }
 
-    // パーサの現在位置から始まる文字列が特定の文字列sで始まっているかどうかをチェックする。
+    // 引用符で囲まれたDOCTYPEの識別子を読む。
+    fn doctype_identifier(&mut self) -> Option<String> {
+        self.consume_whitespace();
+        let quote = self.peek().filter(|&c| c == '"' || c == '\'')?;
+        self.pos += 1;
+        let value = self.consume_while(|c| c != quote && c != '>');
+        if self.peek() == Some(quote) {
+            self.pos += 1;
+        }
+        Some(value)
+    }
+
+    // Tag name state から始まり、属性を読んで'>'までを1つのタグとして返す。
+    fn tag(&mut self, start: usize, end_tag: bool) -> Token {
+        let name = self
+            .consume_while(|c| !c.is_ascii_whitespace() && c != '/' && c != '>')
+            .to_ascii_lowercase();
+        let mut attributes = Vec::new();
+        let mut self_closing = false;
+        loop {
+            self.consume_whitespace();
+            match self.peek() {
+                None => {
+                    // タグの途中で入力が終わった場合、そのタグは捨てる。
+                    self.error(start, ErrorKind::EofInTag);
+                    return Token::Eof;
+                }
+                Some('>') => {
+                    self.pos += 1;
+                    break;
+                }
+                Some('/') => {
+                    let solidus = self.pos;
+                    self.pos += 1;
+                    if self.peek() == Some('>') {
+                        self.pos += 1;
+                        self_closing = true;
+                        break;
+                    }
+                    self.error(solidus, ErrorKind::UnexpectedSolidus);
+                }
+                Some(_) => {
+                    let attribute_start = self.pos;
+                    let (name, value) = self.attribute();
+                    // 同じ名前の属性は最初のものだけを残す。
+                    if attributes.iter().any(|(existing, _)| *existing == name) {
+                        self.error(attribute_start, ErrorKind::DuplicateAttribute(name));
+                    } else {
+                        attributes.push((name, value));
+                    }
+                }
+            }
+        }
+
+        if end_tag {
+            return Token::EndTag { name };
+        }
+        if RAW_TEXT_ELEMENTS.contains(&&*name) && !self_closing {
+            self.raw_text_end = Some(name.clone());
+        }
+        Token::StartTag {
+            name,
+            attributes,
+            self_closing,
+        }
+    }
+
+    // Attribute name state 〜 attribute value state
+    // 属性名は小文字にする。`=`のない属性は値が空文字列の真偽属性になり、値は引用符なしでもよい。
+    fn attribute(&mut self) -> (String, String) {
+        // 最初の文字は'='でも名前の一部になる。
+        let first = self.consume_char();
+        let rest =
+            self.consume_while(|c| !c.is_ascii_whitespace() && !matches!(c, '/' | '>' | '='));
+        let name = format!("{}{}", first, rest).to_ascii_lowercase();
+        self.consume_whitespace();
+        if self.peek() != Some('=') {
+            return (name, String::new());
+        }
+        self.pos += 1;
+        self.consume_whitespace();
+
+        let value_start = self.pos;
+        let value = match self.peek() {
+            Some(quote @ ('"' | '\'')) => {
+                self.pos += 1;
+                let value = self.consume_while(|c| c != quote);
+                if self.peek().is_none() {
+                    self.error(value_start, ErrorKind::EofInTag);
+                } else {
+                    self.pos += 1;
+                    if self
+                        .peek()
+                        .is_some_and(|c| !c.is_ascii_whitespace() && c != '/' && c != '>')
+                    {
+                        self.error(self.pos, ErrorKind::MissingWhitespaceBetweenAttributes);
+                    }
+                }
+                self.decode_character_references(&value, value_start + 1, true)
+            }
+            Some('>') | None => {
+                self.error(value_start, ErrorKind::MissingAttributeValue(name.clone()));
+                String::new()
+            }
+            Some(_) => {
+                let value = self.consume_while(|c| !c.is_ascii_whitespace() && c != '>');
+                if let Some(c) = value
+                    .chars()
+                    .find(|c| matches!(c, '"' | '\'' | '<' | '=' | '`'))
+                {
+                    self.error(
+                        value_start,
+                        ErrorKind::UnexpectedCharInUnquotedAttributeValue(c),
+                    );
+                }
+                self.decode_character_references(&value, value_start, true)
+            }
+        };
+        (name, value)
+    }
+
+    // Character reference state: `text`の中の`&...;`を対応する文字に置き換える。
+    // `start`は`text`の入力中での位置で、エラーの位置を求めるのに使う。
+    fn decode_character_references(
+        &mut self,
+        text: &str,
+        start: usize,
+        in_attribute: bool,
+    ) -> String {
+        if !text.contains('&') {
+            return text.to_string();
+        }
+        let mut decoded = String::with_capacity(text.len());
+        let mut pos = 0;
+        while let Some(i) = text[pos..].find('&') {
+            let amp = pos + i;
+            decoded.push_str(&text[pos..amp]);
+            let rest = &text[amp + 1..];
+            let consumed = if rest.starts_with('#') {
+                self.numeric_character_reference(rest, start + amp, &mut decoded)
+            } else {
+                self.named_character_reference(rest, start + amp, in_attribute, &mut decoded)
+            };
+            pos = amp + 1 + consumed;
+        }
+        decoded.push_str(&text[pos..]);
+        decoded
+    }
+
+    // `&`の後の名前を、表にある最も長い名前と照合する。読んだバイト数を返す。
+    fn named_character_reference(
+        &mut self,
+        rest: &str,
+        amp: usize,
+        in_attribute: bool,
+        decoded: &mut String,
+    ) -> usize {
+        let name_len = rest
+            .find(|c: char| !c.is_ascii_alphanumeric())
+            .unwrap_or(rest.len());
+        let candidate_len =
+            name_len.min(entities::LONGEST_NAME) + usize::from(rest[name_len..].starts_with(';'));
+        for len in (1..=candidate_len).rev() {
+            let Some(value) = entities::lookup(&rest[..len]) else {
+                continue;
+            };
+            if !rest[..len].ends_with(';') {
+                // 属性値の中の`&amp=`や`&copyright`は、歴史的な理由で参照として扱わない。
+                let next = rest[len..].chars().next();
+                if in_attribute && next.is_some_and(|c| c == '=' || c.is_ascii_alphanumeric()) {
+                    decoded.push('&');
+                    return 0;
+                }
+                self.error(amp, ErrorKind::MissingSemicolonAfterCharacterReference);
+            }
+            decoded.push_str(value);
+            return len;
+        }
+        if name_len > 0 && rest[name_len..].starts_with(';') {
+            self.error(
+                amp,
+                ErrorKind::UnknownNamedCharacterReference(rest[..name_len].to_string()),
+            );
+        }
+        decoded.push('&');
+        0
+    }
+
+    // `&#123;`や`&#x7B;`を読む。読んだバイト数を返す。
+    fn numeric_character_reference(
+        &mut self,
+        rest: &str,
+        amp: usize,
+        decoded: &mut String,
+    ) -> usize {
+        let hex = rest[1..].starts_with(['x', 'X']);
+        let digits_start = if hex { 2 } else { 1 };
+        let digits_len = rest[digits_start..]
+            .find(|c: char| {
+                !(if hex {
+                    c.is_ascii_hexdigit()
+                } else {
+                    c.is_ascii_digit()
+                })
+            })
+            .unwrap_or(rest.len() - digits_start);
+        if digits_len == 0 {
+            self.error(amp, ErrorKind::MissingDigitsInCharacterReference);
+            decoded.push('&');
+            return 0;
+        }
+        let digits = &rest[digits_start..digits_start + digits_len];
+        let mut len = digits_start + digits_len;
+        if rest[len..].starts_with(';') {
+            len += 1;
+        } else {
+            self.error(amp, ErrorKind::MissingSemicolonAfterCharacterReference);
+        }
+
+        // 桁あふれするほど大きな値は範囲外として扱う。
+        let code = u32::from_str_radix(digits, if hex { 16 } else { 10 }).unwrap_or(u32::MAX);
+        let c = match code {
+            0 | 0xD800..=0xDFFF | 0x110000.. => {
+                self.error(amp, ErrorKind::InvalidCharacterReference(code));
+                '\u{FFFD}'
+            }
+            0x80..=0x9F => {
+                // C1制御文字は、Windows-1252として解釈した文字に置き換える。
+                self.error(amp, ErrorKind::InvalidCharacterReference(code));
+                windows_1252(code)
+            }
+            0x01..=0x08 | 0x0B | 0x0D..=0x1F | 0x7F => {
+                self.error(amp, ErrorKind::InvalidCharacterReference(code));
+                char::from_u32(code).unwrap()
+            }
+            _ => char::from_u32(code).unwrap(),
+        };
+        decoded.push(c);
+        len
+    }
+
+    fn error(&mut self, pos: usize, kind: ErrorKind) {
+        self.errors.push((pos, kind));
+    }
+
+    // 現在位置の文字列が`s`で始まるかどうか。
     fn starts_with(&self, s: &str) -> bool {
         self.input[self.pos..].starts_with(s)
     }
 
-    // 入力文字列がすべて消費されたかどうかを判断する。
-    fn eof(&self) -> bool {
-        self.pos >= self.input.len()
+    // 現在位置の文字列が、ASCIIの大文字小文字を区別せずに`s`で始まるかどうか。
+    fn starts_with_ignore_case(&self, s: &str) -> bool {
+        self.input[self.pos..]
+            .get(..s.len())
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case(s))
+    }
+
+    // パーサが現在処理している文字を返す。入力の終わりではNoneを返す。
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
     }
 
     // パーサの現在位置にある文字を返し、その後pos（パーサの現在位置）を次の文字に進めます。
     fn consume_char(&mut self) -> char {
-        let mut iter = self.input[self.pos..].char_indices(); // 文字とそのバイト位置のペアを提供します。
-        let (_, cur_char) = iter.next().unwrap(); // 現在の文字とその位置を取得
-        let (next_pos, _) = iter.next().unwrap_or((1, ' ')); // 次の文字の位置を取得します。もし次の文字がなければ、デフォルトとして(1, ' ')（1文字分進める）が返されます。
-        self.pos += next_pos;
-        return cur_char;
+        let cur_char = self.peek().unwrap_or('\0');
+        self.pos = (self.pos + cur_char.len_utf8()).min(self.input.len());
+        cur_char
     }
 
     // 指定された条件（test関数）がtrueを返す間、文字を消費し続けます。
@@ -38,126 +818,1003 @@ impl Parser {
     where
         F: Fn(char) -> bool,
     {
-        let mut result = String::new();
-        while !self.eof() && test(self.next_char()) {
-            result.push(self.consume_char());
-        }
-        return result;
+        let start = self.pos;
+        let len = self.input[start..]
+            .find(|c| !test(c))
+            .unwrap_or(self.input.len() - start);
+        self.pos += len;
+        self.input[start..self.pos].to_string()
     }
 
     // Consume and discard zero or more whitespace characters.
     fn consume_whitespace(&mut self) {
-        self.consume_while(char::is_whitespace);
+        self.consume_while(|c| c.is_ascii_whitespace());
     }
+}
 
-    // HTMLタグの名前を解析します。
-    // タグ名は英数字（'a'から'z'、'A'から'Z'、'0'から'9'）で構成されていると仮定しています。
-    // <div>や<span>といったタグにおいて、"div"や"span"という文字列を抽出します。
-    fn parse_tag_name(&mut self) -> String {
-        self.consume_while(|c| match c {
-            'a'..='z' | 'A'..='Z' | '0'..='9' => true,
-            _ => false,
-        })
+// ---------------------------------------------------------------------------
+// Tree construction
+
+/// ツリービルダーの挿入モード。いま文書のどの部分を組み立てているかを表す。
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum InsertionMode {
+    BeforeHtml,
+    BeforeHead,
+    InHead,
+    AfterHead,
+    InBody,
+    /// RAWTEXT/RCDATA要素の中身。終了タグで元のモードに戻る。
+    Text,
+    AfterBody,
+    AfterAfterBody,
+}
+
+struct TreeBuilder {
+    mode: InsertionMode,
+    /// Textモードを抜けたときに戻るモード
+    original_mode: InsertionMode,
+    /// 開いている要素のスタック。要素は閉じられたときに親の子として追加される。
+    open_elements: Vec<dom::Node>,
+    doctype: Option<dom::Doctype>,
+    /// 文書直下のノード（<html>の前後のコメント）。<html>は最後に`html_index`の位置に入る。
+    document_children: Vec<dom::Node>,
+    html_index: usize,
+    /// <pre>などの直後の改行を無視するかどうか
+    ignore_line_feed: bool,
+    /// 現在処理しているトークンの位置
+    offset: usize,
+    errors: Vec<(usize, ErrorKind)>,
+}
+
+impl TreeBuilder {
+    fn process(&mut self, token: Token) {
+        let token = match token {
+            Token::Comment(data) => return self.insert_comment(data),
+            Token::Doctype(doctype) => return self.insert_doctype(doctype),
+            token => token,
+        };
+        if std::mem::take(&mut self.ignore_line_feed) {
+            if let Token::Text(ref text) = token {
+                if let Some(rest) = text.strip_prefix('\n') {
+                    return self.process(Token::Text(rest.to_string()));
+                }
+            }
+        }
+
+        match self.mode {
+            InsertionMode::BeforeHtml => self.before_html(token),
+            InsertionMode::BeforeHead => self.before_head(token),
+            InsertionMode::InHead => self.in_head(token),
+            InsertionMode::AfterHead => self.after_head(token),
+            InsertionMode::InBody => self.in_body(token),
+            InsertionMode::Text => self.text(token),
+            InsertionMode::AfterBody | InsertionMode::AfterAfterBody => self.after_body(token),
+        }
     }
 
-    // 単一のノード（要素またはテキスト）を解析します。
-    // self.next_char()が'<'である場合、要素ノード（self.parse_element()）を解析し、そうでない場合はテキストノード（self.parse_text()）を解析します。
-    fn parse_node(&mut self) -> dom::Node {
-        match self.next_char() {
-            '<' => self.parse_element(),
-            _ => self.parse_text(),
+    fn before_html(&mut self, token: Token) {
+        match token {
+            Token::Text(ref text) if is_whitespace(text) => {}
+            Token::Text(text) => {
+                self.insert_html(Vec::new());
+                self.process(Token::Text(
+                    text.trim_start_matches(|c: char| c.is_ascii_whitespace())
+                        .to_string(),
+                ));
+            }
+            Token::StartTag {
+                ref name,
+                ref attributes,
+                ..
+            } if name == "html" => {
+                self.insert_html(attributes.clone());
+            }
+            Token::EndTag { ref name } if !matches!(&**name, "head" | "body" | "html" | "br") => {
+                self.error(ErrorKind::UnexpectedClosingTag(name.clone()));
+            }
+            token => {
+                self.insert_html(Vec::new());
+                self.process(token);
+            }
         }
     }
 
-    // テキストノードを解析します。
-    // self.consume_whileを使用して、次の'<'までの文字を消費します。
-    fn parse_text(&mut self) -> dom::Node {
-        dom::text(self.consume_while(|c| c != '<'))
+    fn before_head(&mut self, token: Token) {
+        match token {
+            Token::Text(ref text) if is_whitespace(text) => {}
+            Token::StartTag { ref name, .. } if name == "html" => self.in_body(token),
+            Token::StartTag {
+                ref name,
+                ref attributes,
+                ..
+            } if name == "head" => {
+                self.insert_element(name, attributes.clone());
+                self.mode = InsertionMode::InHead;
+            }
+            Token::EndTag { ref name } if !matches!(&**name, "head" | "body" | "html" | "br") => {
+                self.error(ErrorKind::UnexpectedClosingTag(name.clone()));
+            }
+            token => {
+                self.insert_element("head", Vec::new());
+                self.mode = InsertionMode::InHead;
+                self.process(token);
+            }
+        }
     }
 
-    // 要素ノード（開始タグ、内容、終了タグを含む）を解析します。
-    fn parse_element(&mut self) -> dom::Node {
-        // Opening tag.
-        assert!(self.consume_char() == '<');
-        let tag_name = self.parse_tag_name();
-        let attrs = self.parse_attributes();
-        assert!(self.consume_char() == '>');
+    fn in_head(&mut self, token: Token) {
+        match token {
+            Token::Text(text) => {
+                let (whitespace, rest) = split_leading_whitespace(&text);
+                self.insert_text(whitespace);
+                if !rest.is_empty() {
+                    self.leave_head();
+                    self.process(Token::Text(rest.to_string()));
+                }
+            }
+            Token::StartTag { ref name, .. } if name == "html" => self.in_body(token),
+            Token::StartTag {
+                ref name,
+                ref attributes,
+                self_closing,
+            } if HEAD_ELEMENTS.contains(&&**name) => {
+                self.insert_start_tag(name, attributes.clone(), self_closing);
+            }
+            Token::StartTag { ref name, .. } if name == "head" => {
+                self.error(ErrorKind::UnexpectedStartTag(name.clone()));
+            }
+            Token::EndTag { ref name } if name == "head" => {
+                self.pop();
+                self.mode = InsertionMode::AfterHead;
+            }
+            Token::EndTag { ref name } if !matches!(&**name, "body" | "html" | "br") => {
+                self.error(ErrorKind::UnexpectedClosingTag(name.clone()));
+            }
+            token => {
+                self.leave_head();
+                self.process(token);
+            }
+        }
+    }
 
-        // Contents.
-        let children = self.parse_nodes();
+    fn after_head(&mut self, token: Token) {
+        match token {
+            Token::Text(text) => {
+                let (whitespace, rest) = split_leading_whitespace(&text);
+                self.insert_text(whitespace);
+                if !rest.is_empty() {
+                    self.insert_element("body", Vec::new());
+                    self.mode = InsertionMode::InBody;
+                    self.process(Token::Text(rest.to_string()));
+                }
+            }
+            Token::StartTag { ref name, .. } if name == "html" => self.in_body(token),
+            Token::StartTag {
+                ref name,
+                ref attributes,
+                ..
+            } if name == "body" => {
+                self.insert_element(name, attributes.clone());
+                self.mode = InsertionMode::InBody;
+            }
+            Token::StartTag { ref name, .. } if HEAD_ELEMENTS.contains(&&**name) => {
+                // </head>の後に現れたheadの要素は、<head>を開き直してそこに入れる。
+                self.error(ErrorKind::UnexpectedStartTag(name.clone()));
+                self.reopen_head();
+                self.mode = InsertionMode::InHead;
+                self.process(token);
+            }
+            Token::StartTag { ref name, .. } if name == "head" => {
+                self.error(ErrorKind::UnexpectedStartTag(name.clone()));
+            }
+            Token::EndTag { ref name } if !matches!(&**name, "body" | "html" | "br") => {
+                self.error(ErrorKind::UnexpectedClosingTag(name.clone()));
+            }
+            token => {
+                self.insert_element("body", Vec::new());
+                self.mode = InsertionMode::InBody;
+                self.process(token);
+            }
+        }
+    }
 
-        // Closing tag.
-        assert!(self.consume_char() == '<');
-        assert!(self.consume_char() == '/');
-        assert!(self.parse_tag_name() == tag_name);
-        assert!(self.consume_char() == '>');
+    fn in_body(&mut self, token: Token) {
+        match token {
+            Token::Text(text) => self.insert_text(&text),
+            Token::StartTag {
+                name,
+                attributes,
+                self_closing,
+            } => self.in_body_start_tag(name, attributes, self_closing),
+            Token::EndTag { name } => self.in_body_end_tag(name),
+            // コメントとDOCTYPEはprocess()で処理済み
+            Token::Comment(_) | Token::Doctype(_) => {}
+            Token::Eof => {
+                if let Some(name) = self.open_elements.iter().rev().map(tag_name).find(|name| {
+                    !IMPLIED_END_TAGS.contains(name)
+                        && !matches!(
+                            *name,
+                            "tbody" | "td" | "tfoot" | "th" | "thead" | "tr" | "body" | "html"
+                        )
+                }) {
+                    self.error(ErrorKind::UnclosedElement(name.to_string()));
+                }
+            }
+        }
+    }
 
-        // DOMを返す
-        return dom::elem(tag_name, attrs, children);
+    fn in_body_start_tag(
+        &mut self,
+        name: String,
+        attributes: Vec<(String, String)>,
+        self_closing: bool,
+    ) {
+        match &*name {
+            "html" => {
+                self.error(ErrorKind::UnexpectedStartTag(name.clone()));
+                self.merge_attributes(0, attributes);
+            }
+            "body" => {
+                self.error(ErrorKind::UnexpectedStartTag(name.clone()));
+                if self.open_elements.len() > 1 && tag_name(&self.open_elements[1]) == "body" {
+                    self.merge_attributes(1, attributes);
+                }
+            }
+            "head" => self.error(ErrorKind::UnexpectedStartTag(name.clone())),
+            "image" => {
+                // <image>は<img>の誤りとして扱う。
+                self.error(ErrorKind::UnexpectedStartTag(name.clone()));
+                self.in_body_start_tag("img".to_string(), attributes, self_closing);
+            }
+            "li" => {
+                self.close_list_item(&["li"]);
+                self.close_p_element();
+                self.insert_start_tag(&name, attributes, self_closing);
+            }
+            "dd" | "dt" => {
+                self.close_list_item(&["dd", "dt"]);
+                self.close_p_element();
+                self.insert_start_tag(&name, attributes, self_closing);
+            }
+            "button" => {
+                if self.has_element_in_scope(&["button"], &[]) {
+                    self.error(ErrorKind::UnexpectedStartTag(name.clone()));
+                    self.generate_implied_end_tags(None);
+                    self.pop_until(&["button"]);
+                }
+                self.insert_start_tag(&name, attributes, self_closing);
+            }
+            "option" | "optgroup" => {
+                if self.current_name() == "option" {
+                    self.pop();
+                }
+                self.insert_start_tag(&name, attributes, self_closing);
+            }
+            "tr" => {
+                if self.has_element_in_scope(&["tr", "td", "th"], &[]) {
+                    self.generate_implied_end_tags(None);
+                    self.pop_until(&["tr"]);
+                }
+                self.insert_start_tag(&name, attributes, self_closing);
+            }
+            "td" | "th" => {
+                if self.has_element_in_scope(&["td", "th"], &[]) {
+                    self.generate_implied_end_tags(None);
+                    self.pop_until(&["td", "th"]);
+                }
+                self.insert_start_tag(&name, attributes, self_closing);
+            }
+            _ if CLOSES_P.contains(&&*name) => {
+                self.close_p_element();
+                if HEADINGS.contains(&&*name) && HEADINGS.contains(&self.current_name()) {
+                    self.error(ErrorKind::UnexpectedStartTag(name.clone()));
+                    self.pop();
+                }
+                self.insert_start_tag(&name, attributes, self_closing);
+                if matches!(&*name, "pre" | "listing") {
+                    self.ignore_line_feed = true;
+                }
+            }
+            _ => {
+                self.insert_start_tag(&name, attributes, self_closing);
+                if name == "textarea" {
+                    self.ignore_line_feed = true;
+                }
+            }
+        }
     }
 
-    // 単一の属性（例：class="example"）を解析します。
-    // 属性名と値のペア（例：("class", "example")）が返されます。
-    fn parse_attr(&mut self) -> (String, String) {
-        let name = self.parse_tag_name();
-        assert!(self.consume_char() == '=');
-        let value = self.parse_attr_value();
-        return (name, value);
+    fn in_body_end_tag(&mut self, name: String) {
+        match &*name {
+            "body" | "html" => {
+                if !self.has_element_in_scope(&["body"], &[]) {
+                    self.error(ErrorKind::UnexpectedClosingTag(name));
+                    return;
+                }
+                self.mode = InsertionMode::AfterBody;
+                if name == "html" {
+                    self.process(Token::EndTag { name });
+                }
+            }
+            "p" => {
+                if !self.has_element_in_scope(&["p"], &["button"]) {
+                    // 対応する<p>がなければ、空の<p>があったものとして扱う。
+                    self.error(ErrorKind::UnexpectedClosingTag(name.clone()));
+                    self.insert_element("p", Vec::new());
+                }
+                self.close_p_element();
+            }
+            "li" => self.close_element_in_scope(&name, &["li"], &["ol", "ul"]),
+            "dd" | "dt" => self.close_element_in_scope(&name, &[&name], &[]),
+            _ if HEADINGS.contains(&&*name) => self.close_element_in_scope(&name, HEADINGS, &[]),
+            "br" => {
+                // </br>は<br>として扱う。
+                self.error(ErrorKind::UnexpectedClosingTag(name.clone()));
+                self.insert_start_tag("br", Vec::new(), false);
+            }
+            _ if SPECIAL_ELEMENTS.contains(&&*name) => {
+                self.close_element_in_scope(&name, &[&name], &[])
+            }
+            _ => self.any_other_end_tag(name),
+        }
     }
 
-    // 属性値を解析します。
-    // 最初に開始引用符をself.consume_charで消費し、次に引用符が再び現れるまでの間、文字を消費します。
-    fn parse_attr_value(&mut self) -> String {
-        let open_quote = self.consume_char();
-        assert!(open_quote == '"' || open_quote == '\'');
-        let value = self.consume_while(|c| c != open_quote);
-        assert!(self.consume_char() == open_quote);
-        return value;
+    fn text(&mut self, token: Token) {
+        match token {
+            Token::Text(text) => self.insert_text(&text),
+            Token::Eof => {
+                self.error(ErrorKind::UnclosedElement(self.current_name().to_string()));
+                self.pop();
+                self.mode = self.original_mode;
+                self.process(Token::Eof);
+            }
+            _ => {
+                self.pop();
+                self.mode = self.original_mode;
+            }
+        }
     }
 
-    // 複数の属性を解析し、HashMapとして返します。
-    // タグの終了（>）が見つかると、属性のHashMapを返します。
-    fn parse_attributes(&mut self) -> dom::AttrMap {
-        let mut attributes = HashMap::new();
-        loop {
-            self.consume_whitespace();
-            if self.next_char() == '>' {
-                break;
+    fn after_body(&mut self, token: Token) {
+        match token {
+            Token::Text(ref text) if is_whitespace(text) => self.in_body(token),
+            Token::StartTag { ref name, .. } if name == "html" => self.in_body(token),
+            Token::EndTag { ref name } if name == "html" => {
+                self.mode = InsertionMode::AfterAfterBody;
+            }
+            Token::Eof => {}
+            token => {
+                // </body>の後の内容は<body>の中に戻して処理する。
+                self.error(ErrorKind::ContentAfterBody);
+                self.mode = InsertionMode::InBody;
+                self.process(token);
             }
-            let (name, value) = self.parse_attr();
-            attributes.insert(name, value);
         }
-        return attributes;
     }
 
-    // 複数の兄弟ノード（隣接するノード）を解析します。
-    // 空白をスキップし、現在位置がファイルの終わりか、閉じタグの開始（"</"）であるかを確認します。
-    // 閉じタグに達するか、入力が終わるまで、self.parse_nodeを繰り返し呼び出して各ノードを解析し、ベクタに追加します。
-    fn parse_nodes(&mut self) -> Vec<dom::Node> {
-        let mut nodes = Vec::new();
-        loop {
-            self.consume_whitespace();
-            if self.eof() || self.starts_with("</") {
+    // --- 要素の挿入 ---
+
+    fn insert_html(&mut self, attributes: Vec<(String, String)>) {
+        self.html_index = self.document_children.len();
+        self.open_elements.push(dom::elem(
+            "html".to_string(),
+            attributes.into_iter().collect(),
+            vec![],
+        ));
+        self.mode = InsertionMode::BeforeHead;
+    }
+
+    fn insert_element(&mut self, name: &str, attributes: Vec<(String, String)>) {
+        self.open_elements.push(dom::elem(
+            name.to_string(),
+            attributes.into_iter().collect(),
+            vec![],
+        ));
+    }
+
+    // 開始タグの要素を挿入します。空要素はすぐに閉じ、RAWTEXT/RCDATA要素ではTextモードに入ります。
+    fn insert_start_tag(
+        &mut self,
+        name: &str,
+        attributes: Vec<(String, String)>,
+        self_closing: bool,
+    ) {
+        self.insert_element(name, attributes);
+        if VOID_ELEMENTS.contains(&name) {
+            self.pop();
+        } else if self_closing && self.in_foreign_content() {
+            // SVGやMathMLの中では`/>`で要素が閉じる。
+            self.pop();
+        } else if self_closing {
+            self.error(ErrorKind::NonVoidSelfClosing(name.to_string()));
+            if RAW_TEXT_ELEMENTS.contains(&name) {
+                self.pop();
+            }
+        } else if RAW_TEXT_ELEMENTS.contains(&name) {
+            self.original_mode = self.mode;
+            self.mode = InsertionMode::Text;
+        }
+    }
+
+    // コメントを挿入します。<html>の前後のコメントは文書の直下に置きます。
+    fn insert_comment(&mut self, data: String) {
+        let node = dom::comment(data);
+        match self.mode {
+            InsertionMode::BeforeHtml | InsertionMode::AfterAfterBody => {
+                self.document_children.push(node)
+            }
+            InsertionMode::AfterBody => self.open_elements[0].children.push(node),
+            _ => self
+                .open_elements
+                .last_mut()
+                .expect("no open element")
+                .children
+                .push(node),
+        }
+    }
+
+    // DOCTYPE宣言は文書の先頭（コメントと空白の後）でだけ受け付けます。
+    fn insert_doctype(&mut self, doctype: dom::Doctype) {
+        if self.mode == InsertionMode::BeforeHtml && self.doctype.is_none() {
+            self.doctype = Some(doctype);
+        } else {
+            self.error(ErrorKind::UnexpectedDoctype);
+        }
+    }
+
+    // テキストを現在の要素に追加します。直前の子がテキストなら、そこにつなげます。
+    fn insert_text(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        let parent = self.open_elements.last_mut().expect("no open element");
+        if let Some(dom::Node {
+            node_type: dom::NodeType::Text(ref mut data),
+            ..
+        }) = parent.children.last_mut()
+        {
+            data.push_str(text);
+            return;
+        }
+        parent.children.push(dom::text(text.to_string()));
+    }
+
+    // 既に開いている要素に、まだない属性だけを追加します。
+    fn merge_attributes(&mut self, index: usize, attributes: Vec<(String, String)>) {
+        if let dom::NodeType::Element(ref mut elem) = self.open_elements[index].node_type {
+            for (name, value) in attributes {
+                elem.attributes.entry(name).or_insert(value);
+            }
+        }
+    }
+
+    // <head>を閉じてAfterHeadモードに移ります。
+    fn leave_head(&mut self) {
+        self.pop();
+        self.mode = InsertionMode::AfterHead;
+    }
+
+    // 閉じた<head>を<html>の子から取り出し、もう一度開きます。
+    fn reopen_head(&mut self) {
+        let html = &mut self.open_elements[0];
+        match html
+            .children
+            .iter()
+            .rposition(|child| tag_name(child) == "head")
+        {
+            Some(index) => {
+                let head = html.children.remove(index);
+                self.open_elements.push(head);
+            }
+            None => self.insert_element("head", Vec::new()),
+        }
+    }
+
+    // --- 開いている要素のスタックの操作 ---
+
+    fn current_name(&self) -> &str {
+        self.open_elements.last().map_or("", tag_name)
+    }
+
+    // 現在の要素を閉じ、親の子として追加します。ルートの<html>は閉じません。
+    fn pop(&mut self) {
+        if self.open_elements.len() > 1 {
+            let node = self.open_elements.pop().unwrap();
+            self.open_elements.last_mut().unwrap().children.push(node);
+        }
+    }
+
+    // `names`のいずれかの要素を閉じるまで要素を閉じていきます。
+    fn pop_until(&mut self, names: &[&str]) {
+        while self.open_elements.len() > 1 {
+            let done = names.contains(&self.current_name());
+            self.pop();
+            if done {
                 break;
             }
-            nodes.push(self.parse_node());
         }
-        return nodes;
+    }
+
+    // 終了タグが省略できる要素を閉じます。`except`の要素は閉じません。
+    fn generate_implied_end_tags(&mut self, except: Option<&str>) {
+        while IMPLIED_END_TAGS.contains(&self.current_name()) && Some(self.current_name()) != except
+        {
+            self.pop();
+        }
+    }
+
+    // `names`のいずれかの要素がスコープ内で開いているかどうか。
+    // `extra_boundaries`はボタンスコープやリスト項目スコープで追加される境界です。
+    fn has_element_in_scope(&self, names: &[&str], extra_boundaries: &[&str]) -> bool {
+        for node in self.open_elements.iter().rev() {
+            let name = tag_name(node);
+            if names.contains(&name) {
+                return true;
+            }
+            if SCOPE_BOUNDARIES.contains(&name) || extra_boundaries.contains(&name) {
+                return false;
+            }
+        }
+        false
+    }
+
+    // ボタンスコープ内に<p>が開いていれば閉じます。
+    fn close_p_element(&mut self) {
+        if !self.has_element_in_scope(&["p"], &["button"]) {
+            return;
+        }
+        self.generate_implied_end_tags(Some("p"));
+        if self.current_name() != "p" {
+            self.mismatched("p");
+        }
+        self.pop_until(&["p"]);
+    }
+
+    // 新しい<li>や<dd>/<dt>の前に、開いている同種の要素を閉じます。
+    fn close_list_item(&mut self, names: &[&str]) {
+        for i in (0..self.open_elements.len()).rev() {
+            let name = tag_name(&self.open_elements[i]).to_string();
+            if names.contains(&&*name) {
+                self.generate_implied_end_tags(Some(&name));
+                if self.current_name() != name {
+                    self.mismatched(&name);
+                }
+                self.pop_until(names);
+                return;
+            }
+            if SPECIAL_ELEMENTS.contains(&&*name) && !matches!(&*name, "address" | "div" | "p") {
+                return;
+            }
+        }
+    }
+
+    // スコープ内の`names`の要素を閉じる終了タグ。対応する要素がなければ無視します。
+    fn close_element_in_scope(&mut self, tag: &str, names: &[&str], extra_boundaries: &[&str]) {
+        if !self.has_element_in_scope(names, extra_boundaries) {
+            self.error(ErrorKind::UnexpectedClosingTag(tag.to_string()));
+            return;
+        }
+        self.generate_implied_end_tags(Some(tag));
+        if !names.contains(&self.current_name()) {
+            self.mismatched(tag);
+        }
+        self.pop_until(names);
+    }
+
+    // 特別な要素以外の終了タグ。スタックを上から探し、同じ名前の要素まで閉じます。
+    fn any_other_end_tag(&mut self, name: String) {
+        for i in (0..self.open_elements.len()).rev() {
+            let node_name = tag_name(&self.open_elements[i]);
+            if node_name == name {
+                self.generate_implied_end_tags(Some(&name));
+                if self.current_name() != name {
+                    self.mismatched(&name);
+                }
+                while self.open_elements.len() > i {
+                    self.pop();
+                }
+                return;
+            }
+            if SPECIAL_ELEMENTS.contains(&node_name) {
+                self.error(ErrorKind::UnexpectedClosingTag(name));
+                return;
+            }
+        }
+    }
+
+    fn in_foreign_content(&self) -> bool {
+        self.open_elements
+            .iter()
+            .any(|node| matches!(tag_name(node), "svg" | "math"))
+    }
+
+    fn mismatched(&mut self, found: &str) {
+        let expected = self.current_name().to_string();
+        self.error(ErrorKind::MismatchedClosingTag {
+            expected,
+            found: found.to_string(),
+        });
+    }
+
+    fn error(&mut self, kind: ErrorKind) {
+        self.errors.push((self.offset, kind));
+    }
+
+    // 開いている要素をすべて閉じ、文書を返します。
+    fn finish(mut self) -> dom::Document {
+        while self.open_elements.len() > 1 {
+            self.pop();
+        }
+        let html = self.open_elements.pop().expect("no root element");
+        self.document_children.insert(self.html_index, html);
+        dom::Document {
+            doctype: self.doctype,
+            children: self.document_children,
+        }
+    }
+}
+
+/// 0x80〜0x9Fの数値文字参照を、Windows-1252でその位置にある文字に置き換えます。
+fn windows_1252(code: u32) -> char {
+    let c = match code {
+        0x80 => 0x20AC,
+        0x82 => 0x201A,
+        0x83 => 0x0192,
+        0x84 => 0x201E,
+        0x85 => 0x2026,
+        0x86 => 0x2020,
+        0x87 => 0x2021,
+        0x88 => 0x02C6,
+        0x89 => 0x2030,
+        0x8A => 0x0160,
+        0x8B => 0x2039,
+        0x8C => 0x0152,
+        0x8E => 0x017D,
+        0x91 => 0x2018,
+        0x92 => 0x2019,
+        0x93 => 0x201C,
+        0x94 => 0x201D,
+        0x95 => 0x2022,
+        0x96 => 0x2013,
+        0x97 => 0x2014,
+        0x98 => 0x02DC,
+        0x99 => 0x2122,
+        0x9A => 0x0161,
+        0x9B => 0x203A,
+        0x9C => 0x0153,
+        0x9E => 0x017E,
+        0x9F => 0x0178,
+        _ => code,
+    };
+    char::from_u32(c).unwrap()
+}
+
+fn tag_name(node: &dom::Node) -> &str {
+    match node.node_type {
+        dom::NodeType::Element(ref elem) => &elem.tag_name,
+        _ => "",
     }
 }
 
-// HTML文書全体を解析して、そのルート要素（DOMツリーの最上位のノード）を返す関数です。
-pub fn parse(source: String) -> dom::Node {
-    let mut nodes = Parser {
+fn is_whitespace(text: &str) -> bool {
+    text.chars().all(|c| c.is_ascii_whitespace())
+}
+
+fn split_leading_whitespace(text: &str) -> (&str, &str) {
+    let end = text
+        .find(|c: char| !c.is_ascii_whitespace())
+        .unwrap_or(text.len());
+    text.split_at(end)
+}
+
+// HTML文書全体を解析して、文書（DOCTYPEとDOMツリー）と解析エラーを返す関数です。
+// <html>、<head>、<body>が省略されていても、常に<html>をルートとするツリーを組み立てます。
+pub fn parse(source: String) -> (dom::Document, Vec<ParseError>) {
+    let mut tokenizer = Tokenizer {
         pos: 0,
         input: source,
+        raw_text_end: None,
+        cdata_allowed: false,
+        errors: Vec::new(),
+    };
+    let mut builder = TreeBuilder {
+        mode: InsertionMode::BeforeHtml,
+        original_mode: InsertionMode::BeforeHtml,
+        open_elements: Vec::new(),
+        doctype: None,
+        document_children: Vec::new(),
+        html_index: 0,
+        ignore_line_feed: false,
+        offset: 0,
+        errors: Vec::new(),
+    };
+
+    loop {
+        tokenizer.cdata_allowed = builder.in_foreign_content();
+        let (token, offset) = tokenizer.next_token();
+        builder.offset = offset;
+        let eof = token == Token::Eof;
+        builder.process(token);
+        if eof {
+            break;
+        }
+    }
+
+    let mut errors: Vec<(usize, ErrorKind)> = tokenizer.errors;
+    errors.append(&mut builder.errors);
+    errors.sort_by_key(|&(offset, _)| offset);
+    let errors = errors
+        .into_iter()
+        .map(|(offset, kind)| ParseError {
+            kind,
+            position: Position::new(&tokenizer.input, offset),
+        })
+        .collect();
+
+    (builder.finish(), errors)
+}
+
+/// 文書の中の`<style>`要素ごとに、中身のテキストが`source`のどこから始まるかを文書順に返します。
+/// `<style/>`のように中身を生のテキストとして読まない要素では`None`です。
+/// `<style>`の中のCSSのエラー位置を、HTMLファイルの中の位置に直すのに使います。
+pub fn style_content_offsets(source: &str) -> Vec<Option<usize>> {
+    let mut tokenizer = Tokenizer {
+        pos: 0,
+        input: source.to_string(),
+        raw_text_end: None,
+        cdata_allowed: false,
+        errors: Vec::new(),
+    };
+    let mut offsets = Vec::new();
+    loop {
+        match tokenizer.next_token() {
+            (Token::Eof, _) => break,
+            (Token::StartTag { name, .. }, _) if name == "style" => {
+                // 生のテキストとして読むときは、次のトークンが中身になる。
+                let content_start = tokenizer.raw_text_end.is_some().then_some(tokenizer.pos);
+                offsets.push(content_start);
+            }
+            _ => {}
+        }
+    }
+    offsets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 文書を`<tag>text</tag>`の形の文字列にします。属性とコメントは省きます。
+    fn serialize(node: &dom::Node) -> String {
+        match node.node_type {
+            dom::NodeType::Element(ref elem) => {
+                let children: String = node.children.iter().map(serialize).collect();
+                format!("<{0}>{1}</{0}>", elem.tag_name, children)
+            }
+            dom::NodeType::Text(ref text) => text.clone(),
+            dom::NodeType::Comment(_) => String::new(),
+        }
+    }
+
+    fn body(source: &str) -> (String, Vec<ParseError>) {
+        let (document, errors) = parse(source.to_string());
+        let html = serialize(document.document_element());
+        let body = html
+            .split_once("<body>")
+            .and_then(|(_, rest)| rest.rsplit_once("</body>"))
+            .map_or(html.clone(), |(body, _)| body.to_string());
+        (body, errors)
+    }
+
+    /// 文書全体を`serialize`した文字列
+    fn document(source: &str) -> String {
+        serialize(parse(source.to_string()).0.document_element())
+    }
+
+    fn kinds(errors: &[ParseError]) -> Vec<&ErrorKind> {
+        errors.iter().map(|e| &e.kind).collect()
+    }
+
+    /// 最初の`tag`要素の属性とエラー
+    fn attributes(source: &str, tag: &str) -> (dom::AttrMap, Vec<ParseError>) {
+        let (document, errors) = parse(source.to_string());
+        let attributes = document
+            .document_element()
+            .descendants()
+            .into_iter()
+            .find_map(|node| node.element().filter(|elem| elem.tag_name == tag))
+            .map(|elem| elem.attributes.clone())
+            .unwrap();
+        (attributes, errors)
+    }
+
+    #[test]
+    fn misnested_formatting_end_tag_closes_inner_element() {
+        let (body, errors) = body("<b><i>x</b>y</i>z");
+        assert_eq!(body, "<b><i>x</i></b>yz");
+        assert_eq!(
+            kinds(&errors),
+            [
+                &ErrorKind::MismatchedClosingTag {
+                    expected: "i".to_string(),
+                    found: "b".to_string()
+                },
+                &ErrorKind::UnexpectedClosingTag("i".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn formatting_end_tag_does_not_close_paragraph() {
+        let (body, errors) = body("<b><p>x</b>y</p>");
+        assert_eq!(body, "<b><p>xy</p></b>");
+        assert_eq!(
+            kinds(&errors),
+            [
+                &ErrorKind::UnexpectedClosingTag("b".to_string()),
+                &ErrorKind::UnclosedElement("b".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn implied_paragraph_end_tags() {
+        let (body, errors) = body("<p>a<p>b<div>c</div><p>d<ul><li>e</ul>");
+        assert_eq!(
+            body,
+            "<p>a</p><p>b</p><div>c</div><p>d</p><ul><li>e</li></ul>"
+        );
+        assert!(errors.is_empty(), "{:?}", errors);
+    }
+
+    #[test]
+    fn stray_paragraph_end_tag_creates_empty_paragraph() {
+        let (body, _) = body("a</p>b");
+        assert_eq!(body, "a<p></p>b");
+    }
+
+    #[test]
+    fn raw_text_elements_keep_markup_as_text() {
+        assert_eq!(
+            document("<style>a</p><b>{}</style><script>if (a</b) {}</script>x"),
+            "<html><head><style>a</p><b>{}</style><script>if (a</b) {}</script></head><body>x</body></html>"
+        );
+    }
+
+    #[test]
+    fn rcdata_elements_decode_character_references() {
+        assert_eq!(
+            document("<title>a &amp; <b></title><textarea>&lt;i&gt;</textarea>"),
+            "<html><head><title>a & <b></title></head><body><textarea><i></textarea></body></html>"
+        );
+    }
+
+    #[test]
+    fn raw_text_end_tag_is_case_insensitive() {
+        let (body, _) = body("<textarea>x</TEXTAREA >y");
+        assert_eq!(body, "<textarea>x</textarea>y");
+    }
+
+    #[test]
+    fn style_content_offsets_point_at_raw_text() {
+        let source = "<style>a{}</style><p><style/>x</p><!-- <style> --><style>b</style>";
+        let offsets = style_content_offsets(source);
+        assert_eq!(offsets, [Some(7), None, Some(source.rfind('b').unwrap())]);
+    }
+
+    #[test]
+    fn error_positions_are_line_and_column() {
+        let (_, errors) = body("<div>\n  </>\n  <p>x</b>\n");
+        let positions: Vec<(usize, usize, &ErrorKind)> = errors
+            .iter()
+            .map(|e| (e.position.line, e.position.column, &e.kind))
+            .collect();
+        assert_eq!(
+            positions,
+            [
+                (2, 3, &ErrorKind::MissingEndTagName),
+                (3, 7, &ErrorKind::UnexpectedClosingTag("b".to_string())),
+                (4, 1, &ErrorKind::UnclosedElement("div".to_string())),
+            ]
+        );
+        assert_eq!(errors[0].position.offset, "<div>\n  ".len());
+    }
+
+    #[test]
+    fn long_run_of_empty_end_tags_does_not_overflow() {
+        let source = format!("a{}b", "</>".repeat(20_000));
+        let (body, errors) = body(&source);
+        assert_eq!(body, "ab");
+        assert_eq!(errors.len(), 20_000);
+    }
+
+    #[test]
+    fn named_character_references() {
+        let (body, errors) = body("&lt;b&gt; &amp;amp; &copy &notit; &bogus;");
+        assert_eq!(body, "<b> &amp; \u{a9} \u{ac}it; &bogus;");
+        assert_eq!(
+            kinds(&errors),
+            [
+                &ErrorKind::MissingSemicolonAfterCharacterReference,
+                &ErrorKind::MissingSemicolonAfterCharacterReference,
+                &ErrorKind::UnknownNamedCharacterReference("bogus".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn numeric_character_references() {
+        let (text, errors) = body("&#65;&#x42;&#X43;&#68");
+        assert_eq!(text, "ABCD");
+        assert_eq!(
+            kinds(&errors),
+            [&ErrorKind::MissingSemicolonAfterCharacterReference]
+        );
+
+        // C1制御文字はWindows-1252として、範囲外の値はU+FFFDとして読む。
+        let (body, errors) = body("&#x80;&#0;&#xD800;&#99999999999;&#;");
+        assert_eq!(body, "\u{20ac}\u{fffd}\u{fffd}\u{fffd}&#;");
+        assert_eq!(
+            kinds(&errors),
+            [
+                &ErrorKind::InvalidCharacterReference(0x80),
+                &ErrorKind::InvalidCharacterReference(0),
+                &ErrorKind::InvalidCharacterReference(0xD800),
+                &ErrorKind::InvalidCharacterReference(u32::MAX),
+                &ErrorKind::MissingDigitsInCharacterReference,
+            ]
+        );
+    }
+
+    #[test]
+    fn character_references_in_attribute_values() {
+        let (attributes, errors) = attributes(
+            r#"<a href="?a=1&amp;b=2&copy=3&copyright&lt" title=&quot;x&quot;></a>"#,
+            "a",
+        );
+        assert_eq!(attributes["href"], "?a=1&b=2&copy=3&copyright<");
+        assert_eq!(attributes["title"], "\"x\"");
+        assert_eq!(
+            kinds(&errors),
+            [&ErrorKind::MissingSemicolonAfterCharacterReference]
+        );
+    }
+
+    #[test]
+    fn boolean_and_unquoted_attributes() {
+        let (attributes, errors) = attributes(
+            "<input DISABLED type=checkbox Value = on checked/>",
+            "input",
+        );
+        assert_eq!(attributes["disabled"], "");
+        assert_eq!(attributes["type"], "checkbox");
+        assert_eq!(attributes["value"], "on");
+        assert_eq!(attributes["checked"], "");
+        assert!(errors.is_empty(), "{:?}", errors);
+    }
+
+    #[test]
+    fn duplicate_attribute_keeps_first_value() {
+        let (attributes, errors) = attributes("<p id=a ID=b class='x'>t</p>", "p");
+        assert_eq!(attributes["id"], "a");
+        assert_eq!(attributes["class"], "x");
+        assert_eq!(
+            kinds(&errors),
+            [&ErrorKind::DuplicateAttribute("id".to_string())]
+        );
     }
-    .parse_nodes();
 
-    // If the document contains a root element, just return it. Otherwise, create one.
-    if nodes.len() == 1 {
-        nodes.swap_remove(0)
-    } else {
-        dom::elem("html".to_string(), HashMap::new(), nodes)
+    #[test]
+    fn malformed_attribute_values() {
+        let (attributes, errors) = attributes("<p a=x\"y b=>t</p>", "p");
+        assert_eq!(attributes["a"], "x\"y");
+        assert_eq!(attributes["b"], "");
+        assert_eq!(
+            kinds(&errors),
+            [
+                &ErrorKind::UnexpectedCharInUnquotedAttributeValue('"'),
+                &ErrorKind::MissingAttributeValue("b".to_string()),
+            ]
+        );
     }
 }
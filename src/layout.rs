@@ -2,13 +2,19 @@
 // https://limpet.net/mbrubeck/2014/09/17/toy-layout-engine-6-block.html
 // @see https://developer.chrome.com/blog/inside-browser-part3?hl=ja
 
+use crate::css::Unit::Percent;
 use crate::css::Unit::Px;
 use crate::css::Value;
+use crate::dom::NodeType;
+use crate::font;
+use crate::font::Family;
 use crate::layout::BoxType::AnonymousBlock;
 use crate::layout::BoxType::BlockNode;
 use crate::layout::BoxType::InlineNode;
+use crate::layout::Value::Calc;
 use crate::layout::Value::Keyword;
 use crate::layout::Value::Length;
+use crate::layout::Value::Number;
 use crate::style::Display;
 use crate::style::StyledNode;
 
@@ -50,6 +56,20 @@ pub struct LayoutBox<'a> {
     pub dimensions: Dimensions,
     pub box_type: BoxType<'a>,
     pub children: Vec<LayoutBox<'a>>,
+    /// インラインボックスが行ボックスごとに分割された断片。ブロックボックスでは常に空です。
+    pub fragments: Vec<InlineFragment>,
+}
+
+/// 1つの行ボックスに収まるインラインボックスの一部分。
+/// インラインボックスは行の折り返し位置で分割され、行ごとに1つの断片を持ちます。
+#[derive(Clone, Debug, Default)]
+pub struct InlineFragment {
+    /// この断片のコンテンツ領域
+    pub rect: Rect,
+    /// テキストノードの場合、この行に配置されたテキスト
+    pub text: Option<String>,
+    /// 断片が属する行のインデックス
+    line: usize,
 }
 
 pub enum BoxType<'a> {
@@ -57,7 +77,7 @@ pub enum BoxType<'a> {
     InlineNode(&'a StyledNode<'a>),
     AnonymousBlock,
 }
-/**
+/*
  * レイアウト・ツリーを構築するには、各DOMノードのdisplayプロパティを調べる必要があります。
  * ノードのdisplay値を取得するコードをstyleモジュールに追加しました。
  * 指定された値がない場合は、初期値の「inline」を返します。
@@ -125,6 +145,7 @@ impl<'a> LayoutBox<'a> {
             box_type,
             dimensions: Default::default(),
             children: Vec::new(),
+            fragments: Vec::new(),
         }
     }
 
@@ -142,9 +163,226 @@ impl<'a> LayoutBox<'a> {
     fn layout(&mut self, containing_block: Dimensions) {
         match self.box_type {
             BlockNode(_) => self.layout_block(containing_block),
-            InlineNode(_) => {}  // TODO
-            AnonymousBlock => {} // TODO
+            InlineNode(_) | AnonymousBlock => self.layout_anonymous_block(containing_block),
+        }
+    }
+
+    /// 匿名ブロックはインライン整形コンテキストを確立し、インラインの子を行ボックスに流し込みます。
+    /// 匿名ブロック自体はマージン・ボーダー・パディングを持たないため、包含ブロックのコンテンツ幅をそのまま使います。
+    fn layout_anonymous_block(&mut self, containing_block: Dimensions) {
+        let d = &mut self.dimensions;
+        d.content.x = containing_block.content.x;
+        d.content.y = containing_block.content.y + containing_block.content.height;
+        d.content.width = containing_block.content.width;
+
+        let mut lines = LineBuilder::new(d.content.x, d.content.width);
+        for child in &mut self.children {
+            child.layout_inline(&mut lines);
+        }
+        lines.finish(d.content.y);
+        for child in &mut self.children {
+            child.position_inline(&lines);
+        }
+
+        // 行ボックスの高さの合計が匿名ブロックの高さになる。
+        d.content.height = sum(lines.heights.iter().copied());
+    }
+
+    /// インラインボックスを水平方向に配置し、行の折り返しを決定します。
+    /// 垂直方向の位置は行の高さが確定してから`position_inline`で決まります。
+    fn layout_inline(&mut self, lines: &mut LineBuilder) {
+        // インライン要素の中のブロックはまだ分割できないので、その中身をそのまま行に流し込む。
+        if let AnonymousBlock = self.box_type {
+            for child in &mut self.children {
+                child.layout_inline(lines);
+            }
+            return;
+        }
+        let style = self.get_style_node();
+        match style.node.node_type {
+            NodeType::Text(ref text) => self.layout_text(text, lines),
+            NodeType::Element(_) => self.layout_inline_element(lines),
+            // コメントはスタイルツリーに含まれない。
+            NodeType::Comment(_) => {}
+        }
+    }
+
+    /// テキストを空白で単語に分割し、収まらない単語の前で行を折り返します。
+    /// 連続する空白は1つにまとめられ、行頭の空白は捨てられます。
+    /// `&nbsp;`（U+00A0）は空白として扱わないので、そこでは折り返しません。
+    fn layout_text(&mut self, text: &str, lines: &mut LineBuilder) {
+        let style = self.get_style_node();
+        let font_size = font_size(style);
+        let line_height = line_height(style, font_size);
+        let family = Family::from_value(style.value("font-family"));
+        let space = font::text_width(" ", family, font_size);
+
+        // 空白はその直前の単語の後ろに置く。行頭の空白と連続する空白は捨てる。
+        let add_space = |lines: &mut LineBuilder| {
+            if lines.occupied && !lines.trailing_space {
+                lines.cursor += space;
+                lines.trailing_space = true;
+            }
+        };
+
+        if text.starts_with(is_collapsible_whitespace) {
+            add_space(lines);
+        }
+
+        let mut current: Option<InlineFragment> = None;
+        for (i, word) in text
+            .split(is_collapsible_whitespace)
+            .filter(|word| !word.is_empty())
+            .enumerate()
+        {
+            if i > 0 {
+                add_space(lines);
+            }
+            let width = font::text_width(word, family, font_size);
+            // 収まらない単語は次の行に送る。ただし行が空なら、はみ出してもその行に置く。
+            if lines.occupied && lines.cursor + width > lines.right() {
+                self.fragments.extend(current.take());
+                lines.break_line();
+            }
+
+            match current {
+                Some(ref mut fragment) => {
+                    let text = fragment.text.get_or_insert_with(String::new);
+                    text.push(' ');
+                    text.push_str(word);
+                }
+                None => {
+                    current = Some(InlineFragment {
+                        rect: Rect {
+                            x: lines.cursor,
+                            height: line_height,
+                            ..Default::default()
+                        },
+                        text: Some(word.to_string()),
+                        line: lines.line,
+                    });
+                }
+            }
+            lines.place(width, line_height);
+            if let Some(ref mut fragment) = current {
+                fragment.rect.width = lines.cursor - fragment.rect.x;
+            }
+        }
+        self.fragments.extend(current);
+
+        if text.ends_with(is_collapsible_whitespace) {
+            add_space(lines);
+        }
+    }
+
+    /// インライン要素の子を配置し、要素がまたがる各行に断片を作ります。
+    /// 左右のマージン・ボーダー・パディングは最初と最後の断片にだけ適用され、上下は行の高さに影響しません。
+    fn layout_inline_element(&mut self, lines: &mut LineBuilder) {
+        let style = self.get_style_node();
+        let zero = Length(0.0, Px);
+        let font_size = font_size(style);
+        let line_height = line_height(style, font_size);
+        // インライン要素の`%`は、行ボックスを並べる包含ブロックの幅に対する割合。
+        let cb_width = lines.width;
+
+        let d = &mut self.dimensions;
+        d.margin.left = used_value(style, "margin-left", cb_width, &zero).to_px();
+        d.margin.right = used_value(style, "margin-right", cb_width, &zero).to_px();
+        d.margin.top = used_value(style, "margin-top", cb_width, &zero).to_px();
+        d.margin.bottom = used_value(style, "margin-bottom", cb_width, &zero).to_px();
+
+        d.border.left = border_width(style, "left", cb_width).to_px();
+        d.border.right = border_width(style, "right", cb_width).to_px();
+        d.border.top = border_width(style, "top", cb_width).to_px();
+        d.border.bottom = border_width(style, "bottom", cb_width).to_px();
+
+        d.padding.left = used_value(style, "padding-left", cb_width, &zero).to_px();
+        d.padding.right = used_value(style, "padding-right", cb_width, &zero).to_px();
+        d.padding.top = used_value(style, "padding-top", cb_width, &zero).to_px();
+        d.padding.bottom = used_value(style, "padding-bottom", cb_width, &zero).to_px();
+
+        lines.cursor += d.margin.left + d.border.left + d.padding.left;
+        let start_line = lines.line;
+        let start_x = lines.cursor;
+
+        for child in &mut self.children {
+            child.layout_inline(lines);
         }
+
+        let end_line = lines.line;
+        let end_x = lines.cursor;
+        lines.cursor += d.padding.right + d.border.right + d.margin.right;
+
+        self.fragments.clear();
+        for line in start_line..=end_line {
+            let x0 = if line == start_line {
+                start_x
+            } else {
+                lines.left
+            };
+            let x1 = if line == end_line {
+                end_x
+            } else {
+                lines.line_ends[line]
+            };
+            // 行末で始まって次の行に送られた部分は空の断片になるので捨てる。
+            if x1 <= x0 && line != end_line {
+                continue;
+            }
+            lines.heights[line] = lines.heights[line].max(line_height);
+            self.fragments.push(InlineFragment {
+                rect: Rect {
+                    x: x0,
+                    width: x1 - x0,
+                    height: line_height,
+                    ..Default::default()
+                },
+                text: None,
+                line,
+            });
+        }
+    }
+
+    /// 行の高さが確定した後、断片を行の下端に揃えて垂直方向に配置します。
+    /// ボックス自体のコンテンツ領域は、すべての断片を囲む矩形になります。
+    fn position_inline(&mut self, lines: &LineBuilder) {
+        for fragment in &mut self.fragments {
+            let line = fragment.line;
+            fragment.rect.y = lines.tops[line] + lines.heights[line] - fragment.rect.height;
+        }
+        for child in &mut self.children {
+            child.position_inline(lines);
+        }
+
+        let mut fragments = self.fragments.iter().map(|f| f.rect);
+        if let Some(first) = fragments.next() {
+            self.dimensions.content = fragments.fold(first, Rect::union);
+        }
+    }
+
+    /// インラインボックスの各断片を、ボックスの寸法として返します。
+    /// 左側の辺は最初の断片にだけ、右側の辺は最後の断片にだけ付きます。
+    pub fn fragment_dimensions(&self) -> Vec<Dimensions> {
+        let last = self.fragments.len().saturating_sub(1);
+        self.fragments
+            .iter()
+            .enumerate()
+            .map(|(i, fragment)| {
+                let mut d = self.dimensions;
+                d.content = fragment.rect;
+                if i != 0 {
+                    d.margin.left = 0.0;
+                    d.border.left = 0.0;
+                    d.padding.left = 0.0;
+                }
+                if i != last {
+                    d.margin.right = 0.0;
+                    d.border.right = 0.0;
+                    d.padding.right = 0.0;
+                }
+                d
+            })
+            .collect()
     }
 
     fn layout_block(&mut self, containing_block: Dimensions) {
@@ -162,28 +400,28 @@ impl<'a> LayoutBox<'a> {
         self.calculate_block_height();
     }
 
-    // これはlookupと呼ばれるヘルパー関数を使用し、一連の値を順番に試します。
-    // 最初のプロパティが設定されていなければ、2番目のプロパティを試します。
-    // それも設定されていなければ、与えられたデフォルト値を返します。これは、省略記法のプロパティと初期値の不完全な（しかし単純な）実装です。
-    // ex) margin_left = style["margin-left"] || style["margin"] || zero;
+    // `margin`などの一括指定はCSSの解析時に個別のプロパティに展開されているので、ここでは個別のプロパティだけを見ます。
+    // 設定されていなければ初期値を使います。
+    // ex) margin_left = style["margin-left"] || zero;
     fn calculate_block_width(&mut self, containing_block: Dimensions) {
         let style = self.get_style_node();
 
         // `width` has initial value `auto`.
         let auto = Keyword("auto".to_string());
-        let mut width = style.value("width").unwrap_or(auto.clone());
+        let cb_width = containing_block.content.width;
+        let mut width = used_value(style, "width", cb_width, &auto);
 
         // margin, border, and padding have initial value 0.
         let zero = Length(0.0, Px);
 
-        let mut margin_left = style.lookup("margin-left", "margin", &zero);
-        let mut margin_right = style.lookup("margin-right", "margin", &zero);
+        let mut margin_left = used_value(style, "margin-left", cb_width, &zero);
+        let mut margin_right = used_value(style, "margin-right", cb_width, &zero);
 
-        let border_left = style.lookup("border-left-width", "border-width", &zero);
-        let border_right = style.lookup("border-right-width", "border-width", &zero);
+        let border_left = border_width(style, "left", cb_width);
+        let border_right = border_width(style, "right", cb_width);
 
-        let padding_left = style.lookup("padding-left", "padding", &zero);
-        let padding_right = style.lookup("padding-right", "padding", &zero);
+        let padding_left = used_value(style, "padding-left", cb_width, &zero);
+        let padding_right = used_value(style, "padding-right", cb_width, &zero);
 
         // プロパティが'auto'に設定されている場合は0を返すので、合計には影響しません。
         let total = sum([
@@ -270,20 +508,17 @@ impl<'a> LayoutBox<'a> {
 
         // margin, border, and padding have initial value 0.
         let zero = Length(0.0, Px);
+        let cb_width = containing_block.content.width;
 
         // If margin-top or margin-bottom is `auto`, the used value is zero.
-        d.margin.top = style.lookup("margin-top", "margin", &zero).to_px();
-        d.margin.bottom = style.lookup("margin-bottom", "margin", &zero).to_px();
+        d.margin.top = used_value(style, "margin-top", cb_width, &zero).to_px();
+        d.margin.bottom = used_value(style, "margin-bottom", cb_width, &zero).to_px();
 
-        d.border.top = style
-            .lookup("border-top-width", "border-width", &zero)
-            .to_px();
-        d.border.bottom = style
-            .lookup("border-bottom-width", "border-width", &zero)
-            .to_px();
+        d.border.top = border_width(style, "top", cb_width).to_px();
+        d.border.bottom = border_width(style, "bottom", cb_width).to_px();
 
-        d.padding.top = style.lookup("padding-top", "padding", &zero).to_px();
-        d.padding.bottom = style.lookup("padding-bottom", "padding", &zero).to_px();
+        d.padding.top = used_value(style, "padding-top", cb_width, &zero).to_px();
+        d.padding.bottom = used_value(style, "padding-bottom", cb_width, &zero).to_px();
 
         d.content.x = containing_block.content.x + d.margin.left + d.border.left + d.padding.left;
 
@@ -301,7 +536,7 @@ impl<'a> LayoutBox<'a> {
         for child in &mut self.children {
             child.layout(*d);
             // 各子コンテンツが前のコンテンツの下にレイアウトされるように、高さを追跡する。
-            d.content.height = d.content.height + child.dimensions.margin_box().height;
+            d.content.height += child.dimensions.margin_box().height;
         }
     }
 
@@ -309,7 +544,13 @@ impl<'a> LayoutBox<'a> {
     fn calculate_block_height(&mut self) {
         // 高さが明示的な長さに設定されている場合は、その長さを使用する。
         // そうでない場合は、`layout_block_children`で設定した値を保持する。
-        if let Some(Length(h, Px)) = self.get_style_node().value("height") {
+        // 包含ブロックの高さはまだわからないので、`%`を含む式は`auto`として扱う。
+        let height = match self.get_style_node().value("height") {
+            Some(Length(h, Px)) => Some(h),
+            Some(Calc(calc)) => calc.evaluate(None),
+            _ => None,
+        };
+        if let Some(h) = height {
             self.dimensions.content.height = h;
         }
     }
@@ -351,6 +592,18 @@ impl Dimensions {
 }
 
 impl Rect {
+    /// 2つの矩形を囲む最小の矩形
+    fn union(self, other: Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Rect {
+            x,
+            y,
+            width: (self.x + self.width).max(other.x + other.width) - x,
+            height: (self.y + self.height).max(other.y + other.height) - y,
+        }
+    }
+
     fn expanded_by(self, edge: EdgeSizes) -> Rect {
         Rect {
             x: self.x - edge.left,
@@ -361,9 +614,126 @@ impl Rect {
     }
 }
 
+/// インライン整形コンテキストで行ボックスを組み立てるための状態。
+/// `cursor`は現在の行で次のインライン要素が置かれるx座標です。
+struct LineBuilder {
+    left: f32,
+    width: f32,
+    cursor: f32,
+    /// 現在の行のインデックス
+    line: usize,
+    /// 現在の行に単語が1つでも置かれたかどうか
+    occupied: bool,
+    /// 現在の行が空白で終わっているかどうか
+    trailing_space: bool,
+    /// 確定した各行の、最後のインライン要素の右端
+    line_ends: Vec<f32>,
+    /// 各行の高さ
+    heights: Vec<f32>,
+    /// 各行の上端のy座標（`finish`の後に有効）
+    tops: Vec<f32>,
+}
+
+impl LineBuilder {
+    fn new(left: f32, width: f32) -> LineBuilder {
+        LineBuilder {
+            left,
+            width,
+            cursor: left,
+            line: 0,
+            occupied: false,
+            trailing_space: false,
+            line_ends: Vec::new(),
+            heights: vec![0.0],
+            tops: Vec::new(),
+        }
+    }
+
+    fn right(&self) -> f32 {
+        self.left + self.width
+    }
+
+    /// 幅`width`、高さ`height`の単語を現在の行に置きます。
+    fn place(&mut self, width: f32, height: f32) {
+        self.cursor += width;
+        self.occupied = true;
+        self.trailing_space = false;
+        self.heights[self.line] = self.heights[self.line].max(height);
+    }
+
+    /// 現在の行を確定し、新しい行を始めます。
+    fn break_line(&mut self) {
+        self.line_ends.push(self.cursor);
+        self.heights.push(0.0);
+        self.line += 1;
+        self.cursor = self.left;
+        self.occupied = false;
+        self.trailing_space = false;
+    }
+
+    /// 最後の行を確定し、各行の上端を`top`から積み上げて求めます。
+    fn finish(&mut self, top: f32) {
+        self.line_ends.push(self.cursor);
+        let mut y = top;
+        for height in &self.heights {
+            self.tops.push(y);
+            y += height;
+        }
+    }
+}
+
+/// `font-size`の値をpxで返します。指定がない場合は初期値の`medium`（16px）です。
+pub fn font_size(style: &StyledNode) -> f32 {
+    match style.value("font-size") {
+        Some(Length(size, Px)) => size,
+        _ => 16.0,
+    }
+}
+
+/// `line-height`の値をpxで返します。`normal`はフォントサイズの1.2倍として扱います。
+pub fn line_height(style: &StyledNode, font_size: f32) -> f32 {
+    match style.value("line-height") {
+        Some(Length(height, Px)) => height,
+        Some(Number(factor)) => font_size * factor,
+        _ => font_size * 1.2,
+    }
+}
+
+/// `side`のボーダーの幅の使用値。`border-*-style`でボーダーを描かない辺は0です。
+fn border_width(style: &StyledNode, side: &str, cb_width: f32) -> Value {
+    if !style.has_border(side) {
+        return Length(0.0, Px);
+    }
+    used_value(
+        style,
+        &format!("border-{}-width", side),
+        cb_width,
+        &Length(0.0, Px),
+    )
+}
+
+/// プロパティの使用値を返します。`%`は包含ブロックの幅`cb_width`に対する割合としてpxに直し、`calc()`などの式は評価します。
+/// 指定がなければ`default`です。
+fn used_value(style: &StyledNode, name: &str, cb_width: f32, default: &Value) -> Value {
+    match style.value(name) {
+        Some(Length(percent, Percent)) => Length(percent * cb_width / 100.0, Px),
+        Some(Calc(calc)) => match calc.evaluate(Some(cb_width)) {
+            Some(px) => Length(px, Px),
+            None => default.clone(),
+        },
+        Some(value) => value,
+        None => default.clone(),
+    }
+}
+
 fn sum<I>(iter: I) -> f32
 where
     I: Iterator<Item = f32>,
 {
     iter.fold(0., |a, b| a + b)
 }
+
+/// CSSで詰められる空白文字かどうか。U+00A0などのUnicodeの空白は含みません。
+fn is_collapsible_whitespace(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\n' | '\r' | '\x0c')
+}
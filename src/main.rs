@@ -1,57 +1,130 @@
-use core::panic;
-use std::fs::File;
-use std::io::Read;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read};
+use std::path::{Path, PathBuf};
+use std::process;
 
 extern crate getopts;
 extern crate image;
 
+pub mod colors;
 pub mod css;
 pub mod dom;
+pub mod entities;
+pub mod font;
 pub mod html;
 pub mod layout;
 pub mod painting;
+pub mod pdf;
+pub mod source;
 pub mod style;
+pub mod svg;
 
 fn main() {
     // Parse command-line options:
     let mut opts = getopts::Options::new();
     opts.optopt("h", "html", "HTML document", "FILENAME");
-    opts.optopt("c", "css", "CSS stylesheet", "FILENAME");
+    opts.optmulti("c", "css", "CSS stylesheet (may be repeated)", "FILENAME");
+    opts.optopt("u", "user-css", "User stylesheet", "FILENAME");
     opts.optopt("o", "output", "Output file", "FILENAME");
-    opts.optopt("f", "format", "Output file format", "png | pdf");
+    opts.optopt("f", "format", "Output file format", "png | pdf | svg");
+    opts.optopt("W", "width", "Viewport width in px (default 800)", "PX");
+    opts.optopt("H", "height", "Viewport height in px (default 600)", "PX");
+    opts.optopt(
+        "m",
+        "media",
+        "Media type for @media rules",
+        "screen | print",
+    );
+    opts.optopt(
+        "s",
+        "color-scheme",
+        "Preferred color scheme",
+        "light | dark",
+    );
 
-    let matches = opts.parse(std::env::args().skip(1)).unwrap();
+    let matches = opts
+        .parse(std::env::args().skip(1))
+        .unwrap_or_else(|e| usage_error(&opts, &e.to_string()));
     let str_arg = |flag: &str, default: &str| -> String {
         matches.opt_str(flag).unwrap_or(default.to_string())
     };
 
     // Choose a format:
-    let png = match &str_arg("f", "png")[..] {
-        "png" => true,
-        "pdf" => false,
-        x => panic!("Unknown output format: {}", x),
-    };
+    let format = str_arg("f", "png");
+    match &format[..] {
+        "png" | "pdf" | "svg" => {}
+        x => usage_error(&opts, &format!("unknown output format: {}", x)),
+    }
 
     // Read input files:
-    let html = read_source(str_arg("h", "examples/test.html"));
-    let css = read_source(str_arg("c", "examples/test.css"));
+    let html_filename = str_arg("h", "examples/test.html");
+    let css_filenames = matches.opt_strs("c");
+    let html = read_source(&html_filename);
 
-    // Since we don't have an actual window, hard-code the "viewport" size.
+    // Since we don't have an actual window, take the "viewport" size from the command line.
+    let size_arg = |flag: &str, default: &str| -> f32 {
+        let size = str_arg(flag, default);
+        match size.parse::<f32>() {
+            Ok(px) if px.is_finite() && px > 0.0 => px,
+            _ => usage_error(&opts, &format!("invalid viewport size: {}", size)),
+        }
+    };
     let mut viewport: layout::Dimensions = Default::default();
-    viewport.content.width = 800.0;
-    viewport.content.height = 600.0;
+    viewport.content.width = size_arg("W", "800");
+    viewport.content.height = size_arg("H", "600");
+
+    // `@media`の条件を評価する環境
+    let media = style::Media {
+        media_type: match &*str_arg("m", "screen") {
+            "screen" => css::MediaType::Screen,
+            "print" => css::MediaType::Print,
+            x => usage_error(&opts, &format!("unknown media type: {}", x)),
+        },
+        viewport: viewport.content,
+        color_scheme: match &*str_arg("s", "light") {
+            "light" => css::ColorScheme::Light,
+            "dark" => css::ColorScheme::Dark,
+            x => usage_error(&opts, &format!("unknown color scheme: {}", x)),
+        },
+    };
 
     // Parsing and rendering:
-    let root_node = html::parse(html);
-    let stylesheet = css::parse(css);
-    let style_root = style::style_tree(&root_node, &stylesheet);
+    // HTMLの解析エラーは回復済みなので、警告として表示して続ける。
+    let (document, html_errors) = html::parse(html.clone());
+    for e in html_errors {
+        eprintln!(
+            "{}",
+            source::diagnostic(
+                "warning",
+                &html_filename,
+                &html,
+                &e.kind.to_string(),
+                e.position
+            )
+        );
+    }
+    let mut stylesheets = Vec::new();
+    if let Some(filename) = matches.opt_str("u") {
+        stylesheets.push(load_stylesheet(&filename, css::Origin::User));
+    }
+    for filename in &css_filenames {
+        stylesheets.push(load_stylesheet(filename, css::Origin::Author));
+    }
+    stylesheets.extend(document_stylesheets(
+        &html_filename,
+        &html,
+        document.document_element(),
+    ));
+    report_style_attribute_errors(&html_filename, document.document_element());
+    register_font_faces(&stylesheets, &media);
+    let style_root = style::style_tree(document.document_element(), &stylesheets, &media);
     let layout_root = layout::layout_tree(&style_root, viewport);
 
     // Create the output file:
-    let filename = str_arg("o", if png { "output.png" } else { "output.pdf" });
+    let filename = str_arg("o", &format!("output.{}", format));
 
     // Write to the file:
-    let ok = if png {
+    let ok = if format == "png" {
         let canvas = painting::paint(&layout_root, viewport.content);
         let (w, h) = (canvas.width as u32, canvas.height as u32);
         let img = image::ImageBuffer::from_fn(w, h, move |x, y| {
@@ -60,8 +133,17 @@ fn main() {
         });
         image::DynamicImage::ImageRgba8(img).save(&filename).is_ok()
     } else {
-        // TODO: save as pdf
-        true
+        match File::create(&filename) {
+            Ok(file) => {
+                let mut writer = BufWriter::new(file);
+                if format == "pdf" {
+                    pdf::render(&layout_root, viewport.content, &mut writer).is_ok()
+                } else {
+                    svg::render(&layout_root, viewport.content, &mut writer).is_ok()
+                }
+            }
+            Err(_) => false,
+        }
     };
 
     if ok {
@@ -71,11 +153,449 @@ fn main() {
     }
 }
 
-fn read_source(filename: String) -> String {
+/// コマンドラインの誤りと使い方を表示して終了します。
+fn usage_error(opts: &getopts::Options, message: &str) -> ! {
+    eprintln!("error: {}", message);
+    eprint!(
+        "{}",
+        opts.usage("Usage: lets-build-a-browser-engine [options]")
+    );
+    process::exit(2);
+}
+
+fn read_source(filename: &str) -> String {
     let mut str = String::new();
-    File::open(filename)
-        .unwrap()
-        .read_to_string(&mut str)
-        .unwrap();
+    if let Err(e) = File::open(filename).and_then(|mut file| file.read_to_string(&mut str)) {
+        eprintln!("error: could not read {}: {}", filename, e);
+        process::exit(1);
+    }
     str
 }
+
+/// CSSファイルを読み込んで解析します。ファイルが読めなければ終了します。
+fn load_stylesheet(filename: &str, origin: css::Origin) -> css::Stylesheet {
+    let css = read_source(filename);
+    let path = Path::new(filename);
+    let base = path.parent().unwrap_or(Path::new(""));
+    let mut loading = vec![canonical_path(path)];
+    parse_stylesheet(filename, base, css, None, origin, &mut loading)
+}
+
+/// `<style>`要素のように、HTMLファイルの一部として書かれたCSSの場所
+struct Embedded<'a> {
+    html_filename: &'a str,
+    html: &'a str,
+    /// CSSの始まるHTMLの中のバイトオフセット
+    offset: usize,
+}
+
+/// スタイルシートを解析し、読み飛ばした部分のエラーを警告として表示します。
+/// `embedded`があれば、エラーの位置はHTMLファイルの中の位置として表示します。
+/// `@import`したスタイルシートも読み込み、`@font-face`のURLと合わせて`base`のディレクトリからの相対パスとして解決します。
+/// `loading`は読み込んでいる途中のファイルの並びで、`@import`の循環を見つけるのに使います。
+fn parse_stylesheet(
+    filename: &str,
+    base: &Path,
+    css: String,
+    embedded: Option<Embedded>,
+    origin: css::Origin,
+    loading: &mut Vec<PathBuf>,
+) -> css::Stylesheet {
+    let (mut stylesheet, errors) = css::parse(css.clone(), origin);
+    for e in errors {
+        let message = e.kind.to_string();
+        let diagnostic = match embedded {
+            Some(Embedded {
+                html_filename,
+                html,
+                offset,
+            }) => {
+                let position = source::Position::new(html, offset + e.position.offset);
+                source::diagnostic("warning", html_filename, html, &message, position)
+            }
+            None => source::diagnostic("warning", filename, &css, &message, e.position),
+        };
+        eprintln!("{}", diagnostic);
+    }
+    resolve_urls(&mut stylesheet.rules, filename, base, origin, loading);
+    stylesheet
+}
+
+/// `@import`したスタイルシートを読み込んで規則に加え、`@font-face`の`src`のURLをファイルのパスに直します。
+/// 読み込めないものや循環する`@import`は、警告を表示して読み飛ばします。
+fn resolve_urls(
+    rules: &mut [css::CssRule],
+    filename: &str,
+    base: &Path,
+    origin: css::Origin,
+    loading: &mut Vec<PathBuf>,
+) {
+    for rule in rules {
+        match rule {
+            css::CssRule::Import(import) => {
+                let Some(path) = local_path(base, &import.url) else {
+                    continue;
+                };
+                let key = canonical_path(&path);
+                if loading.contains(&key) {
+                    eprintln!(
+                        "warning: skipping @import of {} from {}: import cycle",
+                        path.display(),
+                        filename
+                    );
+                    continue;
+                }
+                let css = match fs::read_to_string(&path) {
+                    Ok(css) => css,
+                    Err(e) => {
+                        eprintln!(
+                            "warning: could not load stylesheet {} (imported from {}): {}",
+                            path.display(),
+                            filename,
+                            e
+                        );
+                        continue;
+                    }
+                };
+                loading.push(key);
+                let name = path.display().to_string();
+                let base = path.parent().unwrap_or(Path::new(""));
+                import.rules = parse_stylesheet(&name, base, css, None, origin, loading).rules;
+                loading.pop();
+            }
+            css::CssRule::FontFace(face) => {
+                for source in &mut face.sources {
+                    if let css::FontSource::Url { url, .. } = source {
+                        if let Some(path) = local_path(base, url) {
+                            *url = path.display().to_string();
+                        }
+                    }
+                }
+            }
+            css::CssRule::Media(media) => {
+                resolve_urls(&mut media.rules, filename, base, origin, loading)
+            }
+            css::CssRule::Style(_) | css::CssRule::At(_) => {}
+        }
+    }
+}
+
+/// 相対URLを`base`のディレクトリからのパスにします。クエリ文字列とフラグメントはファイル名に含めません。
+/// ローカルのファイルでないURLは、警告を表示して`None`を返します。
+fn local_path(base: &Path, url: &str) -> Option<PathBuf> {
+    if url.contains("://") || url.starts_with("data:") {
+        eprintln!("warning: skipping {}: only local files are supported", url);
+        return None;
+    }
+    Some(base.join(url.split(['?', '#']).next().unwrap_or("")))
+}
+
+/// `@import`の循環を見つけるために、同じファイルを同じパスで表します。
+fn canonical_path(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// `@font-face`のフォントを読み込み、テキストの描画に使えるように登録します。
+/// `src`は前から順に試します。インストールされたフォントを指す`local()`は使いません。
+fn register_font_faces(stylesheets: &[css::Stylesheet], media: &style::Media) {
+    let matches_media = |queries: &[css::MediaQuery]| style::media_matches(queries, media);
+    for face in stylesheets
+        .iter()
+        .flat_map(|stylesheet| stylesheet.font_faces(&matches_media))
+    {
+        let loaded = face.sources.iter().any(|source| {
+            let css::FontSource::Url { url, format } = source else {
+                return false;
+            };
+            // ab_glyph が読めるのは TrueType と OpenType だけ。
+            if let Some(format) = format {
+                if !matches!(&**format, "truetype" | "opentype" | "ttf" | "otf") {
+                    return false;
+                }
+            }
+            let registered = fs::read(url).map(|data| font::register(&face.family, data));
+            match registered {
+                Ok(Ok(())) => true,
+                Ok(Err(_)) => {
+                    eprintln!("warning: {} is not a TrueType or OpenType font", url);
+                    false
+                }
+                Err(e) => {
+                    eprintln!("warning: could not load font {}: {}", url, e);
+                    false
+                }
+            }
+        });
+        if !loaded {
+            eprintln!("warning: no usable source for @font-face {:?}", face.family);
+        }
+    }
+}
+
+/// 文書の中の`<style>`要素と`<link rel="stylesheet">`を、文書順に作者スタイルシートとして読み込みます。
+/// `href`と`<style>`の中の相対URLは、HTMLファイルのあるディレクトリからの相対パスとして解決します。
+/// `media`属性のある要素のスタイルシートは、その条件に合うときだけ適用します。
+/// 読み込めないものは警告を表示して読み飛ばします。
+fn document_stylesheets(html_filename: &str, html: &str, root: &dom::Node) -> Vec<css::Stylesheet> {
+    let base = Path::new(html_filename).parent().unwrap_or(Path::new(""));
+    let style_offsets = html::style_content_offsets(html);
+    let mut stylesheets = Vec::new();
+    let mut style_count = 0;
+    for node in root.descendants() {
+        let Some(elem) = node.element() else {
+            continue;
+        };
+        let (name, css, embedded, base, mut loading) = match &*elem.tag_name {
+            "style" => {
+                style_count += 1;
+                let name = format!("{} (<style> #{})", html_filename, style_count);
+                let css = node.text_content();
+                // 中身がHTMLの中にそのまま書かれていれば、エラーの位置をHTMLファイルの中の位置にする。
+                // 見つからなければ、`<style>`の中身の先頭からの位置のまま表示する。
+                let embedded = style_offsets
+                    .get(style_count - 1)
+                    .copied()
+                    .flatten()
+                    .filter(|&offset| html[offset..].starts_with(&css))
+                    .map(|offset| Embedded {
+                        html_filename,
+                        html,
+                        offset,
+                    });
+                (name, css, embedded, base.to_path_buf(), Vec::new())
+            }
+            "link" if is_stylesheet_link(elem) => {
+                let Some(href) = elem.attributes.get("href") else {
+                    continue;
+                };
+                let Some(path) = local_path(base, href) else {
+                    continue;
+                };
+                match fs::read_to_string(&path) {
+                    Ok(css) => (
+                        path.display().to_string(),
+                        css,
+                        None,
+                        path.parent().unwrap_or(Path::new("")).to_path_buf(),
+                        vec![canonical_path(&path)],
+                    ),
+                    Err(e) => {
+                        eprintln!(
+                            "warning: could not load stylesheet {} (linked from {}): {}",
+                            path.display(),
+                            html_filename,
+                            e
+                        );
+                        continue;
+                    }
+                }
+            }
+            _ => continue,
+        };
+        let mut stylesheet = parse_stylesheet(
+            &name,
+            &base,
+            css,
+            embedded,
+            css::Origin::Author,
+            &mut loading,
+        );
+        // `media`属性があれば、スタイルシート全体をその条件の`@media`規則で包む。
+        if let Some(media) = elem.attributes.get("media") {
+            let (queries, errors) = css::parse_media_query_list(media.clone());
+            let name = format!("{} (media attribute of <{}>)", html_filename, elem.tag_name);
+            for e in errors {
+                let message = e.kind.to_string();
+                eprintln!(
+                    "{}",
+                    source::diagnostic("warning", &name, media, &message, e.position)
+                );
+            }
+            let rules = std::mem::take(&mut stylesheet.rules);
+            stylesheet.rules = vec![css::CssRule::Media(css::MediaRule { queries, rules })];
+        }
+        stylesheets.push(stylesheet);
+    }
+    stylesheets
+}
+
+/// `style`属性の宣言のうち、読み飛ばされるものを警告として表示します。
+/// 宣言はスタイルを求めるときに改めて解析するので、ここではエラーを表示するだけです。
+fn report_style_attribute_errors(html_filename: &str, root: &dom::Node) {
+    for elem in root
+        .descendants()
+        .into_iter()
+        .filter_map(dom::Node::element)
+    {
+        let Some(style) = elem.attributes.get("style") else {
+            continue;
+        };
+        let name = format!("{} (style attribute of <{}>)", html_filename, elem.tag_name);
+        for e in css::parse_declaration_list(style.clone()).1 {
+            let message = e.kind.to_string();
+            eprintln!(
+                "{}",
+                source::diagnostic("warning", &name, style, &message, e.position)
+            );
+        }
+    }
+}
+
+/// `rel`属性（空白区切り、大文字小文字を区別しない）に`stylesheet`を含み、代替スタイルシートでない`<link>`かどうか。
+fn is_stylesheet_link(elem: &dom::ElementData) -> bool {
+    let rel = elem
+        .attributes
+        .get("rel")
+        .map(|rel| rel.to_ascii_lowercase())
+        .unwrap_or_default();
+    let mut tokens = rel.split_ascii_whitespace();
+    tokens.clone().any(|t| t == "stylesheet") && !tokens.any(|t| t == "alternate")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        css, document_stylesheets, fs, html, layout, load_stylesheet, process, style, PathBuf,
+    };
+
+    /// テストごとの一時ディレクトリに`files`を書き込み、そのディレクトリを返します。
+    fn write_files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("robinson-{}-{}", test, process::id()));
+        for (name, content) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        dir
+    }
+
+    fn imports(rules: &[css::CssRule]) -> Vec<(&str, &[css::CssRule])> {
+        rules
+            .iter()
+            .filter_map(|rule| match rule {
+                css::CssRule::Import(import) => Some((&*import.url, &*import.rules)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn import_loads_relative_to_importing_file() {
+        let dir = write_files(
+            "import",
+            &[
+                (
+                    "main.css",
+                    "@import 'sub/a.css'; @import 'missing.css'; p {}",
+                ),
+                ("sub/a.css", "@import url(b.css); div {}"),
+                ("sub/b.css", "span {}"),
+            ],
+        );
+        let stylesheet =
+            load_stylesheet(dir.join("main.css").to_str().unwrap(), css::Origin::Author);
+        let main = imports(&stylesheet.rules);
+        assert_eq!(main.len(), 2);
+        assert_eq!(main[0].0, "sub/a.css");
+        // 読み込めないファイルは規則のない`@import`として残る。
+        assert!(main[1].1.is_empty());
+        let a = imports(main[0].1);
+        assert_eq!(a[0].0, "b.css");
+        assert!(matches!(a[0].1, [css::CssRule::Style(_)]));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn import_cycle_is_skipped() {
+        let dir = write_files(
+            "import-cycle",
+            &[
+                ("a.css", "@import 'b.css'; a {}"),
+                ("b.css", "@import './a.css'; @import 'b.css'; b {}"),
+            ],
+        );
+        let stylesheet = load_stylesheet(dir.join("a.css").to_str().unwrap(), css::Origin::Author);
+        let b = imports(&stylesheet.rules)[0].1;
+        assert_eq!(b.len(), 3);
+        assert!(imports(b).iter().all(|(_, rules)| rules.is_empty()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn font_face_urls_are_resolved_against_the_stylesheet() {
+        let dir = write_files(
+            "font-face",
+            &[
+                ("main.css", "@import 'fonts/faces.css';"),
+                (
+                    "fonts/faces.css",
+                    "@font-face { font-family: F; src: url(f.ttf?v=1), local(F), url(http://example.com/f.ttf) }",
+                ),
+            ],
+        );
+        let stylesheet =
+            load_stylesheet(dir.join("main.css").to_str().unwrap(), css::Origin::Author);
+        let [css::CssRule::FontFace(face)] = imports(&stylesheet.rules)[0].1 else {
+            panic!("expected @font-face");
+        };
+        let path = dir.join("fonts").join("f.ttf").display().to_string();
+        assert_eq!(
+            face.sources,
+            [
+                css::FontSource::Url {
+                    url: path,
+                    format: None
+                },
+                css::FontSource::Local("F".to_string()),
+                css::FontSource::Url {
+                    url: "http://example.com/f.ttf".to_string(),
+                    format: None
+                },
+            ]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn media_attribute_limits_document_stylesheets() {
+        let page = r#"<link rel=stylesheet href=print.css media=print>
+            <style media="(min-width: 600px)">p { width: 2px }</style>
+            <style>p { height: 3px }</style><p id=x>a</p>"#;
+        let dir = write_files(
+            "media-attribute",
+            &[("page.html", page), ("print.css", "p { width: 1px }")],
+        );
+        let html_filename = dir.join("page.html").display().to_string();
+        let (document, _) = html::parse(page.to_string());
+        let stylesheets = document_stylesheets(&html_filename, page, document.document_element());
+        fs::remove_dir_all(dir).unwrap();
+
+        let media_queries: Vec<Option<usize>> = stylesheets
+            .iter()
+            .map(|stylesheet| match &stylesheet.rules[..] {
+                [css::CssRule::Media(media)] => Some(media.queries.len()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(media_queries, [Some(1), Some(1), None]);
+
+        let width_on = |media_type, width| {
+            let media = style::Media {
+                media_type,
+                viewport: layout::Rect {
+                    width,
+                    ..Default::default()
+                },
+                color_scheme: css::ColorScheme::Light,
+            };
+            let root = style::style_tree(document.document_element(), &stylesheets, &media);
+            let body = &root.children[1];
+            body.children[0].value("width")
+        };
+        let px = |n| Some(css::Value::Length(n, css::Unit::Px));
+        let auto = Some(css::Value::Keyword("auto".to_string()));
+        assert_eq!(width_on(css::MediaType::Screen, 500.0), auto);
+        assert_eq!(width_on(css::MediaType::Screen, 800.0), px(2.0));
+        assert_eq!(width_on(css::MediaType::Print, 500.0), px(1.0));
+    }
+}
@@ -1,9 +1,14 @@
 // https://limpet.net/mbrubeck/2014/11/05/toy-layout-engine-7-painting.html
 use crate::css::Color;
 use crate::css::Value;
+use crate::font;
+use crate::font::Family;
+use crate::font::GlyphRun;
+use crate::layout;
 use crate::layout::BoxType::AnonymousBlock;
 use crate::layout::BoxType::BlockNode;
 use crate::layout::BoxType::InlineNode;
+use crate::layout::Dimensions;
 use crate::layout::LayoutBox;
 use crate::layout::Rect;
 /// この記事では、ごく基本的なペイントコードを追加する。このコードはlayoutモジュールからボックスのツリーを受け取り、それらをピクセルの配列に変える。
@@ -13,44 +18,86 @@ use crate::layout::Rect;
 /// これらのAPIは、多角形、直線、曲線、グラデーション、テキストを描画する関数を提供しています。今のところ、矩形しか描けない独自のラスタライザを書こうと思う。
 /// いずれはテキスト・レンダリングを実装したい。その時には、このおもちゃのペイント・コードを捨てて、「本物の」2Dグラフィックス・ライブラリに切り替えるかもしれない。
 /// しかし、今のところ、私のブロック・レイアウト・アルゴリズムの出力を画像化するには矩形で十分だ。
+pub type DisplayList = Vec<DisplayCommand>;
 
-type DisplayList = Vec<DisplayCommand>;
-
-enum DisplayCommand {
+pub enum DisplayCommand {
     SolidColor(Color, Rect),
+    Text(Color, GlyphRun),
     // insert more commands here
 }
 
-fn build_display_list(layout_root: &LayoutBox) -> DisplayList {
+pub fn build_display_list(layout_root: &LayoutBox) -> DisplayList {
     let mut list = Vec::new();
     render_layout_box(&mut list, layout_root);
-    return list;
+    list
 }
 
 fn render_layout_box(list: &mut DisplayList, layout_box: &LayoutBox) {
-    render_background(list, layout_box);
-    render_borders(list, layout_box);
-    // TODO: render text
+    match layout_box.box_type {
+        // インラインボックスは行ごとの断片をそれぞれ描画する。
+        InlineNode(_) => {
+            for d in layout_box.fragment_dimensions() {
+                render_background(list, layout_box, &d);
+                render_borders(list, layout_box, &d);
+            }
+        }
+        BlockNode(_) | AnonymousBlock => {
+            render_background(list, layout_box, &layout_box.dimensions);
+            render_borders(list, layout_box, &layout_box.dimensions);
+        }
+    }
+    render_text(list, layout_box);
     for child in &layout_box.children {
         render_layout_box(list, child);
     }
 }
 
-///
-fn render_background(list: &mut DisplayList, layout_box: &LayoutBox) {
-    get_color(layout_box, "background").map(|color| {
-        list.push(DisplayCommand::SolidColor(
-            color,
-            layout_box.dimensions.border_box(),
-        ))
+/// ボックスの背景色を描画します。
+fn render_background(list: &mut DisplayList, layout_box: &LayoutBox, d: &Dimensions) {
+    if let Some(color) = get_color(layout_box, "background-color").filter(is_visible) {
+        list.push(DisplayCommand::SolidColor(color, d.border_box()))
+    }
+}
+
+/// テキストノードの各断片をグリフの列に変換して描画します。
+fn render_text(list: &mut DisplayList, layout_box: &LayoutBox) {
+    let style = match layout_box.box_type {
+        InlineNode(style) => style,
+        _ => return,
+    };
+    // `color`の初期値は黒とする。
+    let color = get_color(layout_box, "color").unwrap_or(Color {
+        r: 0,
+        g: 0,
+        b: 0,
+        a: 255,
     });
+    if !is_visible(&color) {
+        return;
+    }
+    let family = Family::from_value(style.value("font-family"));
+    let font_size = layout::font_size(style);
+    let line_height = layout::line_height(style, font_size);
+
+    for fragment in &layout_box.fragments {
+        if let Some(ref text) = fragment.text {
+            let baseline = fragment.rect.y + font::baseline_offset(family, font_size, line_height);
+            let run = font::shape(text, family, font_size, fragment.rect.x, baseline);
+            list.push(DisplayCommand::Text(color, run));
+        }
+    }
+}
+
+/// 完全に透明でない色かどうか。透明な色は描画しません。
+fn is_visible(color: &Color) -> bool {
+    color.a > 0
 }
 
 // CSS プロパティ `name` に指定された色、または指定されなかった場合は None を返す。
 fn get_color(layout_box: &LayoutBox, name: &str) -> Option<Color> {
     // MEMO：本家と異なる
     match &layout_box.box_type {
-        &BlockNode(ref style) | &InlineNode(ref style) => match style.value(name) {
+        &BlockNode(style) | &InlineNode(style) => match style.value(name) {
             Some(Value::ColorValue(color)) => Some(color),
             _ => None,
         },
@@ -58,59 +105,69 @@ fn get_color(layout_box: &LayoutBox, name: &str) -> Option<Color> {
     }
 }
 
-///
-fn render_borders(list: &mut DisplayList, layout_box: &LayoutBox) {
-    let color = match get_color(layout_box, "border-color") {
-        Some(color) => color,
-        _ => return, // border-colorが指定されていない場合はベールアウトする。
-    };
+/// `side`のボーダーの色。ボーダーを描かない辺や透明な色では`None`を返します。
+fn border_color(layout_box: &LayoutBox, side: &str) -> Option<Color> {
+    match layout_box.box_type {
+        BlockNode(style) | InlineNode(style) if !style.has_border(side) => None,
+        _ => get_color(layout_box, &format!("border-{}-color", side)).filter(is_visible),
+    }
+}
 
-    let d = &layout_box.dimensions;
+/// ボックスのボーダーを描画します。色は辺ごとに`border-*-color`から取ります。
+fn render_borders(list: &mut DisplayList, layout_box: &LayoutBox, d: &Dimensions) {
     let border_box = d.border_box();
 
     // Left border
-    list.push(DisplayCommand::SolidColor(
-        color,
-        Rect {
-            x: border_box.x,
-            y: border_box.y,
-            width: d.border.left,
-            height: border_box.height,
-        },
-    ));
+    if let Some(color) = border_color(layout_box, "left") {
+        list.push(DisplayCommand::SolidColor(
+            color,
+            Rect {
+                x: border_box.x,
+                y: border_box.y,
+                width: d.border.left,
+                height: border_box.height,
+            },
+        ));
+    }
 
     // Right border
-    list.push(DisplayCommand::SolidColor(
-        color,
-        Rect {
-            x: border_box.x + border_box.width - d.border.right,
-            y: border_box.y,
-            width: d.border.right,
-            height: border_box.height,
-        },
-    ));
+    if let Some(color) = border_color(layout_box, "right") {
+        list.push(DisplayCommand::SolidColor(
+            color,
+            Rect {
+                x: border_box.x + border_box.width - d.border.right,
+                y: border_box.y,
+                width: d.border.right,
+                height: border_box.height,
+            },
+        ));
+    }
 
     // Top border
-    list.push(DisplayCommand::SolidColor(
-        color,
-        Rect {
-            x: border_box.x,
-            y: border_box.y,
-            width: border_box.width,
-            height: d.border.top,
-        },
-    ));
+    if let Some(color) = border_color(layout_box, "top") {
+        list.push(DisplayCommand::SolidColor(
+            color,
+            Rect {
+                x: border_box.x,
+                y: border_box.y,
+                width: border_box.width,
+                height: d.border.top,
+            },
+        ));
+    }
 
     // Bottom border
-    list.push(DisplayCommand::SolidColor(
-        color,
-        Rect {
-            x: border_box.x,
-            y: border_box.y + border_box.height - d.border.bottom,
-            width: border_box.width,
-            height: d.border.bottom,
-        },
-    ));
+    if let Some(color) = border_color(layout_box, "bottom") {
+        list.push(DisplayCommand::SolidColor(
+            color,
+            Rect {
+                x: border_box.x,
+                y: border_box.y + border_box.height - d.border.bottom,
+                width: border_box.width,
+                height: d.border.bottom,
+            },
+        ));
+    }
 }
 
 pub struct Canvas {
@@ -146,15 +203,36 @@ impl Canvas {
 
                 for y in y0..y1 {
                     for x in x0..x1 {
-                        // TODO: alpha compositing with existing pixel
-                        self.pixels[x + y * self.width] = color;
+                        let pixel = &mut self.pixels[x + y * self.width];
+                        *pixel = blend(*pixel, color, 1.0);
                     }
                 }
             }
+            DisplayCommand::Text(color, run) => {
+                run.rasterize(|x, y, coverage| {
+                    if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.height {
+                        return;
+                    }
+                    let pixel = &mut self.pixels[x as usize + y as usize * self.width];
+                    *pixel = blend(*pixel, *color, coverage);
+                });
+            }
         }
     }
 }
 
+/// 被覆率`coverage`で`src`を`dst`の上に重ねた色を返します。
+fn blend(dst: Color, src: Color, coverage: f32) -> Color {
+    let alpha = coverage.clamp(0.0, 1.0) * src.a as f32 / 255.0;
+    let mix = |d: u8, s: u8| (s as f32 * alpha + d as f32 * (1.0 - alpha)).round() as u8;
+    Color {
+        r: mix(dst.r, src.r),
+        g: mix(dst.g, src.g),
+        b: mix(dst.b, src.b),
+        a: dst.a.max((alpha * 255.0).round() as u8),
+    }
+}
+
 /// LayoutBoxes のツリーをピクセルの配列にペイントします。
 pub fn paint(layout_root: &LayoutBox, bounds: Rect) -> Canvas {
     let display_list = build_display_list(layout_root);
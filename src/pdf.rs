@@ -0,0 +1,251 @@
+//! PDF output.
+//!
+//! `painting`のディスプレイリストをそのまま PDF の描画命令に変換し、ビューポートと同じ大きさの1ページの PDF を書き出します。
+//! 矩形はベクターとして描くので、拡大してもぼやけません。
+//! 半透明の色は、不透明度ごとのグラフィックス状態（ExtGState）を選んでから塗ります。
+//! テキストは PDF 標準の Type1 フォント（Helvetica / Times / Courier）で描き、各グリフはレイアウト時の位置に置きます。
+
+use std::collections::BTreeSet;
+use std::io::{self, Write};
+
+use crate::css::Color;
+use crate::font::{Family, GlyphRun};
+use crate::layout::{LayoutBox, Rect};
+use crate::painting::{build_display_list, DisplayCommand};
+
+/// 各ファミリーに対応する PDF 標準フォントと、ページのリソース内での名前。
+const FONTS: [(Family, &str, &str); 3] = [
+    (Family::SansSerif, "F1", "Helvetica"),
+    (Family::Serif, "F2", "Times-Roman"),
+    (Family::Monospace, "F3", "Courier"),
+];
+
+/// レイアウトツリーを1ページの PDF として`out`に書き出します。
+pub fn render<W: Write>(layout_root: &LayoutBox, bounds: Rect, out: &mut W) -> io::Result<()> {
+    let display_list = build_display_list(layout_root);
+
+    let mut content = Vec::new();
+    let mut alphas = BTreeSet::new();
+    for item in &display_list {
+        let (DisplayCommand::SolidColor(color, _) | DisplayCommand::Text(color, _)) = item;
+        // 完全に透明なものは描かない。
+        if color.a == 0 {
+            continue;
+        }
+        if color.a < 255 {
+            alphas.insert(color.a);
+        }
+        match item {
+            DisplayCommand::SolidColor(color, rect) => {
+                write_rect(&mut content, *color, *rect, bounds.height)?
+            }
+            DisplayCommand::Text(color, run) => {
+                write_text(&mut content, *color, run, bounds.height)?
+            }
+        }
+    }
+
+    let mut pdf = PdfWriter::new(out);
+    pdf.header()?;
+
+    pdf.object(1, |w| writeln!(w, "<< /Type /Catalog /Pages 2 0 R >>"))?;
+    pdf.object(2, |w| {
+        writeln!(w, "<< /Type /Pages /Kids [3 0 R] /Count 1 >>")
+    })?;
+    pdf.object(3, |w| {
+        write!(
+            w,
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] /Contents 4 0 R /Resources << /Font <<",
+            number(bounds.width),
+            number(bounds.height)
+        )?;
+        for (i, (_, name, _)) in FONTS.iter().enumerate() {
+            write!(w, " /{} {} 0 R", name, 5 + i)?;
+        }
+        write!(w, " >>")?;
+        if !alphas.is_empty() {
+            write!(w, " /ExtGState <<")?;
+            for &alpha in &alphas {
+                let opacity = number(alpha as f32 / 255.0);
+                write!(
+                    w,
+                    " /{} << /Type /ExtGState /ca {} /CA {} >>",
+                    alpha_name(alpha),
+                    opacity,
+                    opacity
+                )?;
+            }
+            write!(w, " >>")?;
+        }
+        writeln!(w, " >> >>")
+    })?;
+    pdf.object(4, |w| {
+        writeln!(w, "<< /Length {} >>", content.len())?;
+        writeln!(w, "stream")?;
+        w.write_all(&content)?;
+        writeln!(w, "endstream")
+    })?;
+    for (i, (_, _, base_font)) in FONTS.iter().enumerate() {
+        pdf.object(5 + i, |w| {
+            writeln!(
+                w,
+                "<< /Type /Font /Subtype /Type1 /BaseFont /{} /Encoding /WinAnsiEncoding >>",
+                base_font
+            )
+        })?;
+    }
+
+    pdf.finish(1)
+}
+
+/// 塗りつぶした矩形を描きます。PDF は左下が原点なので、y軸を反転させます。
+fn write_rect<W: Write>(w: &mut W, color: Color, rect: Rect, page_height: f32) -> io::Result<()> {
+    begin_alpha(w, color)?;
+    writeln!(w, "{} rg", rgb(color))?;
+    writeln!(
+        w,
+        "{} {} {} {} re f",
+        number(rect.x),
+        number(page_height - rect.y - rect.height),
+        number(rect.width),
+        number(rect.height)
+    )?;
+    end_alpha(w, color)
+}
+
+/// グリフの列を描きます。各グリフはレイアウトで決まった位置に1文字ずつ置きます。
+fn write_text<W: Write>(
+    w: &mut W,
+    color: Color,
+    run: &GlyphRun,
+    page_height: f32,
+) -> io::Result<()> {
+    // `@font-face`のフォントは埋め込まないので、Helvetica で代用する。
+    let font = FONTS
+        .iter()
+        .find(|(family, _, _)| *family == run.family)
+        .map_or("F1", |(_, name, _)| name);
+
+    begin_alpha(w, color)?;
+    writeln!(w, "BT")?;
+    writeln!(w, "{} rg", rgb(color))?;
+    writeln!(w, "/{} {} Tf", font, number(run.font_size))?;
+    for glyph in &run.glyphs {
+        writeln!(
+            w,
+            "1 0 0 1 {} {} Tm ({}) Tj",
+            number(glyph.x),
+            number(page_height - run.baseline),
+            escape(glyph.c)
+        )?;
+    }
+    writeln!(w, "ET")?;
+    end_alpha(w, color)
+}
+
+/// 半透明の色なら、グラフィックス状態を保存してから、その不透明度の ExtGState を選びます。
+fn begin_alpha<W: Write>(w: &mut W, color: Color) -> io::Result<()> {
+    if color.a < 255 {
+        writeln!(w, "q /{} gs", alpha_name(color.a))?;
+    }
+    Ok(())
+}
+
+/// `begin_alpha`で保存したグラフィックス状態に戻します。
+fn end_alpha<W: Write>(w: &mut W, color: Color) -> io::Result<()> {
+    if color.a < 255 {
+        writeln!(w, "Q")?;
+    }
+    Ok(())
+}
+
+/// 不透明度`alpha`（0〜255）の ExtGState のリソース内での名前
+fn alpha_name(alpha: u8) -> String {
+    format!("A{}", alpha)
+}
+
+/// 色を PDF の`rg`演算子の引数（0.0〜1.0 の RGB）にします。
+fn rgb(color: Color) -> String {
+    let c = |v: u8| number(v as f32 / 255.0);
+    format!("{} {} {}", c(color.r), c(color.g), c(color.b))
+}
+
+/// 数値を PDF の実数として書きます。PDF は指数表記を受け付けないので固定小数点にします。
+fn number(v: f32) -> String {
+    let s = format!("{:.3}", v);
+    let s = s.trim_end_matches('0').trim_end_matches('.');
+    if s == "-0" {
+        "0".to_string()
+    } else {
+        s.to_string()
+    }
+}
+
+/// 1文字を WinAnsiEncoding の文字列リテラルの中身にします。表せない文字は`?`にします。
+fn escape(c: char) -> String {
+    match c {
+        '(' | ')' | '\\' => format!("\\{}", c),
+        ' '..='~' => c.to_string(),
+        '\u{a0}'..='\u{ff}' => format!("\\{:03o}", c as u32),
+        _ => "?".to_string(),
+    }
+}
+
+/// オブジェクトのバイトオフセットを記録しながら PDF を書き出すライター。
+/// 相互参照表（xref）には各オブジェクトの開始位置が必要です。
+struct PdfWriter<'a, W: Write> {
+    out: &'a mut W,
+    position: usize,
+    offsets: Vec<usize>,
+}
+
+impl<'a, W: Write> PdfWriter<'a, W> {
+    fn new(out: &'a mut W) -> PdfWriter<'a, W> {
+        PdfWriter {
+            out,
+            position: 0,
+            offsets: Vec::new(),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.out.write_all(bytes)?;
+        self.position += bytes.len();
+        Ok(())
+    }
+
+    fn header(&mut self) -> io::Result<()> {
+        // 2行目のバイナリコメントは、このファイルがバイナリを含むことをツールに知らせるためのもの。
+        self.write(b"%PDF-1.4\n%\xE2\xE3\xCF\xD3\n")
+    }
+
+    /// `id`番のオブジェクトを書きます。オブジェクトは 1 から順番に書く必要があります。
+    fn object<F>(&mut self, id: usize, body: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut Vec<u8>) -> io::Result<()>,
+    {
+        assert_eq!(id, self.offsets.len() + 1);
+        self.offsets.push(self.position);
+        let mut buffer = format!("{} 0 obj\n", id).into_bytes();
+        body(&mut buffer)?;
+        buffer.extend_from_slice(b"endobj\n");
+        self.write(&buffer)
+    }
+
+    /// 相互参照表とトレーラーを書いて PDF を閉じます。
+    fn finish(mut self, root: usize) -> io::Result<()> {
+        let xref = self.position;
+        let mut trailer = format!("xref\n0 {}\n0000000000 65535 f \n", self.offsets.len() + 1);
+        for offset in &self.offsets {
+            trailer.push_str(&format!("{:010} 00000 n \n", offset));
+        }
+        trailer.push_str(&format!(
+            "trailer\n<< /Size {} /Root {} 0 R >>\nstartxref\n{}\n%%EOF\n",
+            self.offsets.len() + 1,
+            root,
+            xref
+        ));
+        self.write(trailer.as_bytes())?;
+        self.out.flush()
+    }
+}
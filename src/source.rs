@@ -0,0 +1,63 @@
+//! Source positions and diagnostics shared by the HTML and CSS parsers.
+
+use std::fmt;
+
+/// 入力文字列の中の位置。`offset`はバイト単位、`line`と`column`は1始まりの文字単位です。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Position {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Position {
+    /// `input`のバイトオフセット`offset`に対応する行と列を求めます。
+    pub fn new(input: &str, offset: usize) -> Position {
+        let offset = offset.min(input.len());
+        let before = &input[..offset];
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        let column = before[line_start..].chars().count() + 1;
+        Position {
+            offset,
+            line,
+            column,
+        }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// エラーメッセージに、該当する行の抜粋とエラー位置を指す`^`を添えた診断メッセージを作ります。
+/// `severity`は`error`や`warning`などの見出しです。
+///
+/// ```text
+/// error: mismatched closing tag: expected </p>, found </div>
+///  --> examples/test.html:7:5
+///   |
+/// 7 |     </div>
+///   |     ^
+/// ```
+pub fn diagnostic(
+    severity: &str,
+    filename: &str,
+    source: &str,
+    message: &str,
+    position: Position,
+) -> String {
+    let line_text = source.lines().nth(position.line - 1).unwrap_or("");
+    let gutter = " ".repeat(position.line.to_string().len());
+    let marker: String = line_text
+        .chars()
+        .take(position.column - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    format!(
+        "{severity}: {message}\n{gutter}--> {filename}:{position}\n{gutter} |\n{line} | {line_text}\n{gutter} | {marker}^",
+        line = position.line,
+    )
+}
@@ -1,21 +1,28 @@
 // https://limpet.net/mbrubeck/2014/08/23/toy-layout-engine-4-style.html
 //! Code for applying CSS styles to the DOM.
 //!
-//! This is not very interesting at the moment.  It will get much more
-//! complicated if I add support for compound selectors.
+//! Selectors are matched right to left: the rightmost simple selector is
+//! checked against the element itself, then combinators walk up to its
+//! ancestors or back through its previous siblings.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::OnceLock;
 
 use crate::{
-    css::{Rule, Selector, SimpleSelector, Specificity, Stylesheet, Value},
+    css::{
+        self, AttributeMatcher, AttributeSelector, Color, ColorScheme, Combinator, Declaration,
+        MediaFeature, MediaQuery, MediaType, Orientation, Origin, PseudoClass, Rule, Selector,
+        SimpleSelector, Specificity, Stylesheet, Unit, Value,
+    },
     dom::{ElementData, Node, NodeType},
+    layout::Rect,
 };
 
 /// CSSプロパティ名と値をマッピングするHashMapの型エイリアス
 pub type PropertyMap = HashMap<String, Value>;
 
 /// DOMノードとそれに関連するスタイル情報を保持します。
-/// 各ノードにはspecified_values（特定のCSSプロパティの値）と子ノードのリストが含まれます。
+/// 各ノードにはspecified_values（継承と初期値を適用した後のCSSプロパティの値）と子ノードのリストが含まれます。
 pub struct StyledNode<'a> {
     pub node: &'a Node,
     pub specified_values: PropertyMap,
@@ -35,12 +42,6 @@ impl<'a> StyledNode<'a> {
         self.specified_values.get(name).cloned()
     }
 
-    /// 指定されたプロパティ、そのフォールバック、またはデフォルト値を返します。
-    pub fn lookup(&self, name: &str, fallback_name: &str, default: &Value) -> Value {
-        self.value(name)
-            .unwrap_or_else(|| self.value(fallback_name).unwrap_or_else(|| default.clone()))
-    }
-
     /// displayプロパティの値を返します。
     pub fn display(&self) -> Display {
         match self.value("display") {
@@ -52,76 +53,732 @@ impl<'a> StyledNode<'a> {
             _ => Display::Inline,
         }
     }
+
+    /// `side`（`top`、`right`、`bottom`、`left`）のボーダーを描くかどうか。
+    /// `border-*-style`が`none`（初期値）か`hidden`の辺にはボーダーがなく、幅の使用値は0になります。
+    pub fn has_border(&self, side: &str) -> bool {
+        match self.value(&format!("border-{}-style", side)) {
+            Some(Value::Keyword(style)) => {
+                !style.eq_ignore_ascii_case("none") && !style.eq_ignore_ascii_case("hidden")
+            }
+            _ => false,
+        }
+    }
+}
+
+/// セレクタの照合に使う、DOMツリーの中での要素の位置。
+/// DOMノードは親への参照を持たないので、スタイルツリーを作りながら祖先の連鎖をたどれるようにします。
+#[derive(Clone, Copy)]
+struct ElementRef<'a> {
+    node: &'a Node,
+    parent: Option<&'a ElementRef<'a>>,
+    /// 親の子ノード（テキストなども含む）の中でのインデックス
+    index: usize,
+}
+
+impl<'a> ElementRef<'a> {
+    fn element(&self) -> &'a ElementData {
+        match self.node.node_type {
+            NodeType::Element(ref elem) => elem,
+            _ => panic!("ElementRef must point to an element"),
+        }
+    }
+
+    /// 親から順に、祖先の要素を返します。
+    fn ancestors(&self) -> impl Iterator<Item = &'a ElementRef<'a>> {
+        std::iter::successors(self.parent, |parent| parent.parent)
+    }
+
+    /// 直後の兄弟から順に、後ろにある兄弟の要素を返します。
+    fn next_siblings(&self) -> impl Iterator<Item = ElementRef<'a>> {
+        let parent = self.parent;
+        let start = self.index + 1;
+        let siblings = parent.map_or(&[][..], |parent| &parent.node.children[start..]);
+        siblings
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| matches!(node.node_type, NodeType::Element(_)))
+            .map(move |(i, node)| ElementRef {
+                node,
+                parent,
+                index: start + i,
+            })
+    }
+
+    /// 直前の兄弟から順に、前にある兄弟の要素を返します。
+    fn previous_siblings(&self) -> impl Iterator<Item = ElementRef<'a>> {
+        let parent = self.parent;
+        let siblings = parent.map_or(&[][..], |parent| &parent.node.children[..self.index]);
+        siblings
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, node)| matches!(node.node_type, NodeType::Element(_)))
+            .map(move |(index, node)| ElementRef {
+                node,
+                parent,
+                index,
+            })
+    }
+}
+
+/// 継承と初期値を知っているプロパティ。
+struct Property {
+    name: &'static str,
+    /// 指定がないとき親の値を受け継ぐかどうか
+    inherited: bool,
+    initial: fn() -> Value,
+}
+
+/// プロパティの初期値の表。
+/// ここにないプロパティ（marginなど）は、指定がなければレイアウトが初期値を補います。
+const PROPERTIES: &[Property] = &[
+    Property {
+        name: "color",
+        inherited: true,
+        initial: || {
+            Value::ColorValue(Color {
+                r: 0,
+                g: 0,
+                b: 0,
+                a: 255,
+            })
+        },
+    },
+    Property {
+        name: "font-family",
+        inherited: true,
+        initial: || Value::Keyword("sans-serif".to_string()),
+    },
+    Property {
+        name: "font-size",
+        inherited: true,
+        initial: || Value::Length(16.0, Unit::Px),
+    },
+    Property {
+        name: "font-style",
+        inherited: true,
+        initial: || Value::Keyword("normal".to_string()),
+    },
+    Property {
+        name: "font-weight",
+        inherited: true,
+        initial: || Value::Keyword("normal".to_string()),
+    },
+    Property {
+        name: "line-height",
+        inherited: true,
+        initial: || Value::Keyword("normal".to_string()),
+    },
+    Property {
+        name: "text-align",
+        inherited: true,
+        initial: || Value::Keyword("start".to_string()),
+    },
+    Property {
+        name: "visibility",
+        inherited: true,
+        initial: || Value::Keyword("visible".to_string()),
+    },
+    Property {
+        name: "white-space",
+        inherited: true,
+        initial: || Value::Keyword("normal".to_string()),
+    },
+    Property {
+        name: "display",
+        inherited: false,
+        initial: || Value::Keyword("inline".to_string()),
+    },
+    Property {
+        name: "border-top-style",
+        inherited: false,
+        initial: || Value::Keyword("none".to_string()),
+    },
+    Property {
+        name: "border-right-style",
+        inherited: false,
+        initial: || Value::Keyword("none".to_string()),
+    },
+    Property {
+        name: "border-bottom-style",
+        inherited: false,
+        initial: || Value::Keyword("none".to_string()),
+    },
+    Property {
+        name: "border-left-style",
+        inherited: false,
+        initial: || Value::Keyword("none".to_string()),
+    },
+    Property {
+        name: "width",
+        inherited: false,
+        initial: || Value::Keyword("auto".to_string()),
+    },
+    Property {
+        name: "height",
+        inherited: false,
+        initial: || Value::Keyword("auto".to_string()),
+    },
+];
+
+fn property(name: &str) -> Option<&'static Property> {
+    PROPERTIES.iter().find(|p| p.name == name)
+}
+
+/// ブラウザに組み込まれた既定のスタイルシート
+fn user_agent_stylesheet() -> &'static Stylesheet {
+    static STYLESHEET: OnceLock<Stylesheet> = OnceLock::new();
+    STYLESHEET.get_or_init(|| {
+        let (stylesheet, errors) = css::parse(
+            include_str!("../assets/ua.css").to_string(),
+            Origin::UserAgent,
+        );
+        assert!(
+            errors.is_empty(),
+            "invalid user agent stylesheet: {:?}",
+            errors
+        );
+        stylesheet
+    })
 }
 
 /// DOMツリーにスタイルシートを適用し、StyledNodeツリーを返します。
 /// この関数は再帰的に各ノードにスタイルを適用します。
-pub fn style_tree<'a>(root: &'a Node, stylesheet: &'a Stylesheet) -> StyledNode<'a> {
+/// `stylesheets`は文書での出現順に並べます。既定のスタイルシートは自動的に最初に加わります。
+/// `@media`の中の規則は、`media`に合うものだけを適用します。
+pub fn style_tree<'a>(root: &'a Node, stylesheets: &[Stylesheet], media: &Media) -> StyledNode<'a> {
+    let matches_media = |queries: &[MediaQuery]| media_matches(queries, media);
+    let all: Vec<ActiveStylesheet> = std::iter::once(user_agent_stylesheet())
+        .chain(stylesheets)
+        .map(|stylesheet| {
+            ActiveStylesheet::new(stylesheet.origin, stylesheet.style_rules(&matches_media))
+        })
+        .collect();
+    let context = StyleContext {
+        stylesheets: &all,
+        viewport: media.viewport,
+        root_font_size: None,
+    };
+    style_node(root, None, 0, &context, &HashMap::new())
+}
+
+/// 文書を表示する環境。`@media`の条件と、`vw`・`vh`の長さに使います。
+pub struct Media {
+    /// `screen`か`print`
+    pub media_type: MediaType,
+    pub viewport: Rect,
+    pub color_scheme: ColorScheme,
+}
+
+/// メディアクエリの並びのどれかが`media`に合うかどうか。空の並びはいつでも合います。
+pub fn media_matches(queries: &[MediaQuery], media: &Media) -> bool {
+    queries.is_empty()
+        || queries.iter().any(|query| {
+            let type_matches = match query.media_type {
+                MediaType::All => true,
+                ref media_type => *media_type == media.media_type,
+            };
+            let features_match = query
+                .features
+                .iter()
+                .all(|feature| media_feature_matches(feature, media));
+            (type_matches && features_match) != query.negated
+        })
+}
+
+fn media_feature_matches(feature: &MediaFeature, media: &Media) -> bool {
+    // メディアクエリの`em`と`rem`は、フォントサイズの初期値に対する値。
+    let base = LengthBase {
+        em: 16.0,
+        rem: 16.0,
+        viewport: media.viewport,
+    };
+    let px = |value: &Value| {
+        let mut value = value.clone();
+        base.resolve(&mut value, None);
+        value.to_px()
+    };
+    let Rect { width, height, .. } = media.viewport;
+    match feature {
+        MediaFeature::MinWidth(value) => width >= px(value),
+        MediaFeature::MaxWidth(value) => width <= px(value),
+        MediaFeature::MinHeight(value) => height >= px(value),
+        MediaFeature::MaxHeight(value) => height <= px(value),
+        MediaFeature::Orientation(Orientation::Portrait) => height >= width,
+        MediaFeature::Orientation(Orientation::Landscape) => width > height,
+        MediaFeature::PrefersColorScheme(scheme) => *scheme == media.color_scheme,
+    }
+}
+
+/// `@media`を評価した後の、1つのスタイルシートで適用するスタイル規則
+struct ActiveStylesheet<'a> {
+    origin: Origin,
+    rules: Vec<&'a Rule>,
+    /// `rules`の番号を、セレクタの右端の単純セレクタで引く索引
+    index: RuleIndex,
+}
+
+impl<'a> ActiveStylesheet<'a> {
+    fn new(origin: Origin, rules: Vec<&'a Rule>) -> ActiveStylesheet<'a> {
+        let index = RuleIndex::new(&rules);
+        ActiveStylesheet {
+            origin,
+            rules,
+            index,
+        }
+    }
+}
+
+/// スタイル規則の番号を、各セレクタの右端の単純セレクタのid、クラス、タグ名で引けるようにした索引。
+/// 要素に合う可能性のある規則だけを照合するために使います。
+/// セレクタはidがあればid、なければ最初のクラス、それもなければタグ名の1つだけで登録し、
+/// どれも持たないセレクタは`universal`に入れます。
+#[derive(Default)]
+struct RuleIndex {
+    by_id: HashMap<String, Vec<usize>>,
+    by_class: HashMap<String, Vec<usize>>,
+    by_tag: HashMap<String, Vec<usize>>,
+    universal: Vec<usize>,
+}
+
+impl RuleIndex {
+    fn new(rules: &[&Rule]) -> RuleIndex {
+        let mut index = RuleIndex::default();
+        for (i, rule) in rules.iter().enumerate() {
+            for selector in &rule.selectors {
+                let (Selector::Simple(simple) | Selector::Compound(_, _, simple)) = selector;
+                let bucket = if let Some(id) = &simple.id {
+                    index.by_id.entry(id.clone()).or_default()
+                } else if let Some(class) = simple.class.first() {
+                    index.by_class.entry(class.clone()).or_default()
+                } else if let Some(tag_name) = &simple.tag_name {
+                    index.by_tag.entry(tag_name.clone()).or_default()
+                } else {
+                    &mut index.universal
+                };
+                // 同じ規則のセレクタが同じところに入るときは1度だけ登録する。
+                if bucket.last() != Some(&i) {
+                    bucket.push(i);
+                }
+            }
+        }
+        index
+    }
+
+    /// `elem`に合う可能性のある規則の番号を、スタイルシートでの出現順に返します。
+    fn candidates(&self, elem: &ElementData) -> Vec<usize> {
+        let mut candidates = self.universal.clone();
+        if let Some(rules) = elem.id().and_then(|id| self.by_id.get(id)) {
+            candidates.extend(rules);
+        }
+        for class in elem.classes() {
+            candidates.extend(self.by_class.get(class).into_iter().flatten());
+        }
+        candidates.extend(self.by_tag.get(&elem.tag_name).into_iter().flatten());
+        // いくつものセレクタで登録された規則も、照合するのは1度だけ。
+        candidates.sort_unstable();
+        candidates.dedup();
+        candidates
+    }
+}
+
+/// スタイルツリー全体で共通の情報
+struct StyleContext<'a> {
+    stylesheets: &'a [ActiveStylesheet<'a>],
+    viewport: Rect,
+    /// ルート要素のフォントサイズ（px）。ルート要素のスタイルを求めるまでは`None`です。
+    root_font_size: Option<f32>,
+}
+
+fn style_node<'a>(
+    node: &'a Node,
+    parent: Option<&ElementRef>,
+    index: usize,
+    context: &StyleContext,
+    parent_values: &PropertyMap,
+) -> StyledNode<'a> {
+    let element = ElementRef {
+        node,
+        parent,
+        index,
+    };
+    // テキストノードにはセレクタが合わないので、値はすべて継承か初期値になる。
+    let specified = match node.node_type {
+        NodeType::Element(_) => specified_values(&element, context.stylesheets),
+        NodeType::Text(_) | NodeType::Comment(_) => PropertyMap::new(),
+    };
+    let values = computed_values(specified, parent_values, context);
+    // `rem`の基準はルート要素のフォントサイズ。
+    let context = &StyleContext {
+        root_font_size: context
+            .root_font_size
+            .or_else(|| values.get("font-size").map(Value::to_px)),
+        ..*context
+    };
     StyledNode {
-        node: root,
-        specified_values: match root.node_type {
-            NodeType::Element(ref elem) => specified_values(elem, stylesheet),
-            NodeType::Text(_) => HashMap::new(),
-        },
-        // 現在のノードの全ての子ノードに対して、再帰的にstyle_tree関数を適用します。これにより、DOMツリーの各ノードに対応するスタイル付きノードが生成されます。
-        children: root
+        node,
+        // 現在のノードの全ての子ノードに対して、再帰的にstyle_node関数を適用します。これにより、DOMツリーの各ノードに対応するスタイル付きノードが生成されます。
+        // コメントは表示されないので、スタイルツリーには含めません。
+        children: node
             .children
             .iter()
-            .map(|child| style_tree(child, stylesheet))
+            .enumerate()
+            .filter(|(_, child)| !matches!(child.node_type, NodeType::Comment(_)))
+            .map(|(i, child)| style_node(child, Some(&element), i, context, &values))
             .collect(),
+        specified_values: values,
     }
 }
 
+/// 指定値に継承と初期値を適用して、算出値を求めます。
+/// `inherit`は親の値に、`initial`は初期値に、`unset`は継承するプロパティなら親の値、そうでなければ初期値になります。
+/// 初期値の表にないプロパティの`initial`や`unset`は、値を取り除いてレイアウトの既定値に任せます。
+/// 長さは`%`を除いてpxに直します。`%`は包含ブロックの大きさが決まるレイアウトの時に解決します。
+fn computed_values(
+    specified: PropertyMap,
+    parent_values: &PropertyMap,
+    context: &StyleContext,
+) -> PropertyMap {
+    let specified = substitute_vars(specified, parent_values);
+    let inherit = |name: &str| parent_values.get(name).cloned();
+    let initial = |name: &str| property(name).map(|p| (p.initial)());
+
+    let mut values = PropertyMap::new();
+    for (name, value) in specified {
+        let value = match value {
+            Value::Keyword(ref keyword) => match &*keyword.to_ascii_lowercase() {
+                "inherit" => inherit(&name).or_else(|| initial(&name)),
+                "initial" => initial(&name),
+                "unset" if property(&name).is_some_and(|p| p.inherited) => {
+                    inherit(&name).or_else(|| initial(&name))
+                }
+                "unset" => initial(&name),
+                _ => Some(value),
+            },
+            _ => Some(value),
+        };
+        if let Some(value) = value {
+            values.insert(name, value);
+        }
+    }
+
+    // 指定のないプロパティは、継承するものは親から受け継ぎ、それ以外は初期値にする。
+    for p in PROPERTIES {
+        if !values.contains_key(p.name) {
+            let value = if p.inherited { inherit(p.name) } else { None };
+            values.insert(p.name.to_string(), value.unwrap_or_else(p.initial));
+        }
+    }
+
+    // 文字は左から右へ書くものとして扱うので、`text-align`の`start`は`left`、`end`は`right`になる。
+    if let Some(Value::Keyword(align)) = values.get_mut("text-align") {
+        if align.eq_ignore_ascii_case("start") {
+            *align = "left".to_string();
+        } else if align.eq_ignore_ascii_case("end") {
+            *align = "right".to_string();
+        }
+    }
+
+    // `currentColor`は要素の`color`の値。`color`自身に指定されたときは親の値を受け継ぐ。
+    let is_current_color = |value: &Value| matches!(value, Value::Keyword(k) if k.eq_ignore_ascii_case("currentcolor"));
+    if values.get("color").is_some_and(is_current_color) {
+        let color = inherit("color").or_else(|| initial("color"));
+        values.extend(color.map(|color| ("color".to_string(), color)));
+    }
+    let color = values["color"].clone();
+    for value in values.values_mut() {
+        if is_current_color(value) {
+            *value = color.clone();
+        }
+    }
+
+    // `font-size`の`em`と`%`は親のフォントサイズに対する値。ほかのプロパティは自身のフォントサイズを使う。
+    let parent_font_size = parent_values.get("font-size").map_or(16.0, Value::to_px);
+    let mut base = LengthBase {
+        em: parent_font_size,
+        rem: context.root_font_size.unwrap_or(16.0),
+        viewport: context.viewport,
+    };
+    if let Some(size) = values.get_mut("font-size") {
+        if let Value::Keyword(keyword) = size {
+            if let Some(px) = font_size_keyword(keyword, parent_font_size) {
+                *size = Value::Length(px, Unit::Px);
+            }
+        }
+        base.resolve(size, Some(parent_font_size));
+    }
+    base.em = values.get("font-size").map_or(16.0, Value::to_px);
+    for (name, value) in values.iter_mut() {
+        // `line-height`の`%`は自身のフォントサイズに対する値。
+        let percent_base = (name == "line-height").then_some(base.em);
+        base.resolve(value, percent_base);
+    }
+    values
+}
+
+/// カスタムプロパティを親から受け継ぎ、ほかのプロパティの`var()`を置き換えます。
+/// `var()`を置き換えた結果が無効な値になったプロパティは`unset`として扱います。
+fn substitute_vars(specified: PropertyMap, parent_values: &PropertyMap) -> PropertyMap {
+    let (custom, mut specified): (PropertyMap, PropertyMap) = specified
+        .into_iter()
+        .partition(|(name, _)| name.starts_with("--"));
+    let names: HashSet<String> = custom
+        .keys()
+        .chain(parent_values.keys().filter(|name| name.starts_with("--")))
+        .cloned()
+        .collect();
+    let mut custom = CustomProperties {
+        specified: custom,
+        parent_values,
+        computed: HashMap::new(),
+        stack: Vec::new(),
+        cyclic: HashSet::new(),
+    };
+    for (name, value) in specified.iter_mut() {
+        if let Value::Var { property, text } = value {
+            *value = css::substitute_vars(text, &mut |n| custom.get(n))
+                .and_then(|text| parse_substituted(property, name, &text))
+                .unwrap_or_else(|| Value::Keyword("unset".to_string()));
+        }
+    }
+    for name in names {
+        if let Some(text) = custom.get(&name) {
+            specified.insert(name, Value::Raw(text));
+        }
+    }
+    specified
+}
+
+/// `var()`を置き換えた文字列を一括指定プロパティ`property`の値として解析し、個別のプロパティ`name`の値を返します。
+fn parse_substituted(property: &str, name: &str, text: &str) -> Option<Value> {
+    let (declarations, errors) = css::parse_declaration_list(format!("{}: {}", property, text));
+    if !errors.is_empty() {
+        return None;
+    }
+    declarations
+        .into_iter()
+        .find(|declaration| declaration.name == name)
+        .map(|declaration| declaration.value)
+}
+
+/// 1つの要素のカスタムプロパティの算出値を、参照をたどりながら求めます。
+struct CustomProperties<'a> {
+    specified: PropertyMap,
+    parent_values: &'a PropertyMap,
+    /// 求め終わった値。`None`は無効な値（guaranteed-invalid）。
+    computed: HashMap<String, Option<String>>,
+    /// 値を求めている途中のプロパティ。ここにあるプロパティをもう一度参照したら循環している。
+    stack: Vec<String>,
+    /// 循環参照に含まれるプロパティ。代替値があっても無効になる。
+    cyclic: HashSet<String>,
+}
+
+impl CustomProperties<'_> {
+    /// カスタムプロパティ`name`の算出値を返します。値がないときや無効なときは`None`です。
+    fn get(&mut self, name: &str) -> Option<String> {
+        if let Some(value) = self.computed.get(name) {
+            return value.clone();
+        }
+        let inherited = match self.parent_values.get(name) {
+            Some(Value::Raw(text)) => Some(text.clone()),
+            _ => None,
+        };
+        let Some(Value::Raw(text)) = self.specified.get(name) else {
+            return inherited;
+        };
+        if let Some(i) = self.stack.iter().position(|n| n == name) {
+            self.cyclic.extend(self.stack[i..].iter().cloned());
+            return None;
+        }
+        let text = text.clone();
+        let value = match &*text.to_ascii_lowercase() {
+            "inherit" | "unset" => inherited,
+            "initial" => None,
+            _ => {
+                self.stack.push(name.to_string());
+                let value = css::substitute_vars(&text, &mut |n| self.get(n));
+                self.stack.pop();
+                value
+            }
+        };
+        let value = value.filter(|_| !self.cyclic.contains(name));
+        self.computed.insert(name.to_string(), value.clone());
+        value
+    }
+}
+
+/// 長さをpxに直すための基準の大きさ
+struct LengthBase {
+    em: f32,
+    rem: f32,
+    viewport: Rect,
+}
+
+impl LengthBase {
+    /// 長さをpxに直します。`%`は`percent_base`に対する割合で、`None`ならそのまま残します。
+    /// `calc()`などの式は葉の長さをpxに直し、`%`が残らなければ1つの値にまとめます。
+    fn resolve(&self, value: &mut Value, percent_base: Option<f32>) {
+        if let Value::Calc(calc) = value {
+            calc.for_each_value(&mut |leaf| self.resolve(leaf, percent_base));
+            let folded = calc.evaluate(None).map(|result| {
+                if calc.is_length() {
+                    Value::Length(result, Unit::Px)
+                } else {
+                    Value::Number(result)
+                }
+            });
+            if let Some(folded) = folded {
+                *value = folded;
+            }
+            return;
+        }
+        let Value::Length(n, ref unit) = *value else {
+            return;
+        };
+        let px = match unit {
+            Unit::Px => return,
+            Unit::Em => n * self.em,
+            Unit::Rem => n * self.rem,
+            Unit::Vw => n * self.viewport.width / 100.0,
+            Unit::Vh => n * self.viewport.height / 100.0,
+            Unit::Pt => n * 96.0 / 72.0,
+            Unit::Cm => n * 96.0 / 2.54,
+            Unit::Percent => match percent_base {
+                Some(base) => n * base / 100.0,
+                None => return,
+            },
+        };
+        *value = Value::Length(px, Unit::Px);
+    }
+}
+
+/// `small`や`larger`のような`font-size`のキーワードをpxに直します。
+fn font_size_keyword(keyword: &str, parent_font_size: f32) -> Option<f32> {
+    let px = match &*keyword.to_ascii_lowercase() {
+        "xx-small" => 9.0,
+        "x-small" => 10.0,
+        "small" => 13.0,
+        "medium" => 16.0,
+        "large" => 18.0,
+        "x-large" => 24.0,
+        "xx-large" => 32.0,
+        "xxx-large" => 48.0,
+        "smaller" => parent_font_size / 1.2,
+        "larger" => parent_font_size * 1.2,
+        _ => return None,
+    };
+    Some(px)
+}
+
 /// Apply styles to a single element, returning the specified styles.
 ///
-/// To do: Allow multiple UA/author/user stylesheets, and implement the cascade.
-fn specified_values(elem: &ElementData, stylesheet: &Stylesheet) -> PropertyMap {
-    let mut values = HashMap::new();
-    let mut rules = matching_rules(elem, stylesheet);
-
-    // Go through the rules from lowest to highest specificity.
-    rules.sort_by(|&(a, _), &(b, _)| a.cmp(&b));
-    for (_, rule) in rules {
-        for declaration in &rule.declarations {
-            values.insert(declaration.name.clone(), declaration.value.clone());
+/// カスケードの順序は、出どころと`!important`、`style`属性かどうか、詳細度、出現順の順に比べます。
+/// 後に適用した宣言ほど優先されます。
+fn specified_values(element: &ElementRef, stylesheets: &[ActiveStylesheet]) -> PropertyMap {
+    // (優先順位, style属性か, 詳細度, 出現順, 宣言)
+    let mut declarations: Vec<(u8, bool, Specificity, usize, &Declaration)> = Vec::new();
+    for stylesheet in stylesheets {
+        for (specificity, rule) in matching_rules(element, stylesheet) {
+            for declaration in &rule.declarations {
+                let order = declarations.len();
+                let level = cascade_level(stylesheet.origin, declaration.important);
+                declarations.push((level, false, specificity, order, declaration));
+            }
         }
     }
+
+    // style属性の宣言は、作者のどのセレクタよりも詳細度が高いものとして扱う。
+    // 解析できない宣言は、ブラウザと同じく黙って捨てる。
+    let inline = element
+        .element()
+        .attributes
+        .get("style")
+        .map(|style| css::parse_declaration_list(style.clone()).0)
+        .unwrap_or_default();
+    for declaration in &inline {
+        let order = declarations.len();
+        let level = cascade_level(Origin::Author, declaration.important);
+        declarations.push((level, true, (0, 0, 0), order, declaration));
+    }
+
+    // Go through the declarations from lowest to highest precedence.
+    declarations
+        .sort_by_key(|&(level, inline, specificity, order, _)| (level, inline, specificity, order));
+    let mut values = HashMap::new();
+    for (_, _, _, _, declaration) in declarations {
+        values.insert(declaration.name.clone(), declaration.value.clone());
+    }
     values
 }
 
+/// 出どころと`!important`によるカスケードの優先順位。大きいほど優先されます。
+/// `!important`の宣言では、出どころの順序が逆転します。
+fn cascade_level(origin: Origin, important: bool) -> u8 {
+    match (origin, important) {
+        (Origin::UserAgent, false) => 0,
+        (Origin::User, false) => 1,
+        (Origin::Author, false) => 2,
+        (Origin::Author, true) => 3,
+        (Origin::User, true) => 4,
+        (Origin::UserAgent, true) => 5,
+    }
+}
+
 /// A single CSS rule and the specificity of its most specific matching selector.
 type MatchedRule<'a> = (Specificity, &'a Rule);
 
 /// Find all CSS rules that match the given element.
-fn matching_rules<'a>(elem: &ElementData, stylesheet: &'a Stylesheet) -> Vec<MatchedRule<'a>> {
-    // For now, we just do a linear scan of all the rules.  For large
-    // documents, it would be more efficient to store the rules in hash tables
-    // based on tag name, id, class, etc.
+/// 索引で候補に挙がった規則だけを、スタイルシートでの出現順に照合します。
+fn matching_rules<'a>(
+    element: &ElementRef,
+    stylesheet: &ActiveStylesheet<'a>,
+) -> Vec<MatchedRule<'a>> {
     stylesheet
-        .rules
-        .iter()
-        .filter_map(|rule| match_rule(elem, rule))
+        .index
+        .candidates(element.element())
+        .into_iter()
+        .filter_map(|i| match_rule(element, stylesheet.rules[i]))
         .collect()
 }
 
 /// If `rule` matches `elem`, return a `MatchedRule`. Otherwise return `None`.
-fn match_rule<'a>(elem: &ElementData, rule: &'a Rule) -> Option<MatchedRule<'a>> {
+fn match_rule<'a>(element: &ElementRef, rule: &'a Rule) -> Option<MatchedRule<'a>> {
     // Find the first (most specific) matching selector.
     rule.selectors
         .iter()
-        .find(|selector| matches(elem, *selector))
+        .find(|selector| matches(element, selector))
         .map(|selector| (selector.specificity(), rule))
 }
 
 /// Selector matching:
-fn matches(elem: &ElementData, selector: &Selector) -> bool {
+/// 右端の単純セレクタを要素自身と照合し、結合子に応じて祖先や前の兄弟に左側のセレクタを照合します。
+fn matches(element: &ElementRef, selector: &Selector) -> bool {
     match *selector {
-        Selector::Simple(ref simple_selector) => matches_simple_selector(elem, simple_selector),
+        Selector::Simple(ref simple_selector) => matches_simple_selector(element, simple_selector),
+        Selector::Compound(ref left, combinator, ref right) => {
+            if !matches_simple_selector(element, right) {
+                return false;
+            }
+            match combinator {
+                Combinator::Descendant => element.ancestors().any(|a| matches(a, left)),
+                Combinator::Child => element.parent.is_some_and(|p| matches(p, left)),
+                Combinator::NextSibling => element
+                    .previous_siblings()
+                    .next()
+                    .is_some_and(|s| matches(&s, left)),
+                Combinator::SubsequentSibling => {
+                    element.previous_siblings().any(|s| matches(&s, left))
+                }
+            }
+        }
     }
 }
 
 ///  anyはJSのsomeと同じ
-fn matches_simple_selector(elem: &ElementData, selector: &SimpleSelector) -> bool {
+fn matches_simple_selector(element: &ElementRef, selector: &SimpleSelector) -> bool {
+    let elem = element.element();
+
     // Check type selector
     if selector.tag_name.iter().any(|name| elem.tag_name != *name) {
         return false;
@@ -139,9 +796,554 @@ fn matches_simple_selector(elem: &ElementData, selector: &SimpleSelector) -> boo
         .iter()
         .any(|class| !elem_classes.contains(&**class))
     {
-        false
+        return false;
+    }
+
+    // Check attribute selectors
+    if selector
+        .attributes
+        .iter()
+        .any(|attribute| !matches_attribute_selector(elem, attribute))
+    {
+        return false;
+    }
+
+    // Check pseudo-classes
+    if selector
+        .pseudo_classes
+        .iter()
+        .any(|pseudo_class| !matches_pseudo_class(element, pseudo_class))
+    {
+        return false;
+    }
+
+    // We didn't find any non-matching selector components.
+    true
+}
+
+/// 擬似クラスを、要素のツリーの中での位置と照合します。
+fn matches_pseudo_class(element: &ElementRef, pseudo_class: &PseudoClass) -> bool {
+    match pseudo_class {
+        PseudoClass::FirstChild => element.previous_siblings().next().is_none(),
+        PseudoClass::LastChild => element.next_siblings().next().is_none(),
+        PseudoClass::NthChild(a, b) => matches_nth(*a, *b, element.previous_siblings().count() + 1),
+        PseudoClass::NthOfType(a, b) => {
+            let tag_name = &element.element().tag_name;
+            let position = element
+                .previous_siblings()
+                .filter(|sibling| sibling.element().tag_name == *tag_name)
+                .count()
+                + 1;
+            matches_nth(*a, *b, position)
+        }
+        PseudoClass::Not(selectors) => !selectors.iter().any(|s| matches(element, s)),
+        PseudoClass::Is(selectors) | PseudoClass::Where(selectors) => {
+            selectors.iter().any(|s| matches(element, s))
+        }
+        PseudoClass::Root => element.parent.is_none(),
+        // コメントは子に含めない。
+        PseudoClass::Empty => element
+            .node
+            .children
+            .iter()
+            .all(|child| matches!(child.node_type, NodeType::Comment(_))),
+    }
+}
+
+/// 1から数えた`position`が、ある0以上の整数nについて`a*n+b`と等しいかどうか。
+fn matches_nth(a: i32, b: i32, position: usize) -> bool {
+    let diff = position as i32 - b;
+    if a == 0 {
+        diff == 0
     } else {
-        // We didn't find any non-matching selector components.
-        true
+        diff % a == 0 && diff / a >= 0
+    }
+}
+
+/// 属性セレクタを要素の属性と照合します。
+fn matches_attribute_selector(elem: &ElementData, selector: &AttributeSelector) -> bool {
+    let Some(actual) = elem.attributes.get(&selector.name) else {
+        return false;
+    };
+    let Some(expected) = selector.matcher.value() else {
+        return true;
+    };
+    let (actual, expected) = if selector.case_insensitive {
+        (actual.to_ascii_lowercase(), expected.to_ascii_lowercase())
+    } else {
+        (actual.clone(), expected.to_string())
+    };
+    match selector.matcher {
+        AttributeMatcher::Exists => true,
+        AttributeMatcher::Equals(_) => actual == expected,
+        AttributeMatcher::Includes(_) => {
+            !expected.is_empty()
+                && !expected.contains(|c: char| c.is_ascii_whitespace())
+                && actual.split_ascii_whitespace().any(|word| word == expected)
+        }
+        AttributeMatcher::DashMatch(_) => {
+            actual == expected || actual.starts_with(&format!("{}-", expected))
+        }
+        // 空の値を指定した`^=`、`$=`、`*=`はどの要素にも合わない。
+        AttributeMatcher::Prefix(_) => !expected.is_empty() && actual.starts_with(&expected),
+        AttributeMatcher::Suffix(_) => !expected.is_empty() && actual.ends_with(&expected),
+        AttributeMatcher::Substring(_) => !expected.is_empty() && actual.contains(&expected),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::html;
+
+    /// `html`に`(CSS, 出どころ)`のスタイルシートを適用し、`id`を持つ要素の`name`の値を返します。
+    fn value_of(html: &str, sheets: &[(&str, Origin)], id: &str, name: &str) -> Option<Value> {
+        let (document, _) = html::parse(html.to_string());
+        let stylesheets: Vec<Stylesheet> = sheets
+            .iter()
+            .map(|&(source, origin)| {
+                let (stylesheet, errors) = css::parse(source.to_string(), origin);
+                assert!(errors.is_empty(), "{:?}", errors);
+                stylesheet
+            })
+            .collect();
+        let media = Media {
+            media_type: MediaType::Screen,
+            viewport: Rect {
+                width: 800.0,
+                height: 600.0,
+                ..Default::default()
+            },
+            color_scheme: ColorScheme::Light,
+        };
+        let root = style_tree(document.document_element(), &stylesheets, &media);
+        find(&root, id).and_then(|node| node.value(name))
+    }
+
+    fn find<'a, 'b>(node: &'b StyledNode<'a>, id: &str) -> Option<&'b StyledNode<'a>> {
+        match node.node.node_type {
+            NodeType::Element(ref elem) if elem.id().is_some_and(|i| i == id) => Some(node),
+            _ => node.children.iter().find_map(|child| find(child, id)),
+        }
+    }
+
+    fn px(n: f32) -> Option<Value> {
+        Some(Value::Length(n, Unit::Px))
+    }
+
+    /// 作者スタイルシートだけを適用したときの`#id`の`width`
+    fn author_width_at(html: &str, css: &str, id: &str) -> Option<Value> {
+        value_of(html, &[(css, Origin::Author)], id, "width")
+    }
+
+    fn author_width(html: &str, css: &str) -> Option<Value> {
+        author_width_at(html, css, "x")
+    }
+
+    /// `selector { width: 1px }`に合う要素のidを、文書順に返します。
+    fn matched_ids(html: &str, selector: &str) -> Vec<String> {
+        let (document, _) = html::parse(html.to_string());
+        let ids: Vec<String> = document
+            .document_element()
+            .descendants()
+            .into_iter()
+            .filter_map(|node| node.element().and_then(|elem| elem.id().cloned()))
+            .collect();
+        let css = format!("{} {{ width: 1px }}", selector);
+        ids.into_iter()
+            .filter(|id| author_width_at(html, &css, id) == px(1.0))
+            .collect()
+    }
+
+    #[test]
+    fn cascade_levels_reverse_origins_for_important() {
+        let order = [
+            cascade_level(Origin::UserAgent, false),
+            cascade_level(Origin::User, false),
+            cascade_level(Origin::Author, false),
+            cascade_level(Origin::Author, true),
+            cascade_level(Origin::User, true),
+            cascade_level(Origin::UserAgent, true),
+        ];
+        assert!(order.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn author_overrides_user_agent_and_user() {
+        let html = "<p id=x>a</p>";
+        let user = ("p { margin-top: 2px }", Origin::User);
+        let author = ("p { margin-top: 3px }", Origin::Author);
+        assert_eq!(value_of(html, &[], "x", "margin-top"), px(16.0));
+        assert_eq!(value_of(html, &[user], "x", "margin-top"), px(2.0));
+        assert_eq!(value_of(html, &[author, user], "x", "margin-top"), px(3.0));
+    }
+
+    #[test]
+    fn user_important_overrides_author_important() {
+        let html = "<p id=x>a</p>";
+        let user = ("p { width: 2px !important }", Origin::User);
+        let author = ("#x { width: 3px !important }", Origin::Author);
+        assert_eq!(value_of(html, &[user, author], "x", "width"), px(2.0));
+    }
+
+    #[test]
+    fn important_overrides_specificity() {
+        let html = "<p id=x>a</p>";
+        assert_eq!(
+            author_width(html, "p { width: 2px !important } #x { width: 3px }"),
+            px(2.0)
+        );
+    }
+
+    #[test]
+    fn specificity_overrides_source_order() {
+        let html = "<p id=x class=c>a</p>";
+        assert_eq!(
+            author_width(
+                html,
+                "#x { width: 1px } p.c { width: 2px } .c { width: 3px }"
+            ),
+            px(1.0)
+        );
+        assert_eq!(
+            author_width(html, "p.c { width: 2px } .c { width: 3px }"),
+            px(2.0)
+        );
+    }
+
+    #[test]
+    fn later_rule_wins_with_equal_specificity() {
+        let html = "<p id=x class=c>a</p>";
+        assert_eq!(
+            author_width(html, ".c { width: 1px } .c { width: 2px }"),
+            px(2.0)
+        );
+    }
+
+    #[test]
+    fn inline_style_overrides_selectors_but_not_important() {
+        let html = r#"<p id=x style="width: 4px">a</p>"#;
+        assert_eq!(author_width(html, "#x { width: 1px }"), px(4.0));
+        assert_eq!(author_width(html, "p { width: 1px !important }"), px(1.0));
+        let html = r#"<p id=x style="width: 4px !important">a</p>"#;
+        assert_eq!(author_width(html, "#x { width: 1px !important }"), px(4.0));
+    }
+
+    #[test]
+    fn combinators() {
+        let html = "<div id=a><p id=b><span id=c></span></p><p id=d></p><span id=e></span></div>";
+        assert_eq!(matched_ids(html, "div span"), ["c", "e"]);
+        assert_eq!(matched_ids(html, "div > span"), ["e"]);
+        assert_eq!(matched_ids(html, "p + p"), ["d"]);
+        assert_eq!(matched_ids(html, "#b ~ span"), ["e"]);
+        assert_eq!(matched_ids(html, "p > span"), ["c"]);
+    }
+
+    #[test]
+    fn attribute_selectors() {
+        let html = r#"<div>
+            <a id=a href="https://example.com/x.pdf" lang="en-US" class="big red"></a>
+            <a id=b href="/local" lang="en" title=""></a>
+            <a id=c></a>
+        </div>"#;
+        assert_eq!(matched_ids(html, "[href]"), ["a", "b"]);
+        assert_eq!(matched_ids(html, "[href='/local']"), ["b"]);
+        assert_eq!(matched_ids(html, "[class~=red]"), ["a"]);
+        assert_eq!(matched_ids(html, "[lang|=en]"), ["a", "b"]);
+        assert_eq!(matched_ids(html, "[href^=https]"), ["a"]);
+        assert_eq!(matched_ids(html, "[href$='.PDF' i]"), ["a"]);
+        assert_eq!(matched_ids(html, "[href$='.PDF']"), Vec::<String>::new());
+        assert_eq!(matched_ids(html, "[href*=loc]"), ["b"]);
+        assert_eq!(matched_ids(html, "[title^='']"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn nth_child() {
+        let html =
+            "<ul id=u><li id=a></li><li id=b></li><li id=c></li><li id=d></li><li id=e></li></ul>";
+        assert_eq!(matched_ids(html, "li:nth-child(odd)"), ["a", "c", "e"]);
+        assert_eq!(matched_ids(html, "li:nth-child(2n)"), ["b", "d"]);
+        assert_eq!(matched_ids(html, "li:nth-child(-n+2)"), ["a", "b"]);
+        assert_eq!(matched_ids(html, "li:nth-child(3)"), ["c"]);
+        assert_eq!(matched_ids(html, "li:first-child"), ["a"]);
+        assert_eq!(matched_ids(html, "li:last-child"), ["e"]);
+    }
+
+    #[test]
+    fn nth_of_type() {
+        let html = "<div><p id=a></p><span id=b></span><p id=c></p><p id=d></p></div>";
+        assert_eq!(matched_ids(html, "p:nth-of-type(2)"), ["c"]);
+        assert_eq!(matched_ids(html, "p:nth-of-type(2n+1)"), ["a", "d"]);
+    }
+
+    #[test]
+    fn matches_nth_formula() {
+        assert!(matches_nth(2, 1, 1));
+        assert!(matches_nth(2, 1, 3));
+        assert!(!matches_nth(2, 1, 2));
+        assert!(matches_nth(0, 3, 3));
+        assert!(!matches_nth(0, 3, 4));
+        assert!(matches_nth(-1, 3, 3));
+        assert!(!matches_nth(-1, 3, 4));
+    }
+
+    #[test]
+    fn logical_pseudo_classes() {
+        let html = "<div id=r><p id=a class=x></p><p id=b></p><span id=c></span></div>";
+        assert_eq!(matched_ids(html, "p:not(.x)"), ["b"]);
+        assert_eq!(matched_ids(html, ":is(span, .x)"), ["a", "c"]);
+        assert_eq!(matched_ids(html, "#r :where(p)"), ["a", "b"]);
+        assert_eq!(matched_ids(html, "p:empty"), ["a", "b"]);
+    }
+
+    #[test]
+    fn source_order_is_kept_across_index_buckets() {
+        // `.a`と`.b`と`[class~=a]`は詳細度が同じで、索引では別々のところに入る。
+        let html = "<p id=x class='b a'>a</p>";
+        assert_eq!(
+            author_width(html, ".b { width: 1px } .a { width: 2px }"),
+            px(2.0)
+        );
+        assert_eq!(
+            author_width(html, ".a { width: 2px } .b { width: 1px }"),
+            px(1.0)
+        );
+        assert_eq!(
+            author_width(html, ".a { width: 2px } [class~=a] { width: 3px }"),
+            px(3.0)
+        );
+        assert_eq!(
+            author_width(html, "[class~=a] { width: 3px } .a { width: 2px }"),
+            px(2.0)
+        );
+    }
+
+    #[test]
+    fn rule_with_selectors_in_several_buckets_uses_most_specific_match() {
+        // `#x`と`.a`と`p`の3つのところに入る規則は、1度だけ照合して`#x`の詳細度を使う。
+        let html = "<p id=x class=a>a</p>";
+        assert_eq!(
+            author_width(html, "#x, .a, p { width: 1px } p.a.a { width: 2px }"),
+            px(1.0)
+        );
+    }
+
+    #[test]
+    fn current_color_uses_the_element_color() {
+        let html = "<div id=p style='color: #00f'><p id=x>a</p></div>";
+        let css = "p { color: red; border-top-color: currentColor } div { border-top-color: currentcolor }";
+        let sheets = [(css, Origin::Author)];
+        let red = Some(Value::ColorValue(Color {
+            r: 255,
+            g: 0,
+            b: 0,
+            a: 255,
+        }));
+        let blue = Some(Value::ColorValue(Color {
+            r: 0,
+            g: 0,
+            b: 255,
+            a: 255,
+        }));
+        assert_eq!(value_of(html, &sheets, "x", "border-top-color"), red);
+        assert_eq!(value_of(html, &sheets, "p", "border-top-color"), blue);
+        // `color`自身の`currentColor`は親の色になる。
+        let sheets = [("p { color: currentColor }", Origin::Author)];
+        assert_eq!(value_of(html, &sheets, "x", "color"), blue);
+    }
+
+    #[test]
+    fn var_substitution_and_inheritance() {
+        let html = "<div id=p><p id=x>a</p></div>";
+        let css = "div { --w: 10px; --Gap: 2px } p { width: var(--w); margin: var(--Gap) 1px }";
+        let sheets = [(css, Origin::Author)];
+        assert_eq!(value_of(html, &sheets, "x", "width"), px(10.0));
+        assert_eq!(value_of(html, &sheets, "x", "margin-top"), px(2.0));
+        assert_eq!(value_of(html, &sheets, "x", "margin-left"), px(1.0));
+        assert_eq!(
+            value_of(html, &sheets, "x", "--w"),
+            Some(Value::Raw("10px".to_string()))
+        );
+        // カスタムプロパティの名前は大文字と小文字を区別する。
+        let sheets = [("p { --gap: 1px; width: var(--GAP, 3px) }", Origin::Author)];
+        assert_eq!(value_of(html, &sheets, "x", "width"), px(3.0));
+    }
+
+    #[test]
+    fn var_fallbacks() {
+        let html = "<p id=x>a</p>";
+        let width = |css: &str| author_width(html, css);
+        assert_eq!(width("p { width: var(--missing, 4px) }"), px(4.0));
+        assert_eq!(
+            width("p { --b: 5px; width: var(--missing, var(--b)) }"),
+            px(5.0)
+        );
+        assert_eq!(width("p { width: calc(var(--missing, 1px) * 3) }"), px(3.0));
+        // 代替値もなければ、`unset`として初期値になる。
+        assert_eq!(
+            width("p { width: var(--missing) }"),
+            Some(Value::Keyword("auto".to_string()))
+        );
+        // 置き換えた結果がプロパティに合わなくても`unset`になる。
+        assert_eq!(
+            width("p { --c: #zz; width: var(--c) }"),
+            Some(Value::Keyword("auto".to_string()))
+        );
+    }
+
+    #[test]
+    fn cyclic_custom_properties_are_invalid() {
+        let html = "<div><p id=x>a</p></div>";
+        let auto = Some(Value::Keyword("auto".to_string()));
+        let css = "p { --a: var(--b, 1px); --b: var(--a, 2px); --c: 3px; width: var(--a, 4px) }";
+        let sheets = [(css, Origin::Author)];
+        // 循環に含まれるプロパティは、代替値があっても無効になる。
+        assert_eq!(value_of(html, &sheets, "x", "--a"), None);
+        assert_eq!(value_of(html, &sheets, "x", "--b"), None);
+        assert_eq!(
+            value_of(html, &sheets, "x", "--c"),
+            Some(Value::Raw("3px".to_string()))
+        );
+        assert_eq!(value_of(html, &sheets, "x", "width"), px(4.0));
+        assert_eq!(
+            author_width(html, "p { --a: var(--a); width: var(--a) }"),
+            auto
+        );
+        // 自分自身を参照すると、親に値があっても循環になる。
+        let css = "div { --a: 6px } p { --a: calc(var(--a) + 1px); width: var(--a) }";
+        assert_eq!(author_width(html, css), auto);
+    }
+
+    #[test]
+    fn invalid_later_declaration_keeps_the_earlier_one() {
+        let html = r#"<p id=x style="width: 100px; width: foo">a</p>"#;
+        let (document, _) = html::parse(html.to_string());
+        let media = Media {
+            media_type: MediaType::Screen,
+            viewport: Rect::default(),
+            color_scheme: ColorScheme::Light,
+        };
+        let root = style_tree(document.document_element(), &[], &media);
+        assert_eq!(
+            find(&root, "x").and_then(|node| node.value("width")),
+            px(100.0)
+        );
+
+        let html = "<p id=x>a</p>";
+        let css =
+            "p { width: 10px; font-size: 20px } p { width: foo; font-size: -5px; line-height: -3 }";
+        let (stylesheet, errors) = css::parse(css.to_string(), Origin::Author);
+        assert_eq!(errors.len(), 3);
+        let (document, _) = html::parse(html.to_string());
+        let root = style_tree(document.document_element(), &[stylesheet], &media);
+        let p = find(&root, "x").unwrap();
+        assert_eq!(p.value("width"), px(10.0));
+        assert_eq!(p.value("font-size"), px(20.0));
+        assert_eq!(
+            p.value("line-height"),
+            Some(Value::Keyword("normal".to_string()))
+        );
+    }
+
+    #[test]
+    fn border_needs_a_style() {
+        let html = "<p id=x>a</p>";
+        let has_border = |css: &str| {
+            let (document, _) = html::parse(html.to_string());
+            let (stylesheet, _) = css::parse(css.to_string(), Origin::Author);
+            let media = Media {
+                media_type: MediaType::Screen,
+                viewport: Rect::default(),
+                color_scheme: ColorScheme::Light,
+            };
+            let root = style_tree(document.document_element(), &[stylesheet], &media);
+            let p = find(&root, "x").unwrap();
+            ["top", "right", "bottom", "left"].map(|side| p.has_border(side))
+        };
+        assert_eq!(has_border("p { border-width: 2px }"), [false; 4]);
+        assert_eq!(has_border("p { border: 2px #666 }"), [false; 4]);
+        assert_eq!(has_border("p { border: 2px SOLID #666 }"), [true; 4]);
+        assert_eq!(
+            has_border(
+                "p { border: 1px solid; border-left-style: hidden; border-top-style: none }"
+            ),
+            [false, true, true, false]
+        );
+    }
+
+    #[test]
+    fn css_wide_keywords_are_case_insensitive() {
+        let html = "<div style='color: #00f; width: 5px'><p id=x>a</p></div>";
+        let blue = Some(Value::ColorValue(Color {
+            r: 0,
+            g: 0,
+            b: 255,
+            a: 255,
+        }));
+        let sheets = |css| [(css, Origin::Author)];
+        assert_eq!(
+            value_of(
+                html,
+                &sheets("p { color: red; color: INHERIT }"),
+                "x",
+                "color"
+            ),
+            blue
+        );
+        assert_eq!(
+            value_of(html, &sheets("p { width: Inherit }"), "x", "width"),
+            px(5.0)
+        );
+        assert_eq!(
+            value_of(html, &sheets("p { color: Initial }"), "x", "color"),
+            Some(Value::ColorValue(Color {
+                r: 0,
+                g: 0,
+                b: 0,
+                a: 255
+            }))
+        );
+        assert_eq!(
+            value_of(html, &sheets("p { color: UNSET }"), "x", "color"),
+            blue
+        );
+    }
+
+    #[test]
+    fn text_align_start_and_end_follow_left_to_right() {
+        let html = "<div id=d style='text-align: End'><p id=x>a</p></div>";
+        let align = |css, id| value_of(html, &[(css, Origin::Author)], id, "text-align");
+        let keyword = |k: &str| Some(Value::Keyword(k.to_string()));
+        assert_eq!(align("", "d"), keyword("right"));
+        assert_eq!(align("", "x"), keyword("right"));
+        assert_eq!(align("p { text-align: initial }", "x"), keyword("left"));
+        assert_eq!(align("p { text-align: center }", "x"), keyword("center"));
+    }
+
+    #[test]
+    fn negated_media_feature_query() {
+        let html = "<p id=x>a</p>";
+        let css = "@media not (min-width: 600px) { p { width: 1px } }";
+        let (stylesheet, errors) = css::parse(css.to_string(), Origin::Author);
+        assert!(errors.is_empty(), "{:?}", errors);
+        let width_at = |viewport_width| {
+            let media = Media {
+                media_type: MediaType::Screen,
+                viewport: Rect {
+                    width: viewport_width,
+                    ..Default::default()
+                },
+                color_scheme: ColorScheme::Light,
+            };
+            let (document, _) = html::parse(html.to_string());
+            let root = style_tree(
+                document.document_element(),
+                std::slice::from_ref(&stylesheet),
+                &media,
+            );
+            find(&root, "x").and_then(|node| node.value("width"))
+        };
+        assert_eq!(width_at(500.0), px(1.0));
+        assert_eq!(width_at(800.0), Some(Value::Keyword("auto".to_string())));
     }
 }
@@ -0,0 +1,117 @@
+//! SVG output.
+//!
+//! ディスプレイリストの各コマンドを、描画順のまま SVG の要素に変換します。
+//! 出力はテキストなので、レイアウトの変更をコードレビューで diff として確認できます。
+
+use std::io::{self, Write};
+
+use crate::css::Color;
+use crate::font::{Family, GlyphRun};
+use crate::layout::{LayoutBox, Rect};
+use crate::painting::{build_display_list, DisplayCommand};
+
+/// レイアウトツリーを SVG 文書として`out`に書き出します。
+pub fn render<W: Write>(layout_root: &LayoutBox, bounds: Rect, out: &mut W) -> io::Result<()> {
+    let display_list = build_display_list(layout_root);
+
+    writeln!(
+        out,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}">"#,
+        w = number(bounds.width),
+        h = number(bounds.height)
+    )?;
+    // PNG 出力と同じく、白いキャンバスから描き始める。
+    writeln!(out, r#"  <rect width="100%" height="100%" fill="white"/>"#)?;
+    for item in &display_list {
+        match item {
+            DisplayCommand::SolidColor(color, rect) => write_rect(out, *color, *rect)?,
+            DisplayCommand::Text(color, run) => write_text(out, *color, run)?,
+        }
+    }
+    writeln!(out, "</svg>")?;
+    out.flush()
+}
+
+/// `SolidColor`を`<rect>`にします。
+fn write_rect<W: Write>(out: &mut W, color: Color, rect: Rect) -> io::Result<()> {
+    writeln!(
+        out,
+        r#"  <rect x="{}" y="{}" width="{}" height="{}"{}/>"#,
+        number(rect.x),
+        number(rect.y),
+        number(rect.width),
+        number(rect.height),
+        fill(color)
+    )
+}
+
+/// `Text`を`<text>`にします。各文字のx座標を`x`属性に並べて、レイアウト時のグリフ位置を保ちます。
+fn write_text<W: Write>(out: &mut W, color: Color, run: &GlyphRun) -> io::Result<()> {
+    let family = match run.family {
+        Family::SansSerif => "DejaVu Sans, sans-serif".to_string(),
+        Family::Serif => "DejaVu Serif, serif".to_string(),
+        Family::Monospace => "DejaVu Sans Mono, monospace".to_string(),
+        // フォントは埋め込まないので、表示する側に同じ名前のフォントがなければ sans-serif になる。
+        Family::Custom(_) => format!(
+            "'{}', sans-serif",
+            escape(&css_string(&run.family.face_name().unwrap_or_default()))
+        ),
+    };
+    let xs: Vec<String> = run.glyphs.iter().map(|g| number(g.x)).collect();
+    let text: String = run.glyphs.iter().map(|g| g.c).collect();
+    writeln!(
+        out,
+        r#"  <text x="{}" y="{}" font-family="{}" font-size="{}"{} xml:space="preserve">{}</text>"#,
+        xs.join(" "),
+        number(run.baseline),
+        family,
+        number(run.font_size),
+        fill(color),
+        escape(&text)
+    )
+}
+
+/// 文字列を`'`で囲んだ CSS の文字列の中身にします。`'`と`\`はバックスラッシュでエスケープします。
+fn css_string(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+/// 色を`fill`属性にします。不透明でなければ`fill-opacity`も付けます。
+fn fill(color: Color) -> String {
+    let mut attrs = format!(
+        r##" fill="#{:02x}{:02x}{:02x}""##,
+        color.r, color.g, color.b
+    );
+    if color.a != 255 {
+        attrs.push_str(&format!(
+            r#" fill-opacity="{}""#,
+            number(color.a as f32 / 255.0)
+        ));
+    }
+    attrs
+}
+
+/// 座標を小数点以下2桁に丸めて書きます。diff が細かな誤差で荒れないようにするためです。
+fn number(v: f32) -> String {
+    let rounded = (v * 100.0).round() / 100.0;
+    if rounded == 0.0 {
+        "0".to_string()
+    } else {
+        rounded.to_string()
+    }
+}
+
+/// XML の特殊文字をエスケープします。
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}